@@ -0,0 +1,131 @@
+/* Decoding throughput of the readers on a synthetic stream.
+ *
+ * Run with `cargo bench`. The stream is written to a temporary file, so reads
+ * go through the file system like with a real `btrfs send` dump. */
+extern crate btrfs_send_parse as bf;
+
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::time::{Duration, Instant};
+use bf::commands::*;
+use bf::{BtrfsReader, BtrfsWriter, Command, ReaderOptions, SliceReader};
+
+const FILES: u64 = 20_000;
+const ROUNDS: usize = 5;
+
+/* Many small files, as in an initial send of a typical root file system */
+fn stream() -> Vec<u8> {
+    let mut w = BtrfsWriter::new(Vec::new(), 1).unwrap();
+    w.write_command(&Command::Subvol(Subvol {path: "bench".into(), ..Subvol::default()})).unwrap();
+    let data = vec![0x5a; 4096];
+    for i in 0..FILES {
+        let tmp = format!("o{}-5-0", i + 257);
+        let path = format!("usr/share/doc/package-{}/file-{}", i / 50, i);
+        let cmds = [
+            Command::MkFile(MkFile {path: tmp.as_str().into(), ino: i + 257}),
+            Command::Rename(Rename {path: tmp.as_str().into(), path_to: path.as_str().into()}),
+            Command::SetXattr(SetXattr {path: path.as_str().into(), xattr_name: "security.selinux".into(),
+                                        xattr_data: b"system_u:object_r:usr_t:s0".to_vec().into()}),
+            Command::Write(Write {path: path.as_str().into(), file_offset: 0, data: data.clone().into()}),
+            Command::Write(Write {path: path.as_str().into(), file_offset: 4096, data: data[..1000].to_vec().into()}),
+            Command::Chown(Chown {path: path.as_str().into(), uid: 0, gid: 0}),
+            Command::Chmod(Chmod {path: path.as_str().into(), mode: 0o644}),
+            Command::Utimes(Utimes {path: path.as_str().into(), ..Utimes::default()}),
+        ];
+        for c in cmds.iter() {
+            w.write_command(c).unwrap();
+        }
+    }
+    w.write_command(&Command::End(End::default())).unwrap();
+    w.into_inner()
+}
+
+/* Counts the reads that reach the file, seeks are passed through */
+struct CountingReader<R> {
+    r: R,
+    reads: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads += 1;
+        self.r.read(buf)
+    }
+}
+
+impl<R: Seek> Seek for CountingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.r.seek(pos)
+    }
+}
+
+fn open(path: &std::path::Path) -> BtrfsReader<CountingReader<fs::File>> {
+    BtrfsReader::new(CountingReader {r: fs::File::open(path).unwrap(), reads: 0}).unwrap()
+}
+
+/* Runs `f` several times and reports the best time. `f` returns the number of
+ * commands and of reads from the file. */
+fn bench<F: FnMut() -> (u64, u64)>(name: &str, len: usize, mut f: F) {
+    let mut best = Duration::from_secs(u64::MAX);
+    let mut counts = (0, 0);
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        counts = f();
+        best = best.min(start.elapsed());
+    }
+    let secs = best.as_secs_f64();
+    println!("{:<40} {:>8.1} MiB/s {:>10.0} commands/s {:>8} reads",
+             name, len as f64 / secs / (1 << 20) as f64, counts.0 as f64 / secs, counts.1);
+}
+
+fn main() {
+    let data = stream();
+    let path = std::env::temp_dir().join(format!("btrfs-send-parse-bench-{}", std::process::id()));
+    fs::write(&path, &data).unwrap();
+    println!("{} commands, {} bytes", FILES * 8 + 2, data.len());
+
+    bench("read_generic_command + parse_command", data.len(), || {
+        let mut r = open(&path);
+        let mut n = 0;
+        while let Some(cmd) = r.read_generic_command().unwrap() {
+            r.parse_command(cmd).unwrap();
+            n += 1;
+        }
+        (n, r.get_ref().reads)
+    });
+    bench("read_command", data.len(), || {
+        let mut r = open(&path);
+        let mut n = 0;
+        while r.read_command().unwrap().is_some() {
+            n += 1;
+        }
+        (n, r.get_ref().reads)
+    });
+    bench("read_command_ref", data.len(), || {
+        let mut r = open(&path);
+        let mut n = 0;
+        while r.read_command_ref().unwrap().is_some() {
+            n += 1;
+        }
+        (n, r.get_ref().reads)
+    });
+    bench("read_command_ref, skip_payload", data.len(), || {
+        let opts = ReaderOptions {skip_payload: true, ..ReaderOptions::default()};
+        let f = CountingReader {r: fs::File::open(&path).unwrap(), reads: 0};
+        let mut r = BtrfsReader::with_seek(f, opts).unwrap();
+        let mut n = 0;
+        while r.read_command_ref().unwrap().is_some() {
+            n += 1;
+        }
+        (n, r.get_ref().reads)
+    });
+    bench("SliceReader (in memory)", data.len(), || {
+        let mut r = SliceReader::new(&data).unwrap();
+        let mut n = 0;
+        while r.read_command().unwrap().is_some() {
+            n += 1;
+        }
+        (n, 0)
+    });
+    fs::remove_file(&path).unwrap();
+}
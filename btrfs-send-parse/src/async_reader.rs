@@ -0,0 +1,271 @@
+use std::io;
+use std::pin::Pin;
+use std::future::poll_fn;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, ReadBuf};
+use futures_core::Stream;
+use crate::definitions::*;
+use crate::*;
+
+/// Async counterpart of `BtrfsReader`, reading from a tokio `AsyncRead`.
+///
+/// Commands are decoded by the same code as in `BtrfsReader`, so both behave
+/// the same. Partially read commands are kept in the reader, which makes
+/// `read_command` cancel safe.
+pub struct AsyncBtrfsReader<R> {
+    r: R,
+    opts: ReaderOptions,
+    segment: Segment,
+    offset: u64,
+    cmd_count: u64,
+    cmd_pos: Position,
+    cmd_loc: CommandLocation,
+    /* Header and payload of the command being read, `filled` bytes are valid */
+    buf: Vec<u8>,
+    filled: usize,
+    failed: bool,
+}
+
+impl<R: AsyncRead + Unpin> AsyncBtrfsReader<R> {
+    pub async fn new(r: R) -> Result<AsyncBtrfsReader<R>> {
+        AsyncBtrfsReader::with_options(r, ReaderOptions::default()).await
+    }
+    pub async fn with_options(r: R, opts: ReaderOptions) -> Result<AsyncBtrfsReader<R>> {
+        let pos = Position::default();
+        let mut reader = AsyncBtrfsReader {
+            r, opts, segment: Segment::default(), offset: 0, cmd_count: 0, cmd_pos: pos,
+            cmd_loc: CommandLocation::default(), buf: Vec::new(), filled: 0, failed: false,
+        };
+        let filled = poll_fn(|cx| reader.poll_fill(cx, STREAM_HEADER_LEN)).await
+            .map_err(|e| Error::from_io(e, pos))?;
+        if filled < STREAM_HEADER_LEN {
+            return Err(Error::Truncated {pos});
+        }
+        reader.segment.version = parse_stream_header(&reader.buf, pos)?;
+        reader.offset = STREAM_HEADER_LEN as u64;
+        reader.filled = 0;
+        Ok(reader)
+    }
+    /// Version of the current segment.
+    pub fn version(&self) -> u32 {
+        self.segment.version
+    }
+    /// Segment of the last command read.
+    pub fn segment(&self) -> &Segment {
+        &self.segment
+    }
+    /// Location of the last command read.
+    pub fn command_location(&self) -> CommandLocation {
+        self.cmd_loc
+    }
+    /// Number of bytes read so far, not counting a partially read command.
+    pub fn bytes_consumed(&self) -> u64 {
+        self.offset
+    }
+    pub fn get_ref(&self) -> &R {
+        &self.r
+    }
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.r
+    }
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+    /// Fails unless the END command was read, see `BtrfsReader::finish`.
+    pub fn finish(&self) -> Result<()> {
+        self.segment.check_end(Position {offset: self.offset, command: self.cmd_count})
+    }
+    pub async fn read_command(&mut self) -> Result<Option<Command>> {
+        poll_fn(|cx| self.poll_command(cx)).await
+    }
+    pub async fn read_generic_command(&mut self) -> Result<Option<commands::Unknown>> {
+        poll_fn(|cx| self.poll_generic_command(cx)).await
+    }
+    pub fn parse_command(&self, cmd: commands::Unknown) -> Result<Command> {
+        decode_command(&self.opts, self.segment.version, self.cmd_pos, cmd)
+    }
+    pub fn poll_command(&mut self, cx: &mut Context) -> Poll<Result<Option<Command>>> {
+        Poll::Ready(match ready!(self.poll_generic_command(cx)) {
+            Ok(Some(cmd)) => self.parse_command(cmd).map(Some),
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        })
+    }
+    pub fn poll_generic_command(&mut self, cx: &mut Context) -> Poll<Result<Option<commands::Unknown>>> {
+        if self.segment.expects_header(&self.opts) {
+            let pos = Position {offset: self.offset, command: self.cmd_count};
+            let filled = match ready!(self.poll_fill(cx, STREAM_HEADER_LEN)) {
+                Ok(filled) => filled,
+                Err(e) => return Poll::Ready(Err(Error::from_io(e, pos))),
+            };
+            if filled == 0 {
+                return Poll::Ready(Ok(None));
+            } else if filled < STREAM_HEADER_LEN {
+                return Poll::Ready(Err(Error::Truncated {pos}));
+            }
+            self.filled = 0;
+            let version = match parse_stream_header(&self.buf, pos) {
+                Ok(version) => version,
+                Err(e) => return Poll::Ready(Err(e)),
+            };
+            self.segment = Segment::start(self.segment.index + 1, self.offset, version);
+            self.offset += STREAM_HEADER_LEN as u64;
+        }
+        if self.filled == 0 {
+            self.cmd_pos = Position {offset: self.offset, command: self.cmd_count};
+        }
+        let pos = self.cmd_pos;
+        let filled = match ready!(self.poll_fill(cx, CMD_HEADER_LEN)) {
+            Ok(filled) => filled,
+            Err(e) => return Poll::Ready(Err(Error::from_io(e, pos))),
+        };
+        if filled == 0 {
+            return Poll::Ready(Ok(None));
+        } else if filled < CMD_HEADER_LEN {
+            return Poll::Ready(Err(Error::Truncated {pos}));
+        }
+        let header = parse_command_header(&self.buf);
+        if let Err(e) = check_command_header(&self.opts, &header, pos) {
+            return Poll::Ready(Err(e));
+        }
+        let total = CMD_HEADER_LEN + header.len as usize;
+        let filled = match ready!(self.poll_fill(cx, total)) {
+            Ok(filled) => filled,
+            Err(e) => return Poll::Ready(Err(Error::from_io(e, pos))),
+        };
+        if filled < total {
+            return Poll::Ready(Err(Error::Truncated {pos}));
+        }
+        self.filled = 0;
+        self.offset += total as u64;
+        self.cmd_loc = CommandLocation::new(pos, &header);
+        self.segment.note_header(&header);
+        let version = self.segment.version;
+        let payload = &self.buf[CMD_HEADER_LEN..total];
+        let cmd = decode_generic_command(&self.opts, version, pos, header, payload);
+        if cmd.is_ok() {
+            self.segment.note_command(&borrowed::Unknown {header, data: borrowed::Tlvs::new(payload, version)}, pos);
+            self.cmd_count += 1;
+        }
+        Poll::Ready(cmd.map(Some))
+    }
+    /* Reads until `want` bytes are buffered or EOF, returns the buffered amount */
+    fn poll_fill(&mut self, cx: &mut Context, want: usize) -> Poll<io::Result<usize>> {
+        if self.buf.len() < want {
+            self.buf.resize(want, 0);
+        }
+        while self.filled < want {
+            let mut rb = ReadBuf::new(&mut self.buf[self.filled..want]);
+            ready!(Pin::new(&mut self.r).poll_read(cx, &mut rb))?;
+            let n = rb.filled().len();
+            if n == 0 {
+                break;
+            }
+            self.filled += n;
+        }
+        Poll::Ready(Ok(self.filled))
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for AsyncBtrfsReader<R> {
+    type Item = Result<Command>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Command>>> {
+        let this = self.get_mut();
+        if this.failed {
+            return Poll::Ready(None);
+        }
+        let r = ready!(this.poll_command(cx)).transpose();
+        if let Some(Err(_)) = r {
+            this.failed = true;
+        }
+        Poll::Ready(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use crate::commands::{End, MkFile, Payload, Subvol};
+
+    fn stream() -> Vec<u8> {
+        let mut w = BtrfsWriter::new(Vec::new(), 2).unwrap();
+        for cmd in [
+            Command::Subvol(Subvol {path: BtrfsString::from("sv"), ..Subvol::default()}),
+            Command::MkFile(MkFile {path: BtrfsString::from("f"), ino: 257}),
+            Command::Write(commands::Write {
+                path: BtrfsString::from("f"), file_offset: 0,
+                data: Payload::from((0..3000).map(|i| i as u8).collect::<Vec<_>>()),
+            }),
+            Command::End(End {}),
+        ] {
+            w.write_command(&cmd).unwrap();
+        }
+        w.into_inner()
+    }
+
+    /* Commands and the first error, or the result of finish */
+    fn read(bytes: &[u8]) -> (Vec<String>, String) {
+        let mut r = match BtrfsReader::new(bytes) {
+            Ok(r) => r,
+            Err(e) => return (Vec::new(), format!("{:?}", e)),
+        };
+        let mut cmds = Vec::new();
+        loop {
+            match r.read_command() {
+                Ok(Some(cmd)) => cmds.push(format!("{:?}", cmd)),
+                Ok(None) => return (cmds, format!("{:?}", r.finish())),
+                Err(e) => return (cmds, format!("{:?}", e)),
+            }
+        }
+    }
+
+    /* The same through a pipe that returns at most 7 bytes per read */
+    async fn read_async(bytes: &[u8]) -> (Vec<String>, String) {
+        let (mut tx, rx) = tokio::io::duplex(7);
+        let bytes = bytes.to_vec();
+        let writer = tokio::spawn(async move {
+            /* Fails once the reader gives up */
+            let _ = tx.write_all(&bytes).await;
+        });
+        let mut r = match AsyncBtrfsReader::new(rx).await {
+            Ok(r) => r,
+            Err(e) => return (Vec::new(), format!("{:?}", e)),
+        };
+        let mut cmds = Vec::new();
+        let result = loop {
+            match r.read_command().await {
+                Ok(Some(cmd)) => cmds.push(format!("{:?}", cmd)),
+                Ok(None) => break format!("{:?}", r.finish()),
+                Err(e) => break format!("{:?}", e),
+            }
+        };
+        drop(r);
+        writer.await.unwrap();
+        (cmds, result)
+    }
+
+    #[tokio::test]
+    async fn same_as_blocking() {
+        let bytes = stream();
+        let blocking = read(&bytes);
+        assert_eq!(blocking.0.len(), 4);
+        assert_eq!(blocking.1, "Ok(())");
+        assert_eq!(read_async(&bytes).await, blocking);
+    }
+
+    #[tokio::test]
+    async fn same_errors_as_blocking() {
+        let bytes = stream();
+        let mut corrupt = bytes.clone();
+        let last = corrupt.len() - 20;
+        corrupt[last] ^= 1;
+        let mut bad_header = bytes.clone();
+        bad_header[0] = b'x';
+        for damaged in [&bytes[..10], &bytes[..100], &bytes[..bytes.len() - CMD_HEADER_LEN], &corrupt, &bad_header] {
+            let blocking = read(damaged);
+            assert_ne!(blocking.1, "Ok(())");
+            assert_eq!(read_async(damaged).await, blocking);
+        }
+    }
+}
@@ -0,0 +1,844 @@
+use byteorder::{ByteOrder, LittleEndian};
+use crate::definitions::*;
+use crate::*;
+
+/// Attributes of a command, looked up in its payload when asked for.
+///
+/// Nothing is copied or allocated, the values borrow from the payload.
+#[derive(Clone, Copy, Debug)]
+pub struct Tlvs<'a> {
+    payload: &'a [u8],
+    version: u32,
+}
+
+/// Iterator over the (type, value) pairs of `Tlvs`.
+pub struct TlvIter<'a> {
+    rest: &'a [u8],
+    version: u32,
+}
+
+impl<'a> Iterator for TlvIter<'a> {
+    type Item = (u16, &'a [u8]);
+    fn next(&mut self) -> Option<(u16, &'a [u8])> {
+        let rest = self.rest;
+        if rest.len() < 2 {
+            return None;
+        }
+        let key = LittleEndian::read_u16(rest);
+        /* Since v2, DATA has no length and spans the rest of the command */
+        let (value, next) = if self.version >= 2 && key == Attr::DATA as u16 {
+            (&rest[2..], &rest[rest.len()..])
+        } else {
+            let len = LittleEndian::read_u16(rest.get(2..4)?) as usize;
+            let value = rest.get(4..4 + len)?;
+            (value, &rest[4 + len..])
+        };
+        self.rest = next;
+        Some((key, value))
+    }
+}
+
+impl<'a> Tlvs<'a> {
+    pub(crate) fn new(payload: &'a [u8], version: u32) -> Tlvs<'a> {
+        Tlvs {payload, version}
+    }
+    pub fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+    pub fn iter(&self) -> TlvIter<'a> {
+        TlvIter {rest: self.payload, version: self.version}
+    }
+    pub fn contains(&self, key: u16) -> bool {
+        self.iter().any(|(k, _)| k == key)
+    }
+    pub fn get(&self, key: u16) -> Result<&'a [u8]> {
+        match self.iter().find(|&(k, _)| k == key) {
+            Some((_, value)) => Ok(value),
+            None => Err(Error::missing_attribute(key)),
+        }
+    }
+    /* At least `len` bytes of the value, like reading it with a Cursor */
+    fn get_fixed(&self, key: u16, len: usize) -> Result<&'a [u8]> {
+        self.get(key)?.get(..len).ok_or_else(|| Error::invalid_attribute(key))
+    }
+    pub fn get_u8(&self, key: u16) -> Result<u8> {
+        Ok(self.get_fixed(key, 1)?[0])
+    }
+    pub fn get_u32(&self, key: u16) -> Result<u32> {
+        Ok(LittleEndian::read_u32(self.get_fixed(key, 4)?))
+    }
+    pub fn get_u64(&self, key: u16) -> Result<u64> {
+        Ok(LittleEndian::read_u64(self.get_fixed(key, 8)?))
+    }
+    /* For attributes that are optional or not sent by all producers */
+    pub fn get_u64_or_zero(&self, key: u16) -> Result<u64> {
+        if !self.contains(key) {
+            return Ok(0)
+        }
+        self.get_u64(key)
+    }
+    pub fn get_u32_or_zero(&self, key: u16) -> Result<u32> {
+        if !self.contains(key) {
+            return Ok(0)
+        }
+        self.get_u32(key)
+    }
+    pub fn get_str(&self, key: u16) -> Result<BtrfsStr<'a>> {
+        Ok(BtrfsStr::new(self.get(key)?))
+    }
+    pub fn get_timespec(&self, key: u16) -> Result<Timespec> {
+        let v = self.get_fixed(key, 12)?;
+        Ok(Timespec {sec: LittleEndian::read_u64(&v[0..8]), nsec: LittleEndian::read_u32(&v[8..12])})
+    }
+    pub fn get_uuid(&self, key: u16) -> Result<Uuid> {
+        let mut uuid = Uuid {data: [0u8; UUID_SIZE]};
+        uuid.data.copy_from_slice(self.get_fixed(key, UUID_SIZE)?);
+        Ok(uuid)
+    }
+    pub fn to_tlv_data(&self) -> TLVData {
+        TLVData {entries: self.iter().map(|(key, v)| TLVEntry {key, value: v.to_vec()}).collect()}
+    }
+}
+
+/* Validates the attribute lengths once, so that lookups can trust them */
+pub(crate) fn check_tlvs(payload: &[u8], version: u32, limits: &Limits, paths: Option<&PathPolicy>,
+                         pos: Position) -> Result<()> {
+    let mut rest = payload;
+    let mut count = 0;
+    while !rest.is_empty() {
+        if count >= limits.max_attributes {
+            return Err(Error::LimitExceeded {limit: Limit::Attributes, value: count as u64 + 1,
+                                             max: limits.max_attributes as u64, pos});
+        }
+        if rest.len() < 2 {
+            return Err(Error::BadLength {pos});
+        }
+        let key = LittleEndian::read_u16(rest);
+        let len = if version >= 2 && key == Attr::DATA as u16 {
+            rest.len() - 2
+        } else {
+            match rest.get(2..4) {
+                Some(l) if 4 + LittleEndian::read_u16(l) as usize <= rest.len() =>
+                    LittleEndian::read_u16(l) as usize,
+                _ => return Err(Error::BadLength {pos}),
+            }
+        };
+        if is_path_attr(key) && len > limits.max_path_len {
+            return Err(Error::LimitExceeded {limit: Limit::PathLen, value: len as u64,
+                                             max: limits.max_path_len as u64, pos});
+        }
+        let hdr_len = if version >= 2 && key == Attr::DATA as u16 { 2 } else { 4 };
+        check_path(paths, key, &rest[hdr_len..hdr_len + len], pos)?;
+        rest = &rest[hdr_len + len..];
+        count += 1;
+    }
+    Ok(())
+}
+
+/// File data or xattr value of a command, borrowed version of `commands::Payload`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Payload<'a> {
+    Data(&'a [u8]),
+    /// Length of the value, which was not read.
+    Skipped(u64),
+}
+
+impl<'a> Payload<'a> {
+    /// Length of the value, also if it was skipped.
+    pub fn len(&self) -> u64 {
+        match self {
+            Payload::Data(d) => d.len() as u64,
+            Payload::Skipped(len) => *len,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn is_skipped(&self) -> bool {
+        matches!(self, Payload::Skipped(_))
+    }
+    /// The value, unless it was skipped.
+    pub fn data(&self) -> Option<&'a [u8]> {
+        match *self {
+            Payload::Data(d) => Some(d),
+            Payload::Skipped(_) => None,
+        }
+    }
+    pub fn into_owned(self) -> commands::Payload {
+        match self {
+            Payload::Data(d) => commands::Payload::Data(d.to_vec()),
+            Payload::Skipped(len) => commands::Payload::Skipped(len),
+        }
+    }
+}
+
+impl<'a> Default for Payload<'a> {
+    fn default() -> Self {
+        Payload::Data(&[])
+    }
+}
+
+impl<'a> From<&'a [u8]> for Payload<'a> {
+    fn from(data: &'a [u8]) -> Payload<'a> {
+        Payload::Data(data)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Unknown<'a> {
+    pub header: CommandHeader,
+    pub data: Tlvs<'a>,
+}
+
+impl<'a> Unknown<'a> {
+    pub fn into_owned(self) -> commands::Unknown {
+        commands::Unknown {header: self.header, data: self.data.to_tlv_data()}
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Subvol<'a> {
+    pub path: BtrfsStr<'a>,
+    pub uuid: Uuid,
+    pub ctransid: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Snapshot<'a> {
+    pub path: BtrfsStr<'a>,
+    pub uuid: Uuid,
+    pub ctransid: u64,
+    pub clone_uuid: Uuid,
+    pub clone_ctransid: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MkFile<'a> {
+    pub path: BtrfsStr<'a>,
+    pub ino: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MkDir<'a> {
+    pub path: BtrfsStr<'a>,
+    pub ino: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MkNod<'a> {
+    pub path: BtrfsStr<'a>,
+    pub ino: u64,
+    pub mode: u64,
+    pub rdev: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MkFifo<'a> {
+    pub path: BtrfsStr<'a>,
+    pub ino: u64,
+    pub mode: u64,
+    pub rdev: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MkSock<'a> {
+    pub path: BtrfsStr<'a>,
+    pub ino: u64,
+    pub mode: u64,
+    pub rdev: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SymLink<'a> {
+    pub path: BtrfsStr<'a>,
+    pub ino: u64,
+    pub path_link: BtrfsStr<'a>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rename<'a> {
+    pub path: BtrfsStr<'a>,
+    pub path_to: BtrfsStr<'a>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Link<'a> {
+    pub path: BtrfsStr<'a>,
+    pub path_link: BtrfsStr<'a>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UnLink<'a> {
+    pub path: BtrfsStr<'a>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RmDir<'a> {
+    pub path: BtrfsStr<'a>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Write<'a> {
+    pub path: BtrfsStr<'a>,
+    pub file_offset: u64,
+    pub data: Payload<'a>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Clone<'a> {
+    pub path: BtrfsStr<'a>,
+    pub file_offset: u64,
+    pub clone_len: u64,
+    pub clone_uuid: Uuid,
+    pub clone_ctransid: u64,
+    pub clone_path: BtrfsStr<'a>,
+    pub clone_offset: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SetXattr<'a> {
+    pub path: BtrfsStr<'a>,
+    pub xattr_name: BtrfsStr<'a>,
+    pub xattr_data: Payload<'a>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RemoveXattr<'a> {
+    pub path: BtrfsStr<'a>,
+    pub xattr_name: BtrfsStr<'a>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Truncate<'a> {
+    pub path: BtrfsStr<'a>,
+    pub size: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Chmod<'a> {
+    pub path: BtrfsStr<'a>,
+    pub mode: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Chown<'a> {
+    pub path: BtrfsStr<'a>,
+    pub uid: u64,
+    pub gid: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Utimes<'a> {
+    pub path: BtrfsStr<'a>,
+    pub atime: Timespec,
+    pub mtime: Timespec,
+    pub ctime: Timespec,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UpdateExtent<'a> {
+    pub path: BtrfsStr<'a>,
+    pub file_offset: u64,
+    pub size: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Fallocate<'a> {
+    pub path: BtrfsStr<'a>,
+    pub mode: u32,
+    pub file_offset: u64,
+    pub size: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FileAttr<'a> {
+    pub path: BtrfsStr<'a>,
+    pub fileattr: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EncodedWrite<'a> {
+    pub path: BtrfsStr<'a>,
+    pub file_offset: u64,
+    pub unencoded_file_len: u64,
+    pub unencoded_len: u64,
+    pub unencoded_offset: u64,
+    pub compression: u32,
+    pub encryption: u32,
+    pub data: Payload<'a>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EnableVerity<'a> {
+    pub path: BtrfsStr<'a>,
+    pub algorithm: u8,
+    pub block_size: u32,
+    pub salt_data: &'a [u8],
+    pub sig_data: &'a [u8],
+}
+
+/// Command borrowing its paths and data from the buffer it was parsed from.
+#[derive(Clone, Copy, Debug)]
+pub enum Command<'a> {
+    Unknown(Unknown<'a>),
+    Subvol(Subvol<'a>),
+    Snapshot(Snapshot<'a>),
+    MkFile(MkFile<'a>),
+    MkDir(MkDir<'a>),
+    MkNod(MkNod<'a>),
+    MkFifo(MkFifo<'a>),
+    MkSock(MkSock<'a>),
+    SymLink(SymLink<'a>),
+    Rename(Rename<'a>),
+    Link(Link<'a>),
+    UnLink(UnLink<'a>),
+    RmDir(RmDir<'a>),
+    Write(Write<'a>),
+    Clone(Clone<'a>),
+    SetXattr(SetXattr<'a>),
+    RemoveXattr(RemoveXattr<'a>),
+    Truncate(Truncate<'a>),
+    Chmod(Chmod<'a>),
+    Chown(Chown<'a>),
+    Utimes(Utimes<'a>),
+    UpdateExtent(UpdateExtent<'a>),
+    End,
+    Fallocate(Fallocate<'a>),
+    FileAttr(FileAttr<'a>),
+    EncodedWrite(EncodedWrite<'a>),
+    EnableVerity(EnableVerity<'a>),
+}
+
+impl<'a> Command<'a> {
+    /// Copies the paths and data into an owned `Command`.
+    pub fn into_owned(self) -> crate::Command {
+        use crate::Command as C;
+        let s = |s: BtrfsStr| s.to_btrfs_string();
+        match self {
+            Command::Unknown(c) => C::Unknown(c.into_owned()),
+            Command::Subvol(c) => C::Subvol(commands::Subvol {
+                path: s(c.path), uuid: c.uuid, ctransid: c.ctransid,
+            }),
+            Command::Snapshot(c) => C::Snapshot(commands::Snapshot {
+                path: s(c.path), uuid: c.uuid, ctransid: c.ctransid,
+                clone_uuid: c.clone_uuid, clone_ctransid: c.clone_ctransid,
+            }),
+            Command::MkFile(c) => C::MkFile(commands::MkFile {path: s(c.path), ino: c.ino}),
+            Command::MkDir(c) => C::MkDir(commands::MkDir {path: s(c.path), ino: c.ino}),
+            Command::MkNod(c) => C::MkNod(commands::MkNod {
+                path: s(c.path), ino: c.ino, mode: c.mode, rdev: c.rdev,
+            }),
+            Command::MkFifo(c) => C::MkFifo(commands::MkFifo {
+                path: s(c.path), ino: c.ino, mode: c.mode, rdev: c.rdev,
+            }),
+            Command::MkSock(c) => C::MkSock(commands::MkSock {
+                path: s(c.path), ino: c.ino, mode: c.mode, rdev: c.rdev,
+            }),
+            Command::SymLink(c) => C::SymLink(commands::SymLink {
+                path: s(c.path), ino: c.ino, path_link: s(c.path_link),
+            }),
+            Command::Rename(c) => C::Rename(commands::Rename {path: s(c.path), path_to: s(c.path_to)}),
+            Command::Link(c) => C::Link(commands::Link {path: s(c.path), path_link: s(c.path_link)}),
+            Command::UnLink(c) => C::UnLink(commands::UnLink {path: s(c.path)}),
+            Command::RmDir(c) => C::RmDir(commands::RmDir {path: s(c.path)}),
+            Command::Write(c) => C::Write(commands::Write {
+                path: s(c.path), file_offset: c.file_offset, data: c.data.into_owned(),
+            }),
+            Command::Clone(c) => C::Clone(commands::Clone {
+                path: s(c.path), file_offset: c.file_offset, clone_len: c.clone_len,
+                clone_uuid: c.clone_uuid, clone_ctransid: c.clone_ctransid,
+                clone_path: s(c.clone_path), clone_offset: c.clone_offset,
+            }),
+            Command::SetXattr(c) => C::SetXattr(commands::SetXattr {
+                path: s(c.path), xattr_name: s(c.xattr_name), xattr_data: c.xattr_data.into_owned(),
+            }),
+            Command::RemoveXattr(c) => C::RemoveXattr(commands::RemoveXattr {
+                path: s(c.path), xattr_name: s(c.xattr_name),
+            }),
+            Command::Truncate(c) => C::Truncate(commands::Truncate {path: s(c.path), size: c.size}),
+            Command::Chmod(c) => C::Chmod(commands::Chmod {path: s(c.path), mode: c.mode}),
+            Command::Chown(c) => C::Chown(commands::Chown {path: s(c.path), uid: c.uid, gid: c.gid}),
+            Command::Utimes(c) => C::Utimes(commands::Utimes {
+                path: s(c.path), atime: c.atime, mtime: c.mtime, ctime: c.ctime,
+            }),
+            Command::UpdateExtent(c) => C::UpdateExtent(commands::UpdateExtent {
+                path: s(c.path), file_offset: c.file_offset, size: c.size,
+            }),
+            Command::End => C::End(commands::End {}),
+            Command::Fallocate(c) => C::Fallocate(commands::Fallocate {
+                path: s(c.path), mode: c.mode, file_offset: c.file_offset, size: c.size,
+            }),
+            Command::FileAttr(c) => C::FileAttr(commands::FileAttr {path: s(c.path), fileattr: c.fileattr}),
+            Command::EncodedWrite(c) => C::EncodedWrite(commands::EncodedWrite {
+                path: s(c.path), file_offset: c.file_offset,
+                unencoded_file_len: c.unencoded_file_len, unencoded_len: c.unencoded_len,
+                unencoded_offset: c.unencoded_offset, compression: c.compression,
+                encryption: c.encryption, data: c.data.into_owned(),
+            }),
+            Command::EnableVerity(c) => C::EnableVerity(commands::EnableVerity {
+                path: s(c.path), algorithm: c.algorithm, block_size: c.block_size,
+                salt_data: c.salt_data.to_vec(), sig_data: c.sig_data.to_vec(),
+            }),
+        }
+    }
+}
+
+impl<'a> From<Command<'a>> for crate::Command {
+    fn from(c: Command<'a>) -> crate::Command {
+        c.into_owned()
+    }
+}
+
+pub(crate) fn parse_known_command<'a>(c: Cmd, cmd: Unknown<'a>) -> Result<Command<'a>> {
+    let t = &cmd.data;
+    let path = || t.get_str(Attr::PATH as u16);
+    Ok(match c {
+        Cmd::SUBVOL => Command::Subvol(Subvol {
+            path: path()?,
+            uuid: t.get_uuid(Attr::UUID as u16)?,
+            ctransid: t.get_u64(Attr::CTRANSID as u16)?,
+        }),
+        Cmd::SNAPSHOT => Command::Snapshot(Snapshot {
+            path: path()?,
+            uuid: t.get_uuid(Attr::UUID as u16)?,
+            ctransid: t.get_u64(Attr::CTRANSID as u16)?,
+            clone_ctransid: t.get_u64(Attr::CLONE_CTRANSID as u16)?,
+            clone_uuid: t.get_uuid(Attr::CLONE_UUID as u16)?,
+        }),
+        Cmd::MKFILE => Command::MkFile(MkFile {
+            path: path()?,
+            ino: t.get_u64_or_zero(Attr::INO as u16)?,
+        }),
+        Cmd::MKDIR => Command::MkDir(MkDir {
+            path: path()?,
+            ino: t.get_u64_or_zero(Attr::INO as u16)?,
+        }),
+        Cmd::MKNOD => Command::MkNod(MkNod {
+            path: path()?,
+            ino: t.get_u64_or_zero(Attr::INO as u16)?,
+            mode: t.get_u64(Attr::MODE as u16)?,
+            rdev: t.get_u64(Attr::RDEV as u16)?,
+        }),
+        Cmd::MKFIFO => Command::MkFifo(MkFifo {
+            path: path()?,
+            ino: t.get_u64_or_zero(Attr::INO as u16)?,
+            mode: t.get_u64_or_zero(Attr::MODE as u16)?,
+            rdev: t.get_u64_or_zero(Attr::RDEV as u16)?,
+        }),
+        Cmd::MKSOCK => Command::MkSock(MkSock {
+            path: path()?,
+            ino: t.get_u64_or_zero(Attr::INO as u16)?,
+            mode: t.get_u64_or_zero(Attr::MODE as u16)?,
+            rdev: t.get_u64_or_zero(Attr::RDEV as u16)?,
+        }),
+        Cmd::SYMLINK => Command::SymLink(SymLink {
+            path: path()?,
+            ino: t.get_u64_or_zero(Attr::INO as u16)?,
+            path_link: t.get_str(Attr::PATH_LINK as u16)?,
+        }),
+        Cmd::RENAME => Command::Rename(Rename {
+            path: path()?,
+            path_to: t.get_str(Attr::PATH_TO as u16)?,
+        }),
+        Cmd::LINK => Command::Link(Link {
+            path: path()?,
+            path_link: t.get_str(Attr::PATH_LINK as u16)?,
+        }),
+        Cmd::UNLINK => Command::UnLink(UnLink {path: path()?}),
+        Cmd::RMDIR => Command::RmDir(RmDir {path: path()?}),
+        Cmd::WRITE => Command::Write(Write {
+            path: path()?,
+            file_offset: t.get_u64(Attr::FILE_OFFSET as u16)?,
+            data: t.get(Attr::DATA as u16)?.into(),
+        }),
+        Cmd::CLONE => Command::Clone(Clone {
+            path: path()?,
+            file_offset: t.get_u64(Attr::FILE_OFFSET as u16)?,
+            clone_len: t.get_u64(Attr::CLONE_LEN as u16)?,
+            clone_uuid: t.get_uuid(Attr::CLONE_UUID as u16)?,
+            clone_ctransid: t.get_u64(Attr::CLONE_CTRANSID as u16)?,
+            clone_path: t.get_str(Attr::CLONE_PATH as u16)?,
+            clone_offset: t.get_u64(Attr::CLONE_OFFSET as u16)?,
+        }),
+        Cmd::SET_XATTR => Command::SetXattr(SetXattr {
+            path: path()?,
+            xattr_name: t.get_str(Attr::XATTR_NAME as u16)?,
+            xattr_data: t.get(Attr::XATTR_DATA as u16)?.into(),
+        }),
+        Cmd::REMOVE_XATTR => Command::RemoveXattr(RemoveXattr {
+            path: path()?,
+            xattr_name: t.get_str(Attr::XATTR_NAME as u16)?,
+        }),
+        Cmd::TRUNCATE => Command::Truncate(Truncate {
+            path: path()?,
+            size: t.get_u64(Attr::SIZE as u16)?,
+        }),
+        Cmd::CHMOD => Command::Chmod(Chmod {
+            path: path()?,
+            mode: t.get_u64(Attr::MODE as u16)?,
+        }),
+        Cmd::CHOWN => Command::Chown(Chown {
+            path: path()?,
+            uid: t.get_u64(Attr::UID as u16)?,
+            gid: t.get_u64(Attr::GID as u16)?,
+        }),
+        Cmd::UTIMES => Command::Utimes(Utimes {
+            path: path()?,
+            atime: t.get_timespec(Attr::ATIME as u16)?,
+            mtime: t.get_timespec(Attr::MTIME as u16)?,
+            ctime: t.get_timespec(Attr::CTIME as u16)?,
+        }),
+        Cmd::UPDATE_EXTENT => Command::UpdateExtent(UpdateExtent {
+            path: path()?,
+            file_offset: t.get_u64(Attr::FILE_OFFSET as u16)?,
+            size: t.get_u64(Attr::SIZE as u16)?,
+        }),
+        Cmd::END => Command::End,
+        Cmd::FALLOCATE => Command::Fallocate(Fallocate {
+            path: path()?,
+            mode: t.get_u32(Attr::FALLOCATE_MODE as u16)?,
+            file_offset: t.get_u64(Attr::FILE_OFFSET as u16)?,
+            size: t.get_u64(Attr::SIZE as u16)?,
+        }),
+        Cmd::FILEATTR => Command::FileAttr(FileAttr {
+            path: path()?,
+            fileattr: t.get_u64(Attr::FILEATTR as u16)?,
+        }),
+        Cmd::ENCODED_WRITE => Command::EncodedWrite(EncodedWrite {
+            path: path()?,
+            file_offset: t.get_u64(Attr::FILE_OFFSET as u16)?,
+            unencoded_file_len: t.get_u64(Attr::UNENCODED_FILE_LEN as u16)?,
+            unencoded_len: t.get_u64(Attr::UNENCODED_LEN as u16)?,
+            unencoded_offset: t.get_u64(Attr::UNENCODED_OFFSET as u16)?,
+            compression: t.get_u32_or_zero(Attr::COMPRESSION as u16)?,
+            encryption: t.get_u32_or_zero(Attr::ENCRYPTION as u16)?,
+            data: t.get(Attr::DATA as u16)?.into(),
+        }),
+        Cmd::ENABLE_VERITY => Command::EnableVerity(EnableVerity {
+            path: path()?,
+            algorithm: t.get_u8(Attr::VERITY_ALGORITHM as u16)?,
+            block_size: t.get_u32(Attr::VERITY_BLOCK_SIZE as u16)?,
+            salt_data: t.get(Attr::VERITY_SALT_DATA as u16)?,
+            sig_data: t.get(Attr::VERITY_SIG_DATA as u16)?,
+        }),
+        _ => Command::Unknown(cmd),
+    })
+}
+
+pub(crate) fn decode_command<'a>(opts: &ReaderOptions, pos: Position, cmd: Unknown<'a>) -> Result<Command<'a>> {
+    match check_known(opts, cmd.header.cmd, cmd.data.iter().map(|(k, _)| k), pos)? {
+        Some(c) => parse_known_command(c, cmd).map_err(|e| e.in_command(c, pos)),
+        None => Ok(Command::Unknown(cmd)),
+    }
+}
+
+/// Zero-copy reader over a stream held in memory, such as a mapped file.
+///
+/// Returns commands that borrow from the buffer, which can be turned into
+/// owned ones with `into_owned`. Checks and errors are the same as with
+/// `BtrfsReader`.
+pub struct SliceReader<'a> {
+    data: &'a [u8],
+    opts: ReaderOptions,
+    segment: Segment,
+    offset: usize,
+    cmd_count: u64,
+    cmd_pos: Position,
+    cmd_loc: CommandLocation,
+    failed: bool,
+}
+
+impl<'a> SliceReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<SliceReader<'a>> {
+        SliceReader::with_options(data, ReaderOptions::default())
+    }
+    pub fn with_options(data: &'a [u8], opts: ReaderOptions) -> Result<SliceReader<'a>> {
+        let pos = Position::default();
+        if data.len() < STREAM_HEADER_LEN {
+            return Err(Error::Truncated {pos});
+        }
+        let version = parse_stream_header(data, pos)?;
+        Ok(SliceReader {
+            data, opts, segment: Segment::start(0, 0, version), offset: STREAM_HEADER_LEN,
+            cmd_count: 0, cmd_pos: pos, cmd_loc: CommandLocation::default(), failed: false,
+        })
+    }
+    /// Version of the current segment.
+    pub fn version(&self) -> u32 {
+        self.segment.version
+    }
+    /// Segment of the last command read.
+    pub fn segment(&self) -> &Segment {
+        &self.segment
+    }
+    /// Location of the last command read.
+    pub fn command_location(&self) -> CommandLocation {
+        self.cmd_loc
+    }
+    pub fn bytes_consumed(&self) -> u64 {
+        self.offset as u64
+    }
+    /// Fails unless the END command was read, see `BtrfsReader::finish`.
+    pub fn finish(&self) -> Result<()> {
+        self.segment.check_end(self.next_position())
+    }
+    pub fn read_command(&mut self) -> Result<Option<Command<'a>>> {
+        match self.read_generic_command()? {
+            Some(cmd) => self.parse_command(cmd).map(Some),
+            None => Ok(None),
+        }
+    }
+    pub fn parse_command(&self, cmd: Unknown<'a>) -> Result<Command<'a>> {
+        decode_command(&self.opts, self.cmd_pos, cmd)
+    }
+    pub fn read_generic_command(&mut self) -> Result<Option<Unknown<'a>>> {
+        let data = self.data;
+        if self.segment.expects_header(&self.opts) && self.offset < data.len() {
+            let pos = self.next_position();
+            let header = data.get(self.offset..self.offset + STREAM_HEADER_LEN)
+                .ok_or(Error::Truncated {pos})?;
+            let version = parse_stream_header(header, pos)?;
+            self.segment = Segment::start(self.segment.index + 1, pos.offset, version);
+            self.offset += STREAM_HEADER_LEN;
+        }
+        if self.offset == data.len() {
+            return Ok(None);
+        }
+        let pos = self.next_position();
+        let rest = &data[self.offset..];
+        if rest.len() < CMD_HEADER_LEN {
+            return Err(Error::Truncated {pos});
+        }
+        let header = parse_command_header(rest);
+        check_command_header(&self.opts, &header, pos)?;
+        let total = CMD_HEADER_LEN + header.len as usize;
+        let payload = rest.get(CMD_HEADER_LEN..total).ok_or(Error::Truncated {pos})?;
+        verify_crc(&self.opts, &header, payload, pos)?;
+        check_tlvs(payload, self.segment.version, &self.opts.limits, self.opts.paths.as_ref(), pos)?;
+        let cmd = Unknown {header, data: Tlvs {payload, version: self.segment.version}};
+        self.segment.note_header(&header);
+        self.segment.note_command(&cmd, pos);
+        self.cmd_pos = pos;
+        self.cmd_loc = CommandLocation::new(pos, &header);
+        self.offset += total;
+        self.cmd_count += 1;
+        Ok(Some(cmd))
+    }
+    fn next_position(&self) -> Position {
+        Position {offset: self.offset as u64, command: self.cmd_count}
+    }
+}
+
+impl<'a> Iterator for SliceReader<'a> {
+    type Item = Result<Command<'a>>;
+    fn next(&mut self) -> Option<Result<Command<'a>>> {
+        if self.failed {
+            return None;
+        }
+        let r = self.read_command().transpose();
+        if let Some(Err(_)) = r {
+            self.failed = true;
+        }
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{Clone as CloneCmd, End, MkFile, SetXattr, Subvol, Utimes};
+
+    fn s(path: &str) -> BtrfsString {
+        BtrfsString::from(path)
+    }
+
+    /* A v1 and a v2 stream back to back, and where the second one starts */
+    fn stream() -> (Vec<u8>, usize) {
+        let mut bytes = Vec::new();
+        for version in [1, 2] {
+            let mut w = BtrfsWriter::new(Vec::new(), version).unwrap();
+            for cmd in [
+                crate::Command::Subvol(Subvol {path: s("sv"), ..Subvol::default()}),
+                crate::Command::MkFile(MkFile {path: s("f"), ino: 257}),
+                crate::Command::Write(commands::Write {
+                    path: s("f"), file_offset: 100, data: commands::Payload::from(vec![9; 300]),
+                }),
+                crate::Command::SetXattr(SetXattr {
+                    path: s("f"), xattr_name: s("user.a"), xattr_data: commands::Payload::from(vec![1, 2]),
+                }),
+                crate::Command::Clone(CloneCmd {path: s("g"), clone_path: s("f"), clone_len: 10, ..CloneCmd::default()}),
+                crate::Command::Utimes(Utimes {path: s("f"), ..Utimes::default()}),
+                crate::Command::End(End {}),
+            ] {
+                w.write_command(&cmd).unwrap();
+            }
+            bytes.push(w.into_inner());
+        }
+        (bytes.concat(), bytes[0].len())
+    }
+
+    /* Commands with their segment, and the final error or result of finish */
+    fn read_slice(bytes: &[u8]) -> (Vec<String>, String) {
+        let mut r = match SliceReader::new(bytes) {
+            Ok(r) => r,
+            Err(e) => return (Vec::new(), format!("{:?}", e)),
+        };
+        let mut cmds = Vec::new();
+        loop {
+            match r.read_command() {
+                Ok(Some(c)) => cmds.push(format!("{} {} {:?}", r.segment().index, r.version(), c.into_owned())),
+                Ok(None) => return (cmds, format!("{:?} {}", r.finish(), r.bytes_consumed())),
+                Err(e) => return (cmds, format!("{:?}", e)),
+            }
+        }
+    }
+
+    /* The same with BtrfsReader, parsing owned commands if `generic` */
+    fn read_owned(bytes: &[u8], generic: bool) -> (Vec<String>, String) {
+        let mut r = match BtrfsReader::new(bytes) {
+            Ok(r) => r,
+            Err(e) => return (Vec::new(), format!("{:?}", e)),
+        };
+        let mut cmds = Vec::new();
+        loop {
+            let c = if generic {
+                r.read_generic_command().and_then(|c| c.map(|c| r.parse_command(c)).transpose())
+            } else {
+                r.read_command()
+            };
+            match c {
+                Ok(Some(c)) => cmds.push(format!("{} {} {:?}", r.segment().index, r.version(), c)),
+                Ok(None) => return (cmds, format!("{:?} {}", r.finish(), r.bytes_consumed())),
+                Err(e) => return (cmds, format!("{:?}", e)),
+            }
+        }
+    }
+
+    #[test]
+    fn same_as_btrfs_reader() {
+        let (bytes, _) = stream();
+        let read = read_slice(&bytes);
+        assert_eq!(read.0.len(), 14);
+        assert!(read.0[7].starts_with("1 2 Subvol"), "{}", read.0[7]);
+        assert_eq!(read.1, format!("Ok(()) {}", bytes.len()));
+        assert_eq!(read_owned(&bytes, false), read);
+        assert_eq!(read_owned(&bytes, true), read);
+    }
+
+    #[test]
+    fn same_errors_as_btrfs_reader() {
+        let (bytes, second) = stream();
+        /* Cut anywhere, at command boundaries too */
+        for len in 0..bytes.len() {
+            let read = read_slice(&bytes[..len]);
+            assert_eq!(read.1.starts_with("Ok"), len == second, "{}: {}", len, read.1);
+            assert!(read_owned(&bytes[..len], false) == read, "{}", len);
+        }
+        /* Damaged anywhere after the first header */
+        for at in STREAM_HEADER_LEN..bytes.len() {
+            let mut damaged = bytes.clone();
+            damaged[at] ^= 0x10;
+            assert!(read_owned(&damaged, false) == read_slice(&damaged), "{}", at);
+        }
+    }
+}
@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use crate::*;
+use crate::tracker::Origins;
+
+/// What changed about a file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Created,
+    Deleted,
+    /// The file was moved or renamed. For files in a renamed directory, only
+    /// the directory is reported.
+    Renamed { from: BtrfsString, to: BtrfsString },
+    /// Byte ranges that were written, cloned or allocated, sorted and merged.
+    ContentModified { ranges: Vec<Range<u64>> },
+    /// The size was set. `old` is the size before the first truncation, if
+    /// known; sizes of files of the parent snapshot are not in the stream.
+    Truncated { old: Option<u64>, new: u64 },
+    MetadataChanged(MetadataChange),
+}
+
+/// Which metadata of a file changed. The new values are in `ChangeSet::fs`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetadataChange {
+    pub mode: bool,
+    pub owner: bool,
+    pub times: bool,
+    pub fileattr: bool,
+    /// Extended attributes that were set or removed, sorted.
+    pub xattrs: Vec<BtrfsString>,
+}
+
+impl MetadataChange {
+    pub fn is_empty(&self) -> bool {
+        !self.mode && !self.owner && !self.times && !self.fileattr && self.xattrs.is_empty()
+    }
+}
+
+/// Changes of one file, in the order of the `Change` variants.
+#[derive(Clone, Debug)]
+pub struct FileChange {
+    /// Path once the stream was applied, or the path in the parent snapshot
+    /// for deleted files.
+    pub path: BtrfsString,
+    pub kind: FileKind,
+    pub changes: Vec<Change>,
+}
+
+/// Differences between the parent snapshot and the received subvolume.
+///
+/// Built by `ChangeSetBuilder`. Hard links are separate files, one for
+/// each name.
+pub struct ChangeSet {
+    files: Vec<FileChange>,
+    fs: StreamFs,
+}
+
+impl ChangeSet {
+    /// Changed files, sorted by path.
+    pub fn files(&self) -> &[FileChange] {
+        &self.files
+    }
+    pub fn into_files(self) -> Vec<FileChange> {
+        self.files
+    }
+    /// Model of the files after the stream, see `StreamFs`.
+    pub fn fs(&self) -> &StreamFs {
+        &self.fs
+    }
+}
+
+/* What is known about one name */
+#[derive(Default)]
+struct Entry {
+    /* Directory and name in the parent snapshot, None if created */
+    origin: Option<(Option<NodeId>, BtrfsString)>,
+    kind: Option<FileKind>,
+    deleted: bool,
+    ranges: Vec<Range<u64>>,
+    truncated: Option<(Option<u64>, u64)>,
+    meta: MetadataChange,
+}
+
+/// Folds commands into per-file changes.
+///
+/// Files created and deleted by the stream are left out, and so are the
+/// other changes of files that end up deleted. A change set describes a
+/// single subvolume: a Subvol or Snapshot command, like the one starting the
+/// next segment of a concatenated stream, drops the changes collected so far
+/// and starts over with an empty tree.
+pub struct ChangeSetBuilder {
+    fs: StreamFs,
+    entries: HashMap<NodeId, Entry>,
+    origins: Origins,
+}
+
+impl Default for ChangeSetBuilder {
+    fn default() -> Self {
+        ChangeSetBuilder::new()
+    }
+}
+
+impl ChangeSetBuilder {
+    pub fn new() -> ChangeSetBuilder {
+        ChangeSetBuilder {fs: StreamFs::new(), entries: HashMap::new(), origins: Origins::default()}
+    }
+    pub fn apply(&mut self, cmd: &Command) {
+        let node = match cmd {
+            Command::Subvol(_) | Command::Snapshot(_) => {
+                self.entries.clear();
+                self.origins = Origins::default();
+                self.fs.apply(cmd);
+                return;
+            },
+            Command::MkFile(c) => self.create(cmd, &c.path),
+            Command::MkDir(c) => self.create(cmd, &c.path),
+            Command::MkNod(c) => self.create(cmd, &c.path),
+            Command::MkFifo(c) => self.create(cmd, &c.path),
+            Command::MkSock(c) => self.create(cmd, &c.path),
+            Command::SymLink(c) => self.create(cmd, &c.path),
+            Command::Link(c) => {
+                self.seen(&c.path_link);
+                self.create(cmd, &c.path)
+            },
+            Command::Rename(c) => {
+                let node = self.seen(&c.path);
+                if let Some(to) = self.fs.tracker().get(c.path_to.as_bytes()) {
+                    if to != node {
+                        self.seen_node(to);
+                        self.delete(to);
+                    }
+                }
+                self.fs.apply(cmd);
+                node
+            },
+            Command::UnLink(c) => self.remove(cmd, &c.path),
+            Command::RmDir(c) => self.remove(cmd, &c.path),
+            Command::Write(c) => self.write(cmd, &c.path, c.file_offset, c.data.len()),
+            Command::Clone(c) => self.write(cmd, &c.path, c.file_offset, c.clone_len),
+            Command::EncodedWrite(c) => self.write(cmd, &c.path, c.file_offset, c.unencoded_file_len),
+            Command::UpdateExtent(c) => self.write(cmd, &c.path, c.file_offset, c.size),
+            Command::Fallocate(c) if c.mode == FALLOC_FL_KEEP_SIZE => self.touch(cmd, &c.path),
+            Command::Fallocate(c) => self.write(cmd, &c.path, c.file_offset, c.size),
+            Command::Truncate(c) => {
+                let node = self.seen(&c.path);
+                let old = self.fs.inode(node).and_then(|i| i.size);
+                self.fs.apply(cmd);
+                let entry = self.entries.get_mut(&node).unwrap();
+                let old = entry.truncated.map_or(old, |t| t.0);
+                entry.truncated = Some((old, c.size));
+                node
+            },
+            Command::Chmod(c) => self.meta(cmd, &c.path, |m| m.mode = true),
+            Command::Chown(c) => self.meta(cmd, &c.path, |m| m.owner = true),
+            Command::Utimes(c) => self.meta(cmd, &c.path, |m| m.times = true),
+            Command::FileAttr(c) => self.meta(cmd, &c.path, |m| m.fileattr = true),
+            Command::SetXattr(c) => self.meta(cmd, &c.path, |m| add_xattr(m, &c.xattr_name)),
+            Command::RemoveXattr(c) => self.meta(cmd, &c.path, |m| add_xattr(m, &c.xattr_name)),
+            Command::EnableVerity(c) => self.touch(cmd, &c.path),
+            Command::End(_) | Command::Unknown(_) => return,
+        };
+        if let Some(inode) = self.fs.inode(node) {
+            self.entries.get_mut(&node).unwrap().kind = Some(inode.kind);
+        }
+    }
+    pub fn finish(self) -> ChangeSet {
+        let mut files = Vec::new();
+        let tracker = self.fs.tracker();
+        for (&node, entry) in &self.entries {
+            let mut changes = Vec::new();
+            let path = match (&entry.origin, entry.deleted) {
+                (None, true) => continue,
+                (Some(_), true) => {
+                    changes.push(Change::Deleted);
+                    self.origin_path(node)
+                },
+                (origin, false) => {
+                    let path = match tracker.path(node) {
+                        Some(path) => path,
+                        /* In a deleted directory */
+                        None => continue,
+                    };
+                    match origin {
+                        None => changes.push(Change::Created),
+                        Some((dir, name)) => {
+                            if *dir != tracker.parent(node) || name != tracker.name(node) {
+                                changes.push(Change::Renamed {from: self.origin_path(node), to: path.clone()});
+                            }
+                        },
+                    }
+                    if !entry.ranges.is_empty() {
+                        changes.push(Change::ContentModified {ranges: merge_ranges(entry.ranges.clone())});
+                    }
+                    if let Some((old, new)) = entry.truncated {
+                        changes.push(Change::Truncated {old, new});
+                    }
+                    if !entry.meta.is_empty() {
+                        changes.push(Change::MetadataChanged(entry.meta.clone()));
+                    }
+                    path
+                },
+            };
+            if !changes.is_empty() {
+                /* Files of the parent snapshot turn out to be directories once
+                 * something is found in them, after their own commands */
+                let inode = if entry.deleted { None } else { self.fs.inode(node) };
+                let kind = inode.map(|i| i.kind).or(entry.kind).unwrap_or(FileKind::Unknown);
+                files.push(FileChange {path, kind, changes});
+            }
+        }
+        /* A file deleted at a path comes before the one that replaced it */
+        let replaced = |f: &FileChange| f.changes[0] != Change::Deleted;
+        files.sort_by(|a, b| a.path.cmp(&b.path).then(replaced(a).cmp(&replaced(b))));
+        ChangeSet {files, fs: self.fs}
+    }
+    fn create(&mut self, cmd: &Command, path: &BtrfsString) -> NodeId {
+        if let Some(old) = self.fs.tracker().get(path.as_bytes()) {
+            self.seen_node(old);
+            self.delete(old);
+        }
+        self.fs.apply(cmd);
+        let node = self.fs.tracker().get(path.as_bytes()).unwrap();
+        self.entries.insert(node, Entry::default());
+        node
+    }
+    fn remove(&mut self, cmd: &Command, path: &BtrfsString) -> NodeId {
+        let node = self.seen(path);
+        self.delete(node);
+        self.fs.apply(cmd);
+        if let Command::RmDir(_) = cmd {
+            self.entries.get_mut(&node).unwrap().kind = Some(FileKind::Dir);
+        }
+        node
+    }
+    fn write(&mut self, cmd: &Command, path: &BtrfsString, offset: u64, len: u64) -> NodeId {
+        let node = self.touch(cmd, path);
+        if len > 0 {
+            let range = offset..offset.saturating_add(len);
+            self.entries.get_mut(&node).unwrap().ranges.push(range);
+        }
+        node
+    }
+    fn meta<F: FnOnce(&mut MetadataChange)>(&mut self, cmd: &Command, path: &BtrfsString, f: F) -> NodeId {
+        let node = self.touch(cmd, path);
+        f(&mut self.entries.get_mut(&node).unwrap().meta);
+        node
+    }
+    fn touch(&mut self, cmd: &Command, path: &BtrfsString) -> NodeId {
+        let node = self.seen(path);
+        self.fs.apply(cmd);
+        node
+    }
+    fn delete(&mut self, node: NodeId) {
+        let entry = self.entries.get_mut(&node).unwrap();
+        entry.kind = self.fs.inode(node).map(|i| i.kind);
+        entry.deleted = true;
+    }
+    /* Node of a path that exists before the command */
+    fn seen(&mut self, path: &BtrfsString) -> NodeId {
+        let node = self.fs.lookup(path.as_bytes());
+        self.seen_node(node);
+        node
+    }
+    /* Records where a file of the parent snapshot, and the directories
+     * above it, are before the stream moves them */
+    fn seen_node(&mut self, node: NodeId) {
+        let tracker = self.fs.tracker();
+        let mut id = Some(node);
+        while let Some(node) = id {
+            if self.entries.contains_key(&node) {
+                break;
+            }
+            let origin = (tracker.parent(node), tracker.name(node).clone());
+            self.entries.insert(node, Entry {origin: Some(origin), ..Entry::default()});
+            id = tracker.parent(node);
+        }
+        self.origins.see(tracker, node);
+    }
+    /// Current path of the file that was at `origin` in the parent snapshot,
+    /// None if it was removed.
+    pub fn current_path(&self, origin: &[u8]) -> Option<BtrfsString> {
+        self.origins.current_path(self.fs.tracker(), origin)
+    }
+    /* Path of a file in the parent snapshot */
+    fn origin_path(&self, node: NodeId) -> BtrfsString {
+        let mut names = Vec::new();
+        let mut id = node;
+        while let Some(Some((Some(dir), name))) = self.entries.get(&id).map(|e| e.origin.as_ref()) {
+            names.push(name.as_bytes());
+            id = *dir;
+        }
+        names.reverse();
+        BtrfsString::from(names.join(&b'/'))
+    }
+}
+
+fn add_xattr(meta: &mut MetadataChange, name: &BtrfsString) {
+    if let Err(i) = meta.xattrs.binary_search(name) {
+        meta.xattrs.insert(i, name.clone());
+    }
+}
+
+fn merge_ranges(mut ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<u64>> = Vec::new();
+    for r in ranges {
+        match merged.last_mut() {
+            Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+            _ => merged.push(r),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::*;
+
+    fn s(path: &str) -> BtrfsString {
+        BtrfsString::from(path)
+    }
+
+    fn write(path: &str, offset: u64, len: usize) -> Command {
+        Command::Write(commands::Write {path: s(path), file_offset: offset, data: Payload::from(vec![0; len])})
+    }
+
+    fn rename(from: &str, to: &str) -> Command {
+        Command::Rename(Rename {path: s(from), path_to: s(to)})
+    }
+
+    fn builder(cmds: &[Command]) -> ChangeSetBuilder {
+        let mut b = ChangeSetBuilder::new();
+        b.apply(&Command::Snapshot(Snapshot {path: s("snap"), ..Snapshot::default()}));
+        for c in cmds {
+            b.apply(c);
+        }
+        b
+    }
+
+    fn changes(cmds: &[Command]) -> Vec<(String, FileKind, Vec<Change>)> {
+        builder(cmds).finish().into_files().into_iter().map(|f| (f.path.to_string(), f.kind, f.changes)).collect()
+    }
+
+    fn renamed(from: &str, to: &str) -> Change {
+        Change::Renamed {from: s(from), to: s(to)}
+    }
+
+    fn modified(ranges: &[(u64, u64)]) -> Change {
+        Change::ContentModified {ranges: ranges.iter().map(|&(start, end)| start..end).collect()}
+    }
+
+    #[test]
+    fn created_and_deleted() {
+        let files = changes(&[
+            Command::MkFile(MkFile {path: s("o257-1-0"), ino: 257}),
+            rename("o257-1-0", "new"),
+            Command::MkFile(MkFile {path: s("tmp"), ino: 258}),
+            Command::UnLink(UnLink {path: s("tmp")}),
+            Command::UnLink(UnLink {path: s("old")}),
+            Command::RmDir(RmDir {path: s("olddir")}),
+        ]);
+        assert_eq!(files, [
+            ("new".to_string(), FileKind::File, vec![Change::Created]),
+            ("old".to_string(), FileKind::Unknown, vec![Change::Deleted]),
+            ("olddir".to_string(), FileKind::Dir, vec![Change::Deleted]),
+        ]);
+    }
+
+    #[test]
+    fn renamed_directory() {
+        let files = changes(&[rename("d", "e"), write("e/f", 0, 10), Command::MkFile(MkFile {path: s("e/g"), ino: 300})]);
+        assert_eq!(files, [
+            ("e".to_string(), FileKind::Dir, vec![renamed("d", "e")]),
+            ("e/f".to_string(), FileKind::File, vec![modified(&[(0, 10)])]),
+            ("e/g".to_string(), FileKind::File, vec![Change::Created]),
+        ]);
+        /* Only the directory is reported as renamed */
+        let files = changes(&[write("d/f", 0, 1), rename("d", "e")]);
+        assert_eq!(files[0], ("e".to_string(), FileKind::Dir, vec![renamed("d", "e")]));
+        assert_eq!(files[1].2, [modified(&[(0, 1)])]);
+        /* Moved back, nothing to report */
+        assert!(changes(&[rename("a", "b"), rename("b", "a")]).is_empty());
+    }
+
+    #[test]
+    fn rename_over_existing() {
+        /* The stream only tells that a file of the parent snapshot existed once it uses it */
+        let files = changes(&[write("b", 0, 1), rename("a", "b")]);
+        assert_eq!(files, [
+            ("b".to_string(), FileKind::File, vec![Change::Deleted]),
+            ("b".to_string(), FileKind::Unknown, vec![renamed("a", "b")]),
+        ]);
+        let files = changes(&[Command::MkFile(MkFile {path: s("o257-1-0"), ino: 257}), write("b", 0, 1), rename("o257-1-0", "b")]);
+        assert_eq!(files, [
+            ("b".to_string(), FileKind::File, vec![Change::Deleted]),
+            ("b".to_string(), FileKind::File, vec![Change::Created]),
+        ]);
+    }
+
+    #[test]
+    fn content_ranges() {
+        let files = changes(&[
+            write("f", 30, 10),
+            write("f", 0, 10),
+            write("f", 5, 15),
+            write("f", 20, 0),
+            Command::Fallocate(Fallocate {path: s("f"), mode: FALLOC_FL_KEEP_SIZE, file_offset: 100, size: 10}),
+            Command::Fallocate(Fallocate {path: s("f"), mode: 0, file_offset: 40, size: 5}),
+        ]);
+        assert_eq!(files[0].2, [modified(&[(0, 20), (30, 45)])]);
+    }
+
+    #[test]
+    fn truncated() {
+        let truncate = |size| Command::Truncate(Truncate {path: s("f"), size});
+        let files = changes(&[truncate(10), truncate(5)]);
+        assert_eq!(files[0].2, [Change::Truncated {old: None, new: 5}]);
+        let files = changes(&[Command::MkFile(MkFile {path: s("f"), ino: 257}), write("f", 0, 10), truncate(3)]);
+        assert_eq!(files[0].2[2], Change::Truncated {old: Some(10), new: 3});
+    }
+
+    #[test]
+    fn metadata() {
+        let files = changes(&[
+            Command::Chmod(Chmod {path: s("f"), mode: 0o644}),
+            Command::Utimes(Utimes {path: s("f"), ..Utimes::default()}),
+            Command::SetXattr(SetXattr {path: s("f"), xattr_name: s("user.b"), ..SetXattr::default()}),
+            Command::RemoveXattr(RemoveXattr {path: s("f"), xattr_name: s("user.a")}),
+            Command::SetXattr(SetXattr {path: s("f"), xattr_name: s("user.b"), ..SetXattr::default()}),
+            Command::Chown(Chown {path: s("g"), uid: 1, gid: 1}),
+            Command::FileAttr(FileAttr {path: s("g"), fileattr: 1}),
+        ]);
+        let f = MetadataChange {mode: true, times: true, xattrs: vec![s("user.a"), s("user.b")], ..MetadataChange::default()};
+        let g = MetadataChange {owner: true, fileattr: true, ..MetadataChange::default()};
+        assert_eq!(files[0].2, [Change::MetadataChanged(f)]);
+        assert_eq!(files[1].2, [Change::MetadataChanged(g)]);
+    }
+
+    #[test]
+    fn current_path() {
+        let b = builder(&[rename("d/x", "y"), rename("d", "e"), Command::UnLink(UnLink {path: s("e/z")})]);
+        assert_eq!(b.current_path(b"d/x"), Some(s("y")));
+        assert_eq!(b.current_path(b"d/other"), Some(s("e/other")));
+        assert_eq!(b.current_path(b"d"), Some(s("e")));
+        assert_eq!(b.current_path(b"d/z"), None);
+        assert_eq!(b.current_path(b"untouched/f"), Some(s("untouched/f")));
+    }
+
+    #[test]
+    fn new_subvolume() {
+        let mut b = builder(&[write("f", 0, 1)]);
+        b.apply(&Command::Subvol(Subvol {path: s("sv"), ..Subvol::default()}));
+        b.apply(&Command::MkFile(MkFile {path: s("g"), ino: 257}));
+        let files = b.finish().into_files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, s("g"));
+    }
+}
@@ -1,10 +1,56 @@
-use ::*;
+use crate::*;
 #[derive(Clone, Debug)]
 pub struct Unknown {
     pub header: CommandHeader,
     pub data: TLVData
 }
 
+/// File data or xattr value carried by a command.
+///
+/// The value is `Skipped` if the reader discarded it, see
+/// `ReaderOptions::skip_payload`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Payload {
+    Data(Vec<u8>),
+    /// Length of the value, which was not read.
+    Skipped(u64),
+}
+
+impl Payload {
+    /// Length of the value, also if it was skipped.
+    pub fn len(&self) -> u64 {
+        match self {
+            Payload::Data(d) => d.len() as u64,
+            Payload::Skipped(len) => *len,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn is_skipped(&self) -> bool {
+        matches!(self, Payload::Skipped(_))
+    }
+    /// The value, unless it was skipped.
+    pub fn data(&self) -> Option<&[u8]> {
+        match self {
+            Payload::Data(d) => Some(d),
+            Payload::Skipped(_) => None,
+        }
+    }
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Payload::Data(Vec::new())
+    }
+}
+
+impl From<Vec<u8>> for Payload {
+    fn from(data: Vec<u8>) -> Payload {
+        Payload::Data(data)
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct Subvol {
     pub path: BtrfsString,
@@ -24,16 +70,19 @@ pub struct Snapshot {
 #[derive(Clone, Debug, Default)]
 pub struct MkFile {
     pub path: BtrfsString,
+    pub ino: u64,
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct MkDir {
     pub path: BtrfsString,
+    pub ino: u64,
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct MkNod {
     pub path: BtrfsString,
+    pub ino: u64,
     pub mode: u64,
     pub rdev: u64,
 }
@@ -41,16 +90,23 @@ pub struct MkNod {
 #[derive(Clone, Debug, Default)]
 pub struct MkFifo {
     pub path: BtrfsString,
+    pub ino: u64,
+    pub mode: u64,
+    pub rdev: u64,
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct MkSock {
     pub path: BtrfsString,
+    pub ino: u64,
+    pub mode: u64,
+    pub rdev: u64,
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct SymLink {
     pub path: BtrfsString,
+    pub ino: u64,
     pub path_link: BtrfsString,
 }
 
@@ -80,7 +136,7 @@ pub struct RmDir {
 pub struct Write {
     pub path: BtrfsString,
     pub file_offset: u64,
-    pub data: Vec<u8>,
+    pub data: Payload,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -98,7 +154,7 @@ pub struct Clone {
 pub struct SetXattr {
     pub path: BtrfsString,
     pub xattr_name: BtrfsString,
-    pub xattr_data: Vec<u8>
+    pub xattr_data: Payload,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -143,4 +199,39 @@ pub struct UpdateExtent {
 
 #[derive(Clone, Debug,Default)]
 pub struct End {
-}
\ No newline at end of file
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Fallocate {
+    pub path: BtrfsString,
+    pub mode: u32,
+    pub file_offset: u64,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct FileAttr {
+    pub path: BtrfsString,
+    pub fileattr: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct EncodedWrite {
+    pub path: BtrfsString,
+    pub file_offset: u64,
+    pub unencoded_file_len: u64,
+    pub unencoded_len: u64,
+    pub unencoded_offset: u64,
+    pub compression: u32,
+    pub encryption: u32,
+    pub data: Payload,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct EnableVerity {
+    pub path: BtrfsString,
+    pub algorithm: u8,
+    pub block_size: u32,
+    pub salt_data: Vec<u8>,
+    pub sig_data: Vec<u8>,
+}
@@ -0,0 +1,367 @@
+use std::convert::TryFrom;
+use byteorder::{ByteOrder, LittleEndian};
+use crate::definitions::*;
+use crate::*;
+
+/// Push-based decoder that does no I/O of its own.
+///
+/// Feed it chunks of the stream as they arrive, of any size, and take out the
+/// commands that are complete. The stream header is decoded from the first
+/// bytes fed. Decoding is shared with `BtrfsReader`, so results are the same.
+pub struct Decoder {
+    opts: ReaderOptions,
+    /* None until the stream header has been decoded */
+    segment: Option<Segment>,
+    /* Bytes fed, but not decoded yet, start at `start` */
+    buf: Vec<u8>,
+    start: usize,
+    offset: u64,
+    cmd_count: u64,
+    cmd_pos: Position,
+    cmd_loc: CommandLocation,
+    /* A stream header was found by resync */
+    header_next: bool,
+    /* Resync already skipped the byte at `start` */
+    resyncing: bool,
+    /* Version for commands found by resync before any stream header */
+    resync_version: u32,
+}
+
+enum Candidate {
+    No,
+    More,
+    Header,
+    Command,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::with_options(ReaderOptions::default())
+    }
+    pub fn with_options(opts: ReaderOptions) -> Decoder {
+        Decoder {
+            opts, segment: None, buf: Vec::new(), start: 0,
+            offset: 0, cmd_count: 0, cmd_pos: Position::default(),
+            cmd_loc: CommandLocation::default(), header_next: false, resyncing: false,
+            resync_version: 1,
+        }
+    }
+    /// Stream version, once the header has been decoded.
+    pub fn version(&self) -> Option<u32> {
+        self.segment.as_ref().map(|s| s.version)
+    }
+    /// Segment of the last command decoded, once the header has been decoded.
+    pub fn segment(&self) -> Option<&Segment> {
+        self.segment.as_ref()
+    }
+    pub fn feed(&mut self, data: &[u8]) {
+        if self.start > 0 {
+            self.buf.drain(..self.start);
+            self.start = 0;
+        }
+        self.buf.extend_from_slice(data);
+    }
+    /// Location of the last command decoded.
+    pub fn command_location(&self) -> CommandLocation {
+        self.cmd_loc
+    }
+    /// Number of bytes decoded so far, not counting those still buffered.
+    pub fn bytes_consumed(&self) -> u64 {
+        self.offset
+    }
+    /// Number of bytes fed but not decoded yet.
+    pub fn buffered(&self) -> usize {
+        self.buf.len() - self.start
+    }
+    /// Number of bytes that must be fed before the next header or command is
+    /// complete. Zero means `decode` will make progress.
+    pub fn bytes_needed(&self) -> usize {
+        let avail = self.buffered();
+        let want = if self.expects_header() {
+            STREAM_HEADER_LEN
+        } else if avail < CMD_HEADER_LEN {
+            CMD_HEADER_LEN
+        } else {
+            CMD_HEADER_LEN + parse_command_header(&self.buf[self.start..]).len as usize
+        };
+        want.saturating_sub(avail)
+    }
+    /// Returns the next complete command, or None if more data is needed.
+    pub fn decode(&mut self) -> Result<Option<Command>> {
+        match self.decode_generic()? {
+            Some(cmd) => self.parse_command(cmd).map(Some),
+            None => Ok(None),
+        }
+    }
+    pub fn parse_command(&self, cmd: commands::Unknown) -> Result<Command> {
+        decode_command(&self.opts, self.version().unwrap_or(self.resync_version), self.cmd_pos, cmd)
+    }
+    pub fn decode_generic(&mut self) -> Result<Option<commands::Unknown>> {
+        if self.expects_header() {
+            if self.bytes_needed() > 0 {
+                return Ok(None);
+            }
+            let pos = Position {offset: self.offset, command: self.cmd_count};
+            let version = parse_stream_header(&self.buf[self.start..], pos)?;
+            let index = self.segment.as_ref().map_or(0, |s| s.index + 1);
+            self.segment = Some(Segment::start(index, self.offset, version));
+            self.header_next = false;
+            self.consume(STREAM_HEADER_LEN);
+        }
+        let pos = Position {offset: self.offset, command: self.cmd_count};
+        if self.buffered() < CMD_HEADER_LEN {
+            return Ok(None);
+        }
+        let header = parse_command_header(&self.buf[self.start..]);
+        check_command_header(&self.opts, &header, pos)?;
+        if self.bytes_needed() > 0 {
+            return Ok(None);
+        }
+        let segment = self.segment.as_mut().unwrap();
+        let loc = CommandLocation::new(pos, &header);
+        let total = CMD_HEADER_LEN + header.len as usize;
+        let payload = &self.buf[self.start + CMD_HEADER_LEN..self.start + total];
+        /* A command that fails to decode stays buffered, so resync can look into it */
+        let cmd = decode_generic_command(&self.opts, segment.version, pos, header, payload)?;
+        segment.note_header(&cmd.header);
+        segment.note_command(&borrowed::Unknown {header, data: borrowed::Tlvs::new(payload, segment.version)}, pos);
+        self.cmd_pos = pos;
+        self.cmd_loc = loc;
+        self.consume(total);
+        self.cmd_count += 1;
+        Ok(Some(cmd))
+    }
+    /// Skips to the next position that looks like the start of a stream or of
+    /// a command, to recover from a decoding error.
+    ///
+    /// A command must have a known type, a length within the limits and a
+    /// matching checksum. The first byte buffered is always skipped, bytes that
+    /// were ruled out are dropped. Returns false if more data is needed, or at
+    /// the end of input, if nothing was found. Commands found before the first
+    /// stream header use the version of the damaged header if it looks
+    /// valid, or version 1.
+    pub fn resync(&mut self, at_eof: bool) -> bool {
+        let avail = self.buffered();
+        if !self.resyncing && self.segment.is_none() && avail >= STREAM_HEADER_LEN {
+            let data = &self.buf[self.start..];
+            let version = LittleEndian::read_u32(&data[MAGIC_LEN..STREAM_HEADER_LEN]);
+            if (1..=MAX_VERSION).contains(&version) {
+                self.resync_version = version;
+            }
+        }
+        let mut i = if self.resyncing { 0 } else { 1 };
+        self.resyncing = true;
+        while i < avail {
+            match self.candidate(&self.buf[self.start + i..], at_eof) {
+                Candidate::No => i += 1,
+                Candidate::More => {
+                    self.consume(i);
+                    return false;
+                },
+                Candidate::Header => {
+                    self.consume(i);
+                    self.header_next = true;
+                    self.resyncing = false;
+                    return true;
+                },
+                Candidate::Command => {
+                    self.consume(i);
+                    /* The header of the next segment was lost, go on without it */
+                    match self.segment {
+                        None => self.segment = Some(Segment::start(0, self.offset, self.resync_version)),
+                        Some(ref mut s) if s.ended => *s = Segment::start(s.index + 1, self.offset, s.version),
+                        Some(_) => {},
+                    }
+                    self.header_next = false;
+                    self.resyncing = false;
+                    return true;
+                },
+            }
+        }
+        self.consume(i.min(avail));
+        false
+    }
+    fn candidate(&self, data: &[u8], at_eof: bool) -> Candidate {
+        let more = if at_eof { Candidate::No } else { Candidate::More };
+        let n = data.len().min(MAGIC_LEN);
+        if data[..n] == MAGIC.as_bytes()[..n] {
+            if data.len() < STREAM_HEADER_LEN {
+                return more;
+            }
+            return match parse_stream_header(data, Position::default()) {
+                Ok(_) => Candidate::Header,
+                Err(_) => Candidate::No,
+            };
+        }
+        if data.len() < CMD_HEADER_LEN {
+            return more;
+        }
+        let header = parse_command_header(data);
+        match Cmd::try_from(header.cmd) {
+            Ok(Cmd::UNSPEC) | Err(_) => return Candidate::No,
+            Ok(_) => {},
+        }
+        if check_command_header(&self.opts, &header, Position::default()).is_err() {
+            return Candidate::No;
+        }
+        let total = CMD_HEADER_LEN + header.len as usize;
+        if data.len() < total {
+            return more;
+        }
+        if command_crc32(&header, &data[CMD_HEADER_LEN..total]) != header.crc32 {
+            return Candidate::No;
+        }
+        Candidate::Command
+    }
+    /// Call at the end of input. Fails if it ends inside the header or a
+    /// command, or before the END command.
+    pub fn finish(&self) -> Result<()> {
+        let pos = self.position();
+        match self.segment {
+            Some(ref s) if self.buffered() == 0 => s.check_end(pos),
+            _ => Err(Error::Truncated {pos}),
+        }
+    }
+    pub(crate) fn position(&self) -> Position {
+        Position {offset: self.offset, command: self.cmd_count}
+    }
+    fn expects_header(&self) -> bool {
+        if self.header_next {
+            return true;
+        }
+        match &self.segment {
+            Some(s) => s.expects_header(&self.opts),
+            None => true,
+        }
+    }
+    fn consume(&mut self, len: usize) {
+        self.start += len;
+        self.offset += len as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{End, MkFile, Payload, SetXattr, Subvol};
+
+    /* A v2 stream with the offsets of its commands */
+    fn stream() -> (Vec<u8>, Vec<usize>) {
+        let mut w = BtrfsWriter::new(Vec::new(), 2).unwrap();
+        let mut offsets = Vec::new();
+        for cmd in [
+            Command::Subvol(Subvol {path: BtrfsString::from("sv"), ..Subvol::default()}),
+            Command::MkFile(MkFile {path: BtrfsString::from("f"), ino: 257}),
+            Command::Write(commands::Write {
+                path: BtrfsString::from("f"), file_offset: 0,
+                data: Payload::from((0..5000).map(|i| i as u8).collect::<Vec<_>>()),
+            }),
+            Command::SetXattr(SetXattr {
+                path: BtrfsString::from("f"), xattr_name: BtrfsString::from("user.a"),
+                xattr_data: Payload::from(vec![1, 2, 3]),
+            }),
+            Command::End(End {}),
+        ] {
+            offsets.push(w.get_ref().len());
+            w.write_command(&cmd).unwrap();
+        }
+        (w.into_inner(), offsets)
+    }
+
+    fn read(bytes: &[u8]) -> Vec<String> {
+        let mut r = BtrfsReader::new(bytes).unwrap();
+        let mut cmds = Vec::new();
+        while let Some(cmd) = r.read_command().unwrap() {
+            cmds.push(format!("{:?}", cmd));
+        }
+        cmds
+    }
+
+    /* Feeds `bytes` in chunks of the sizes returned by `next` */
+    fn decode(bytes: &[u8], mut next: impl FnMut() -> usize) -> Vec<String> {
+        let mut d = Decoder::new();
+        let mut cmds = Vec::new();
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let n = next().clamp(1, rest.len());
+            d.feed(&rest[..n]);
+            rest = &rest[n..];
+            while let Some(cmd) = d.decode().unwrap() {
+                cmds.push(format!("{:?}", cmd));
+            }
+        }
+        assert_eq!(d.bytes_consumed(), bytes.len() as u64);
+        d.finish().unwrap();
+        cmds
+    }
+
+    #[test]
+    fn chunks() {
+        let (bytes, _) = stream();
+        let expected = read(&bytes);
+        assert_eq!(expected.len(), 5);
+        assert_eq!(decode(&bytes, || 1), expected);
+        assert_eq!(decode(&bytes, || bytes.len()), expected);
+        for seed in 1..20u32 {
+            let mut x = seed;
+            let random = || {
+                x = x.wrapping_mul(1103515245).wrapping_add(12345);
+                (x >> 16) as usize % 700
+            };
+            assert_eq!(decode(&bytes, random), expected);
+        }
+    }
+
+    #[test]
+    fn bytes_needed() {
+        let (bytes, offsets) = stream();
+        let (first, second) = (offsets[0], offsets[1]);
+        let mut d = Decoder::new();
+        assert_eq!(d.bytes_needed(), STREAM_HEADER_LEN);
+        d.feed(&bytes[..10]);
+        assert_eq!(d.bytes_needed(), STREAM_HEADER_LEN - 10);
+        d.feed(&bytes[10..first]);
+        assert_eq!(d.bytes_needed(), 0);
+        assert!(d.decode().unwrap().is_none());
+        assert_eq!(d.version(), Some(2));
+        assert_eq!(d.bytes_needed(), CMD_HEADER_LEN);
+        d.feed(&bytes[first..first + 4]);
+        assert_eq!(d.bytes_needed(), CMD_HEADER_LEN - 4);
+        d.feed(&bytes[first + 4..first + CMD_HEADER_LEN]);
+        assert_eq!(d.bytes_needed(), second - first - CMD_HEADER_LEN);
+        d.feed(&bytes[first + CMD_HEADER_LEN..second - 1]);
+        assert_eq!(d.bytes_needed(), 1);
+        assert!(d.decode().unwrap().is_none());
+        d.feed(&bytes[second - 1..second]);
+        assert_eq!(d.bytes_needed(), 0);
+        assert!(matches!(d.decode().unwrap(), Some(Command::Subvol(_))));
+        assert_eq!(d.bytes_needed(), CMD_HEADER_LEN);
+        assert_eq!(d.bytes_consumed(), second as u64);
+    }
+
+    #[test]
+    fn finish() {
+        let (bytes, offsets) = stream();
+        let decode = |len: usize| {
+            let mut d = Decoder::new();
+            d.feed(&bytes[..len]);
+            while d.decode().unwrap().is_some() {}
+            d.finish()
+        };
+        assert!(matches!(decode(0), Err(Error::Truncated {..})));
+        assert!(matches!(decode(STREAM_HEADER_LEN - 1), Err(Error::Truncated {..})));
+        assert!(matches!(decode(offsets[2] + 3), Err(Error::Truncated {..})));
+        assert!(matches!(decode(offsets[3] + CMD_HEADER_LEN + 1), Err(Error::Truncated {..})));
+        match decode(offsets[4]) {
+            Err(Error::MissingEnd {pos}) => assert_eq!(pos, Position {offset: offsets[4] as u64, command: 4}),
+            r => panic!("{:?}", r),
+        }
+        assert!(decode(bytes.len()).is_ok());
+    }
+}
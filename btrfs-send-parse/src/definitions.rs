@@ -1,10 +1,24 @@
 #![allow(non_camel_case_types)]
+use std::convert::TryFrom;
 
 pub const MAGIC: &str = "btrfs-stream\0";
 pub const MAGIC_LEN: usize = 13;
+/// Length of the stream header (magic and version).
+pub const STREAM_HEADER_LEN: usize = MAGIC_LEN + 4;
+/// Highest stream version understood by the reader.
+pub const MAX_VERSION: u32 = 3;
+/// Length of the command header (length, command type and CRC32C).
+pub const CMD_HEADER_LEN: usize = 10;
+/// Buffer of btrfs receive for v1 commands, the header included. Commands
+/// must be shorter than that.
+pub const SEND_BUF_SIZE_V1: usize = 64 << 10;
+/// Flags of the FALLOCATE_MODE attribute, the same as for fallocate(2).
+pub const FALLOC_FL_KEEP_SIZE: u32 = 0x01;
+pub const FALLOC_FL_PUNCH_HOLE: u32 = 0x02;
+pub const FALLOC_FL_ZERO_RANGE: u32 = 0x10;
 
 #[repr(u16)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Cmd {
 	UNSPEC,
 
@@ -36,11 +50,19 @@ pub enum Cmd {
 
 	END,
 	UPDATE_EXTENT,
+
+	/* Version 2 */
+	FALLOCATE,
+	FILEATTR,
+	ENCODED_WRITE,
+
+	/* Version 3 */
+	ENABLE_VERITY,
 	__MAX,
 }
 
 #[repr(u16)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Attr {
 	UNSPEC,
 
@@ -74,5 +96,69 @@ pub enum Attr {
 	CLONE_OFFSET,
 	CLONE_LEN,
 
+	/* Version 2 */
+	FALLOCATE_MODE,
+	FILEATTR,
+	UNENCODED_FILE_LEN,
+	UNENCODED_LEN,
+	UNENCODED_OFFSET,
+	COMPRESSION,
+	ENCRYPTION,
+
+	/* Version 3 */
+	VERITY_ALGORITHM,
+	VERITY_BLOCK_SIZE,
+	VERITY_SALT_DATA,
+	VERITY_SIG_DATA,
+
 	__MAX,
-}
\ No newline at end of file
+}
+
+impl Cmd {
+	/// First stream version in which the command can appear.
+	pub fn min_version(self) -> u32 {
+		match self {
+			Cmd::FALLOCATE | Cmd::FILEATTR | Cmd::ENCODED_WRITE => 2,
+			Cmd::ENABLE_VERITY => 3,
+			_ => 1,
+		}
+	}
+}
+
+/// Raw command or attribute number that has no matching definition.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct UnknownValue(pub u16);
+
+/* Both enums are numbered implicitly from zero, so the index is the value. */
+const CMDS: [Cmd; 27] = [
+	Cmd::UNSPEC, Cmd::SUBVOL, Cmd::SNAPSHOT, Cmd::MKFILE, Cmd::MKDIR, Cmd::MKNOD,
+	Cmd::MKFIFO, Cmd::MKSOCK, Cmd::SYMLINK, Cmd::RENAME, Cmd::LINK, Cmd::UNLINK,
+	Cmd::RMDIR, Cmd::SET_XATTR, Cmd::REMOVE_XATTR, Cmd::WRITE, Cmd::CLONE, Cmd::TRUNCATE,
+	Cmd::CHMOD, Cmd::CHOWN, Cmd::UTIMES, Cmd::END, Cmd::UPDATE_EXTENT, Cmd::FALLOCATE,
+	Cmd::FILEATTR, Cmd::ENCODED_WRITE, Cmd::ENABLE_VERITY
+];
+
+const ATTRS: [Attr; 36] = [
+	Attr::UNSPEC, Attr::UUID, Attr::CTRANSID, Attr::INO, Attr::SIZE, Attr::MODE, Attr::UID,
+	Attr::GID, Attr::RDEV, Attr::CTIME, Attr::MTIME, Attr::ATIME, Attr::OTIME,
+	Attr::XATTR_NAME, Attr::XATTR_DATA, Attr::PATH, Attr::PATH_TO, Attr::PATH_LINK,
+	Attr::FILE_OFFSET, Attr::DATA, Attr::CLONE_UUID, Attr::CLONE_CTRANSID,
+	Attr::CLONE_PATH, Attr::CLONE_OFFSET, Attr::CLONE_LEN, Attr::FALLOCATE_MODE,
+	Attr::FILEATTR, Attr::UNENCODED_FILE_LEN, Attr::UNENCODED_LEN, Attr::UNENCODED_OFFSET,
+	Attr::COMPRESSION, Attr::ENCRYPTION, Attr::VERITY_ALGORITHM, Attr::VERITY_BLOCK_SIZE,
+	Attr::VERITY_SALT_DATA, Attr::VERITY_SIG_DATA
+];
+
+impl TryFrom<u16> for Cmd {
+	type Error = UnknownValue;
+	fn try_from(c: u16) -> Result<Cmd, UnknownValue> {
+		CMDS.get(c as usize).cloned().ok_or(UnknownValue(c))
+	}
+}
+
+impl TryFrom<u16> for Attr {
+	type Error = UnknownValue;
+	fn try_from(a: u16) -> Result<Attr, UnknownValue> {
+		ATTRS.get(a as usize).cloned().ok_or(UnknownValue(a))
+	}
+}
@@ -0,0 +1,177 @@
+use std::io;
+use std::fmt;
+use std::error;
+use std::convert::TryFrom;
+use crate::definitions::{Cmd, Attr};
+use crate::safe_path::PathError;
+
+/// Location in the stream at which an error was detected.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Position {
+    /// Offset of the command (or stream header) from the start of the stream.
+    pub offset: u64,
+    /// Index of the command in the stream, starting at zero.
+    pub command: u64,
+}
+
+/// Limit of `Limits` that a stream exceeded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    CommandLen,
+    Attributes,
+    PathLen,
+    Memory,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Limit::CommandLen => "command length",
+            Limit::Attributes => "number of attributes",
+            Limit::PathLen => "path length",
+            Limit::Memory => "memory use",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// I/O error of the underlying reader.
+    Io { source: io::Error, pos: Position },
+    /// The stream does not start with the btrfs-stream magic.
+    BadMagic { pos: Position },
+    UnsupportedVersion { version: u32, pos: Position },
+    /// Command type not known to this library (only in strict mode).
+    UnknownCommand { cmd: u16, pos: Position },
+    /// Attribute type not known to this library (only in strict mode).
+    UnknownAttribute { cmd: Cmd, attr: u16, pos: Position },
+    MissingAttribute { cmd: Cmd, attr: Attr, pos: Position },
+    /// The attribute is present, but its value has the wrong size.
+    InvalidAttribute { cmd: Cmd, attr: Attr, pos: Position },
+    /// The stream ended in the middle of a command.
+    Truncated { pos: Position },
+    /// The attribute value is too long to be written (v1 limits all values to 64 KiB).
+    AttributeTooLong { cmd: Cmd, attr: Attr, pos: Position },
+    /// The command can not be written to a stream of this version.
+    UnsupportedCommand { cmd: Cmd, version: u32, pos: Position },
+    ChecksumMismatch { expected: u32, computed: u32, pos: Position },
+    /// The attribute lengths do not add up to the command length.
+    BadLength { pos: Position },
+    /// The stream ended without an END command, it was probably cut off.
+    MissingEnd { pos: Position },
+    /// A value in the stream is over a limit set in `ReaderOptions`, or a
+    /// command is too long to be written in the version of the stream.
+    LimitExceeded { limit: Limit, value: u64, max: u64, pos: Position },
+    /// The attribute was skipped when the command was read, so the command
+    /// can not be written.
+    SkippedPayload { cmd: Cmd, attr: Attr, pos: Position },
+    /// A path attribute was rejected by the `PathPolicy` of `ReaderOptions::paths`.
+    InvalidPath { attr: Attr, error: PathError, pos: Position },
+}
+
+impl Error {
+    pub(crate) fn from_io(e: io::Error, pos: Position) -> Error {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => Error::Truncated {pos},
+            _ => Error::Io {source: e, pos},
+        }
+    }
+    /* Attribute errors are created by TLVData, which does not know what command
+     * it belongs to. The reader fills that in using in_command. */
+    pub(crate) fn missing_attribute(attr: u16) -> Error {
+        let attr = Attr::try_from(attr).unwrap_or(Attr::UNSPEC);
+        Error::MissingAttribute {cmd: Cmd::UNSPEC, attr, pos: Position::default()}
+    }
+    pub(crate) fn invalid_attribute(attr: u16) -> Error {
+        let attr = Attr::try_from(attr).unwrap_or(Attr::UNSPEC);
+        Error::InvalidAttribute {cmd: Cmd::UNSPEC, attr, pos: Position::default()}
+    }
+    pub(crate) fn attribute_too_long(attr: Attr) -> Error {
+        Error::AttributeTooLong {cmd: Cmd::UNSPEC, attr, pos: Position::default()}
+    }
+    pub(crate) fn skipped_payload(attr: Attr) -> Error {
+        Error::SkippedPayload {cmd: Cmd::UNSPEC, attr, pos: Position::default()}
+    }
+    pub(crate) fn in_command(self, c: Cmd, p: Position) -> Error {
+        match self {
+            Error::AttributeTooLong {attr, ..} => Error::AttributeTooLong {cmd: c, attr, pos: p},
+            Error::MissingAttribute {attr, ..} => Error::MissingAttribute {cmd: c, attr, pos: p},
+            Error::InvalidAttribute {attr, ..} => Error::InvalidAttribute {cmd: c, attr, pos: p},
+            Error::SkippedPayload {attr, ..} => Error::SkippedPayload {cmd: c, attr, pos: p},
+            e => e,
+        }
+    }
+    pub fn position(&self) -> Position {
+        match *self {
+            Error::Io {pos, ..} |
+            Error::BadMagic {pos} |
+            Error::UnsupportedVersion {pos, ..} |
+            Error::UnknownCommand {pos, ..} |
+            Error::UnknownAttribute {pos, ..} |
+            Error::MissingAttribute {pos, ..} |
+            Error::InvalidAttribute {pos, ..} |
+            Error::Truncated {pos} |
+            Error::AttributeTooLong {pos, ..} |
+            Error::UnsupportedCommand {pos, ..} |
+            Error::ChecksumMismatch {pos, ..} |
+            Error::BadLength {pos} |
+            Error::MissingEnd {pos} |
+            Error::LimitExceeded {pos, ..} |
+            Error::SkippedPayload {pos, ..} |
+            Error::InvalidPath {pos, ..} => pos,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io {source, ..} => write!(f, "{}", source)?,
+            Error::BadMagic {..} => write!(f, "btrfs stream header does not match")?,
+            Error::UnsupportedVersion {version, ..} =>
+                write!(f, "unsupported btrfs stream version {}", version)?,
+            Error::UnknownCommand {cmd, ..} => write!(f, "unknown command type {}", cmd)?,
+            Error::UnknownAttribute {cmd, attr, ..} =>
+                write!(f, "unknown attribute type {} in {:?} command", attr, cmd)?,
+            Error::MissingAttribute {cmd, attr, ..} =>
+                write!(f, "attribute {:?} missing in {:?} command", attr, cmd)?,
+            Error::InvalidAttribute {cmd, attr, ..} =>
+                write!(f, "attribute {:?} has invalid size in {:?} command", attr, cmd)?,
+            Error::Truncated {..} => write!(f, "stream ends in the middle of a command")?,
+            Error::AttributeTooLong {cmd, attr, ..} =>
+                write!(f, "attribute {:?} is too long to encode in {:?} command", attr, cmd)?,
+            Error::UnsupportedCommand {cmd, version, ..} =>
+                write!(f, "{:?} command can not be used in stream version {}", cmd, version)?,
+            Error::ChecksumMismatch {expected, computed, ..} =>
+                write!(f, "checksum mismatch (expected {:08x}, computed {:08x})", expected, computed)?,
+            Error::BadLength {..} => write!(f, "attribute lengths do not match the command length")?,
+            Error::MissingEnd {..} => write!(f, "stream ends without the END command")?,
+            Error::LimitExceeded {limit, value, max, ..} =>
+                write!(f, "{} {} is over the limit of {}", limit, value, max)?,
+            Error::SkippedPayload {cmd, attr, ..} =>
+                write!(f, "attribute {:?} of {:?} command was skipped when reading", attr, cmd)?,
+            Error::InvalidPath {attr, error, ..} => write!(f, "attribute {:?} is not a safe path: {}", attr, error)?,
+        }
+        let pos = self.position();
+        write!(f, " (command {} at offset {})", pos.command, pos.offset)
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io {source, ..} => Some(source),
+            Error::InvalidPath {error, ..} => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io {source, ..} => source,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
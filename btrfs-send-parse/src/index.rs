@@ -0,0 +1,244 @@
+use std::io;
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::ops::Range;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crate::definitions::*;
+use crate::*;
+
+const INDEX_MAGIC: &[u8] = b"btrfs-index\0";
+const INDEX_VERSION: u32 = 1;
+/* Flags of an entry in the index file */
+const HAS_PATH: u8 = 1;
+const HAS_PATH_TO: u8 = 2;
+const HAS_RANGE: u8 = 4;
+
+/// Index entry of one command, enough to seek to it and read it again.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub location: CommandLocation,
+    /// Command type as in the header, see `Cmd`.
+    pub cmd: u16,
+    /// Index, header offset and version of the segment of the command.
+    pub segment: u32,
+    pub segment_offset: u64,
+    pub version: u32,
+    /// PATH attribute of the command.
+    pub path: Option<BtrfsString>,
+    /// PATH_TO attribute (new name of a Rename).
+    pub path_to: Option<BtrfsString>,
+    /// File range written, cloned, allocated or updated by the command.
+    pub range: Option<Range<u64>>,
+}
+
+impl IndexEntry {
+    fn new(location: CommandLocation, segment: &Segment, cmd: &commands::Unknown) -> IndexEntry {
+        let t = &cmd.data;
+        let string = |key: Attr| t.get_string(key as u16).ok();
+        /* DATA of an encoded write is compressed, its length is not the one in the file */
+        let len = match Cmd::try_from(cmd.header.cmd) {
+            Ok(Cmd::WRITE) => t.get(Attr::DATA as u16).ok().map(|data| data.len() as u64),
+            Ok(Cmd::ENCODED_WRITE) => t.get_u64(Attr::UNENCODED_FILE_LEN as u16).ok(),
+            Ok(Cmd::CLONE) => t.get_u64(Attr::CLONE_LEN as u16).ok(),
+            Ok(Cmd::FALLOCATE) | Ok(Cmd::UPDATE_EXTENT) => t.get_u64(Attr::SIZE as u16).ok(),
+            _ => None,
+        };
+        let range = t.get_u64(Attr::FILE_OFFSET as u16).ok().zip(len)
+            .map(|(start, len)| start..start.saturating_add(len));
+        IndexEntry {
+            location, cmd: cmd.header.cmd,
+            segment: segment.index, segment_offset: segment.offset, version: segment.version,
+            path: string(Attr::PATH), path_to: string(Attr::PATH_TO), range,
+        }
+    }
+    /// The command is about `path`, or a file below it.
+    pub fn touches_path(&self, path: &[u8]) -> bool {
+        let under = |p: &BtrfsString| {
+            let p = p.as_bytes();
+            p.starts_with(path) && (p.len() == path.len() || p[path.len()] == b'/')
+        };
+        self.path.as_ref().is_some_and(under) || self.path_to.as_ref().is_some_and(under)
+    }
+}
+
+/// Offsets, types and paths of the commands in a stream.
+///
+/// Built on a first pass over the stream and stored next to it, the index
+/// lets later passes seek straight to the commands of interest with
+/// `BtrfsReader::seek_command`. Paths are the ones in the commands, renames
+/// are not followed.
+#[derive(Clone, Debug, Default)]
+pub struct CommandIndex {
+    entries: Vec<IndexEntry>,
+}
+
+impl CommandIndex {
+    /// Indexes the rest of the stream.
+    pub fn build<R: Read>(r: &mut BtrfsReader<R>) -> Result<CommandIndex> {
+        let mut entries = Vec::new();
+        while let Some(cmd) = r.read_generic_command()? {
+            entries.push(IndexEntry::new(r.command_location(), r.segment(), &cmd));
+        }
+        Ok(CommandIndex {entries})
+    }
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+    /// Commands on `path` or on files below it.
+    pub fn touching_path<'a>(&'a self, path: &'a [u8]) -> impl Iterator<Item = &'a IndexEntry> + 'a {
+        self.entries.iter().filter(move |e| e.touches_path(path))
+    }
+    /// Commands on `path` or below it whose file range overlaps `range`.
+    pub fn touching_range<'a>(&'a self, path: &'a [u8], range: Range<u64>)
+            -> impl Iterator<Item = &'a IndexEntry> + 'a {
+        self.touching_path(path).filter(move |e| match e.range {
+            Some(ref r) => r.start < range.end && range.start < r.end,
+            None => false,
+        })
+    }
+    pub fn write_to<W: Write>(&self, w: W) -> io::Result<()> {
+        let mut w = io::BufWriter::new(w);
+        w.write_all(INDEX_MAGIC)?;
+        w.write_u32::<LittleEndian>(INDEX_VERSION)?;
+        w.write_u64::<LittleEndian>(self.entries.len() as u64)?;
+        for e in self.entries.iter() {
+            w.write_u64::<LittleEndian>(e.location.offset)?;
+            w.write_u64::<LittleEndian>(e.location.len)?;
+            w.write_u64::<LittleEndian>(e.location.command)?;
+            w.write_u16::<LittleEndian>(e.cmd)?;
+            w.write_u32::<LittleEndian>(e.segment)?;
+            w.write_u64::<LittleEndian>(e.segment_offset)?;
+            w.write_u32::<LittleEndian>(e.version)?;
+            let mut flags = 0;
+            if e.path.is_some() { flags |= HAS_PATH; }
+            if e.path_to.is_some() { flags |= HAS_PATH_TO; }
+            if e.range.is_some() { flags |= HAS_RANGE; }
+            w.write_u8(flags)?;
+            for p in e.path.iter().chain(e.path_to.iter()) {
+                w.write_u32::<LittleEndian>(p.len() as u32)?;
+                w.write_all(p.as_bytes())?;
+            }
+            if let Some(ref r) = e.range {
+                w.write_u64::<LittleEndian>(r.start)?;
+                w.write_u64::<LittleEndian>(r.end)?;
+            }
+        }
+        w.flush()
+    }
+    pub fn read_from<R: Read>(r: R) -> io::Result<CommandIndex> {
+        let mut r = io::BufReader::new(r);
+        let mut magic = [0u8; 12];
+        r.read_exact(&mut magic)?;
+        if magic != INDEX_MAGIC {
+            return Err(invalid_index("not a btrfs stream index"));
+        }
+        if r.read_u32::<LittleEndian>()? != INDEX_VERSION {
+            return Err(invalid_index("unsupported btrfs stream index version"));
+        }
+        let count = r.read_u64::<LittleEndian>()?;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let location = CommandLocation {
+                offset: r.read_u64::<LittleEndian>()?,
+                len: r.read_u64::<LittleEndian>()?,
+                command: r.read_u64::<LittleEndian>()?,
+            };
+            let cmd = r.read_u16::<LittleEndian>()?;
+            let segment = r.read_u32::<LittleEndian>()?;
+            let segment_offset = r.read_u64::<LittleEndian>()?;
+            let version = r.read_u32::<LittleEndian>()?;
+            let flags = r.read_u8()?;
+            let path = if flags & HAS_PATH != 0 { Some(read_string(&mut r)?) } else { None };
+            let path_to = if flags & HAS_PATH_TO != 0 { Some(read_string(&mut r)?) } else { None };
+            let range = if flags & HAS_RANGE != 0 {
+                Some(r.read_u64::<LittleEndian>()?..r.read_u64::<LittleEndian>()?)
+            } else {
+                None
+            };
+            entries.push(IndexEntry {location, cmd, segment, segment_offset, version, path, path_to, range});
+        }
+        Ok(CommandIndex {entries})
+    }
+}
+
+fn invalid_index(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/* The length comes from the file, so read what is there instead of trusting it */
+fn read_string(r: &mut dyn Read) -> io::Result<BtrfsString> {
+    let len = r.read_u32::<LittleEndian>()? as u64;
+    let mut buf = Vec::new();
+    r.take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(BtrfsString::from(buf))
+}
+
+impl<R: Read + Seek> BtrfsReader<R> {
+    /// Continues reading at the command of an index entry.
+    ///
+    /// Offsets are counted from the stream header, so the reader must have been
+    /// created at the start of the underlying file. The Subvol and Snapshot
+    /// commands of the segment are not known after seeking.
+    pub fn seek_command(&mut self, entry: &IndexEntry) -> Result<()> {
+        let pos = Position {offset: entry.location.offset, command: entry.location.command};
+        self.r.seek(SeekFrom::Start(pos.offset)).map_err(|e| Error::from_io(e, pos))?;
+        self.offset = pos.offset;
+        self.cmd_count = pos.command;
+        self.cmd_pos = pos;
+        self.segment = Segment::start(entry.segment, entry.segment_offset, entry.version);
+        self.pending = 0;
+        self.stream_crc = None;
+        self.failed = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{EncodedWrite, Payload, Truncate};
+
+    fn index(cmds: &[Command]) -> CommandIndex {
+        let mut w = BtrfsWriter::new(Vec::new(), 2).unwrap();
+        for c in cmds {
+            w.write_command(c).unwrap();
+        }
+        let stream = w.into_inner();
+        let mut r = BtrfsReader::new(&stream[..]).unwrap();
+        CommandIndex::build(&mut r).unwrap()
+    }
+
+    #[test]
+    fn encoded_write_range_is_unencoded_length() {
+        let idx = index(&[
+            Command::EncodedWrite(EncodedWrite {
+                path: BtrfsString::from(&b"f"[..]),
+                file_offset: 4096,
+                unencoded_file_len: 65536,
+                unencoded_len: 65536,
+                unencoded_offset: 0,
+                compression: 1,
+                encryption: 0,
+                data: Payload::from(vec![0x78; 100]),
+            }),
+            Command::Write(commands::Write {
+                path: BtrfsString::from(&b"f"[..]),
+                file_offset: 0,
+                data: Payload::from(vec![0; 10]),
+            }),
+        ]);
+        let e = idx.entries();
+        assert_eq!(e[0].range, Some(4096..69632));
+        assert_eq!(e[1].range, Some(0..10));
+        let hits: Vec<_> = idx.touching_range(b"f", 60000..60001).map(|e| e.cmd).collect();
+        assert_eq!(hits, vec![Cmd::ENCODED_WRITE as u16]);
+    }
+
+    #[test]
+    fn truncate_has_no_range() {
+        let idx = index(&[Command::Truncate(Truncate {path: BtrfsString::from(&b"f"[..]), size: 10})]);
+        assert_eq!(idx.entries()[0].range, None);
+    }
+}
@@ -1,18 +1,137 @@
 extern crate byteorder;
+extern crate crc32c;
 pub mod definitions;
 pub mod commands;
+pub mod borrowed;
+mod error;
+mod string;
+mod writer;
+mod decoder;
+mod segment;
+mod index;
+mod salvage;
+mod payload;
+mod tracker;
+mod stream_fs;
+mod changes;
+mod safe_path;
+#[cfg(feature = "tokio")]
+mod async_reader;
+#[cfg(feature = "receive")]
+mod receive;
 use definitions::*;
+pub use error::{Error, Limit, Position};
+pub use string::{BtrfsString, BtrfsStr};
+pub use writer::BtrfsWriter;
+pub use decoder::Decoder;
+pub use segment::Segment;
+pub use index::{CommandIndex, IndexEntry};
+pub use salvage::{SalvageReader, Salvaged};
+pub use borrowed::SliceReader;
+pub use payload::{PayloadReader, StreamedCommand};
+pub use tracker::{PathTracker, NodeId, OrphanName};
+pub use stream_fs::{StreamFs, Inode, FileKind};
+pub use changes::{ChangeSet, ChangeSetBuilder, Change, FileChange, MetadataChange};
+pub use safe_path::{SafePath, PathPolicy, PathError};
+#[cfg(feature = "tokio")]
+pub use async_reader::AsyncBtrfsReader;
+#[cfg(feature = "receive")]
+pub use receive::{Receiver, ReceiveOptions, ReceiveError};
 
 use std::io;
-use std::io::{Read, Cursor};
-use byteorder::{LittleEndian, ReadBytesExt};
+use std::io::{BufRead, Read, Seek, Cursor};
+use std::convert::TryFrom;
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
 
-pub type Error = io::Error;
-pub type Result<T> = io::Result<T>;
+pub type Result<T> = std::result::Result<T, Error>;
 
-pub struct BtrfsReader<'a> {
-    r: &'a mut Read,
-    version: u32,
+/* Commands are small, so the input is read in larger chunks */
+const READ_BUF_SIZE: usize = 64 * 1024;
+
+/* Moves the input forward, given only for inputs that implement Seek */
+type SeekFn<R> = fn(&mut io::BufReader<R>, i64) -> io::Result<()>;
+
+pub struct BtrfsReader<R: Read> {
+    r: io::BufReader<R>,
+    opts: ReaderOptions,
+    /* Payload of the last command, reused to avoid allocations */
+    buf: Vec<u8>,
+    /* Length of the value skipped or streamed in the last command */
+    skipped: Option<u64>,
+    /* Bytes of a streamed value not read yet, skipped before the next command */
+    pending: u64,
+    /* Checksum state of a streamed command and its expected value, until
+     * the value was read and verified */
+    stream_crc: Option<(u32, u32)>,
+    /* Set if skipped values can be seeked over */
+    seek: Option<SeekFn<R>>,
+    /* Current segment, holds the stream version */
+    segment: Segment,
+    /* Position in the stream and number of commands read so far */
+    offset: u64,
+    cmd_count: u64,
+    /* Position of the last command read */
+    cmd_pos: Position,
+    cmd_loc: CommandLocation,
+    /* The iterator stops after the first error */
+    failed: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReaderOptions {
+    /// Verify the CRC32C checksum of every command (enabled by default).
+    pub verify_crc: bool,
+    /// Fail on commands and attributes this library does not know, instead of
+    /// returning them as `Command::Unknown` or ignoring them.
+    pub strict: bool,
+    /// Read further streams that follow the END command, as written by
+    /// `btrfs send` with several subvolumes (enabled by default).
+    pub concatenated: bool,
+    /// Do not read the data of Write and EncodedWrite commands and the value
+    /// of SetXattr commands, only report their length as `Payload::Skipped`.
+    ///
+    /// The checksum of these commands is not verified. Only `read_command`
+    /// and `read_command_ref` of `BtrfsReader` skip, other readers and
+    /// `read_generic_command` return the data as usual.
+    pub skip_payload: bool,
+    pub limits: Limits,
+    /// Check the PATH, PATH_TO, PATH_LINK and CLONE_PATH attributes of every
+    /// command with this policy, failing with `Error::InvalidPath` (not
+    /// checked by default).
+    pub paths: Option<PathPolicy>,
+}
+
+impl Default for ReaderOptions {
+    fn default() -> Self {
+        ReaderOptions {verify_crc: true, strict: false, concatenated: true, skip_payload: false,
+                       limits: Limits::default(), paths: None}
+    }
+}
+
+/// Bounds on what a stream can make the reader allocate.
+///
+/// The defaults are well above anything the kernel produces.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// Length of a command, header excluded.
+    pub max_command_len: u32,
+    /// Number of attributes in a command.
+    pub max_attributes: usize,
+    /// Length of PATH, PATH_TO, PATH_LINK and CLONE_PATH attributes.
+    pub max_path_len: usize,
+    /// Memory held for one command, its payload and decoded attributes.
+    pub max_memory: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_command_len: 16 << 20,
+            max_attributes: 256,
+            max_path_len: 4096,
+            max_memory: 64 << 20,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -40,15 +159,40 @@ pub enum Command {
     Utimes(commands::Utimes),
     UpdateExtent(commands::UpdateExtent),
     End(commands::End),
+    Fallocate(commands::Fallocate),
+    FileAttr(commands::FileAttr),
+    EncodedWrite(commands::EncodedWrite),
+    EnableVerity(commands::EnableVerity),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct CommandHeader {
     pub len: u32,
     pub cmd: u16,
     pub crc32: u32,
 }
 
+/// Where a command is stored in the input.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CommandLocation {
+    /// Offset of the command header from the start of the input.
+    pub offset: u64,
+    /// Encoded length of the command, header included.
+    pub len: u64,
+    /// Index of the command in the input, starting at zero.
+    pub command: u64,
+}
+
+impl CommandLocation {
+    pub(crate) fn new(pos: Position, header: &CommandHeader) -> CommandLocation {
+        CommandLocation {offset: pos.offset, len: (CMD_HEADER_LEN as u64) + header.len as u64, command: pos.command}
+    }
+    /// Offset just past the command.
+    pub fn end(&self) -> u64 {
+        self.offset + self.len
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct TLVData {
     pub entries: Vec<TLVEntry>,
@@ -71,71 +215,59 @@ pub struct Timespec {
     pub nsec: u32,
 }
 
-pub type BtrfsString = String;
-
-fn invalid_data<T>(err: &str) -> Result<T> {
-    panic!("Test");
-    return Err(io::Error::new(io::ErrorKind::InvalidData, err));
-}
-
-impl From<u16> for Cmd {
-	fn from(c: u16) -> Cmd{
-		unsafe {
-			std::mem::transmute_copy::<u16, Cmd>(&c)
-		}
-	}
-}
-
-impl From<u16> for Attr {
-	fn from(c: u16) -> Attr{
-		unsafe {
-			std::mem::transmute_copy::<u16, Attr>(&c)
-		}
-	}
-}
-
 impl TLVData {
+    pub fn contains(&self, key: u16) -> bool {
+        self.entries.iter().any(|e| e.key == key)
+    }
     pub fn get(&self, key: u16) -> Result<&Vec<u8>> {
         for e in self.entries.iter() {
             if e.key == key {
                 return Ok(&e.value)
             }
         }
-        invalid_data(&format!("item {} does not exist", key))
+        Err(Error::missing_attribute(key))
     }
     pub fn get_u8(&self, key: u16) -> Result<u8> {
-        match Cursor::new(try!(self.get(key)).clone()).bytes().next() {
-            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
-            Some(x) => x,
+        match self.get(key)?.first() {
+            None => Err(Error::invalid_attribute(key)),
+            Some(x) => Ok(*x),
         }
     }
     pub fn get_u16(&self, key: u16) -> Result<u16> {
-        Cursor::new(try!(self.get(key))).read_u16::<LittleEndian>()
+        Cursor::new(self.get(key)?).read_u16::<LittleEndian>()
+            .map_err(|_| Error::invalid_attribute(key))
     }
     pub fn get_u32(&self, key: u16) -> Result<u32> {
-        Cursor::new(try!(self.get(key))).read_u32::<LittleEndian>()
+        Cursor::new(self.get(key)?).read_u32::<LittleEndian>()
+            .map_err(|_| Error::invalid_attribute(key))
     }
     pub fn get_u64(&self, key: u16) -> Result<u64> {
-        Cursor::new(try!(self.get(key))).read_u64::<LittleEndian>()
+        Cursor::new(self.get(key)?).read_u64::<LittleEndian>()
+            .map_err(|_| Error::invalid_attribute(key))
     }
-    pub fn get_string(&self, key: u16) -> Result<BtrfsString> {
-        // TODO: make the conversion function configurable
-        match String::from_utf8(try!(self.get(key)).clone()) {
-            Ok(x) => Ok(x),
-            Err(_   ) => invalid_data("utf-8 decoding problem"),
+    /* For attributes that are optional or not sent by all producers */
+    pub fn get_u64_or_zero(&self, key: u16) -> Result<u64> {
+        if !self.contains(key) {
+            return Ok(0)
         }
+        self.get_u64(key)
+    }
+    pub fn get_string(&self, key: u16) -> Result<BtrfsString> {
+        Ok(BtrfsString::from(self.get(key)?.as_slice()))
     }
     pub fn get_timespec(&self, key: u16) -> Result<Timespec> {
-        let mut r = Cursor::new(try!(self.get(key)));
-        Ok(Timespec {
-            sec: try!(r.read_u64::<LittleEndian>()),
-            nsec: try!(r.read_u32::<LittleEndian>()),
-        })
+        let mut r = Cursor::new(self.get(key)?);
+        let ts = (|| Ok(Timespec {
+            sec: r.read_u64::<LittleEndian>()?,
+            nsec: r.read_u32::<LittleEndian>()?,
+        }))();
+        ts.map_err(|_: io::Error| Error::invalid_attribute(key))
     }
     pub fn get_uuid(&self, key: u16) -> Result<Uuid> {
-        let data = try!(self.get(key));
+        let data = self.get(key)?;
         let mut uuid = Uuid{data: [0u8; UUID_SIZE]};
-        try!(Cursor::new(data).read_exact(&mut uuid.data));
+        Cursor::new(data).read_exact(&mut uuid.data)
+            .map_err(|_| Error::invalid_attribute(key))?;
         Ok(uuid)
     }
     pub fn uuid(&self) -> Result<Uuid> {
@@ -180,6 +312,10 @@ impl TLVData {
     pub fn gid(&self) -> Result<u64> {
         self.get_u64(Attr::GID as u16)
     }
+    /* Inode number of newly created files, 0 if omitted */
+    pub fn ino(&self) -> Result<u64> {
+        self.get_u64_or_zero(Attr::INO as u16)
+    }
     pub fn rdev(&self) -> Result<u64> {
         self.get_u64(Attr::RDEV as u16)
     }
@@ -204,199 +340,926 @@ impl TLVData {
     pub fn ctime(&self) -> Result<Timespec> {
         self.get_timespec(Attr::CTIME as u16)
     }
+    pub fn fallocate_mode(&self) -> Result<u32> {
+        self.get_u32(Attr::FALLOCATE_MODE as u16)
+    }
+    pub fn fileattr(&self) -> Result<u64> {
+        self.get_u64(Attr::FILEATTR as u16)
+    }
+    pub fn unencoded_file_len(&self) -> Result<u64> {
+        self.get_u64(Attr::UNENCODED_FILE_LEN as u16)
+    }
+    pub fn unencoded_len(&self) -> Result<u64> {
+        self.get_u64(Attr::UNENCODED_LEN as u16)
+    }
+    pub fn unencoded_offset(&self) -> Result<u64> {
+        self.get_u64(Attr::UNENCODED_OFFSET as u16)
+    }
+    /* Compression and encryption default to none (0) if omitted */
+    pub fn compression(&self) -> Result<u32> {
+        if !self.contains(Attr::COMPRESSION as u16) {
+            return Ok(0)
+        }
+        self.get_u32(Attr::COMPRESSION as u16)
+    }
+    pub fn encryption(&self) -> Result<u32> {
+        if !self.contains(Attr::ENCRYPTION as u16) {
+            return Ok(0)
+        }
+        self.get_u32(Attr::ENCRYPTION as u16)
+    }
+    pub fn verity_algorithm(&self) -> Result<u8> {
+        self.get_u8(Attr::VERITY_ALGORITHM as u16)
+    }
+    pub fn verity_block_size(&self) -> Result<u32> {
+        self.get_u32(Attr::VERITY_BLOCK_SIZE as u16)
+    }
+    pub fn verity_salt_data(&self) -> Result<&Vec<u8>> {
+        self.get(Attr::VERITY_SALT_DATA as u16)
+    }
+    pub fn verity_sig_data(&self) -> Result<&Vec<u8>> {
+        self.get(Attr::VERITY_SIG_DATA as u16)
+    }
+}
+
+/* The kernel computes CRC32C with zero seed and no final inversion, over the
+ * command header (with the crc field zeroed) followed by the payload. */
+pub(crate) fn command_crc32(header: &CommandHeader, payload: &[u8]) -> u32 {
+    !crc32c::crc32c_append(command_crc32_start(header), payload)
+}
+
+/* Checksum state after the header, the payload is appended with crc32c_append */
+pub(crate) fn command_crc32_start(header: &CommandHeader) -> u32 {
+    let mut hdr = [0u8; CMD_HEADER_LEN];
+    LittleEndian::write_u32(&mut hdr[0..4], header.len);
+    LittleEndian::write_u16(&mut hdr[4..6], header.cmd);
+    crc32c::crc32c_append(!0, &hdr)
+}
+
+/* `memory` is what the caller already holds for the command. Every length is
+ * checked against the command length and the limits before it is allocated. */
+fn read_tlvs_from(r: &mut dyn Read, version: u32, len_to_read: u32, limits: &Limits,
+                  paths: Option<&PathPolicy>, mut memory: u64, pos: Position) -> Result<TLVData> {
+    let mut tlv_data = TLVData {entries:Vec::new()};
+    let mut remaining = len_to_read;
+    let limit = |limit, value: u64, max: u64| Error::LimitExceeded {limit, value, max, pos};
+    while remaining > 0 {
+        if tlv_data.entries.len() >= limits.max_attributes {
+            let n = tlv_data.entries.len() as u64 + 1;
+            return Err(limit(Limit::Attributes, n, limits.max_attributes as u64));
+        }
+        if remaining < 2 {
+            return Err(Error::BadLength {pos});
+        }
+        let key = r.read_u16::<LittleEndian>().map_err(|e| Error::from_io(e, pos))?;
+        remaining -= 2;
+        /* Since v2, DATA has no length and spans the rest of the command */
+        let len = if version >= 2 && key == Attr::DATA as u16 {
+            remaining
+        } else {
+            if remaining < 2 {
+                return Err(Error::BadLength {pos});
+            }
+            remaining -= 2;
+            r.read_u16::<LittleEndian>().map_err(|e| Error::from_io(e, pos))? as u32
+        };
+        if len > remaining {
+            return Err(Error::BadLength {pos});
+        }
+        if is_path_attr(key) && len as usize > limits.max_path_len {
+            return Err(limit(Limit::PathLen, len as u64, limits.max_path_len as u64));
+        }
+        memory += (len as usize + std::mem::size_of::<TLVEntry>()) as u64;
+        if memory > limits.max_memory {
+            return Err(limit(Limit::Memory, memory, limits.max_memory));
+        }
+        let mut data = vec![0; len as usize];
+        r.read_exact(data.as_mut_slice()).map_err(|e| Error::from_io(e, pos))?;
+        check_path(paths, key, &data, pos)?;
+        tlv_data.entries.push(TLVEntry{key, value: data});
+        remaining -= len;
+    }
+    Ok(tlv_data)
+}
+
+pub(crate) fn is_path_attr(key: u16) -> bool {
+    [Attr::PATH, Attr::PATH_TO, Attr::PATH_LINK, Attr::CLONE_PATH].iter().any(|&a| a as u16 == key)
+}
+
+pub(crate) fn check_path(paths: Option<&PathPolicy>, key: u16, value: &[u8], pos: Position) -> Result<()> {
+    match paths {
+        Some(policy) if is_path_attr(key) => match SafePath::with_policy(value, policy) {
+            Ok(_) => Ok(()),
+            Err(error) => {
+                let attr = Attr::try_from(key).unwrap_or(Attr::UNSPEC);
+                Err(Error::InvalidPath {attr, error, pos})
+            },
+        },
+        _ => Ok(()),
+    }
+}
+
+/* Checked as soon as the header is read, before the payload is allocated */
+pub(crate) fn check_command_header(opts: &ReaderOptions, header: &CommandHeader, pos: Position) -> Result<()> {
+    let limits = &opts.limits;
+    if header.len > limits.max_command_len {
+        return Err(Error::LimitExceeded {limit: Limit::CommandLen, value: header.len as u64,
+                                         max: limits.max_command_len as u64, pos});
+    }
+    if header.len as u64 > limits.max_memory {
+        return Err(Error::LimitExceeded {limit: Limit::Memory, value: header.len as u64,
+                                         max: limits.max_memory, pos});
+    }
+    Ok(())
+}
+
+/* Decoding shared by BtrfsReader and AsyncBtrfsReader, which differ only in
+ * how they get the bytes */
+pub(crate) fn parse_stream_header(buf: &[u8], pos: Position) -> Result<u32> {
+    if &buf[..MAGIC_LEN] != MAGIC.as_bytes() {
+        return Err(Error::BadMagic {pos});
+    }
+    let version = LittleEndian::read_u32(&buf[MAGIC_LEN..STREAM_HEADER_LEN]);
+    if version == 0 || version > MAX_VERSION {
+        return Err(Error::UnsupportedVersion {version, pos});
+    }
+    Ok(version)
+}
+
+pub(crate) fn parse_command_header(buf: &[u8]) -> CommandHeader {
+    CommandHeader {
+        len: LittleEndian::read_u32(&buf[0..4]),
+        cmd: LittleEndian::read_u16(&buf[4..6]),
+        crc32: LittleEndian::read_u32(&buf[6..10]),
+    }
+}
+
+pub(crate) fn verify_crc(opts: &ReaderOptions, header: &CommandHeader, payload: &[u8], pos: Position) -> Result<()> {
+    if opts.verify_crc {
+        let computed = command_crc32(header, payload);
+        if computed != header.crc32 {
+            return Err(Error::ChecksumMismatch {expected: header.crc32, computed, pos});
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn decode_generic_command(opts: &ReaderOptions, version: u32, pos: Position,
+                                     header: CommandHeader, payload: &[u8]) -> Result<commands::Unknown> {
+    verify_crc(opts, &header, payload, pos)?;
+    let data = read_tlvs_from(&mut Cursor::new(payload), version, header.len, &opts.limits,
+                              opts.paths.as_ref(), payload.len() as u64, pos)?;
+    Ok(commands::Unknown {header, data})
+}
+
+/* The command type, or None for commands returned as Unknown. In strict
+ * mode, unknown commands and attributes are errors instead. */
+pub(crate) fn check_known(opts: &ReaderOptions, cmd: u16, keys: impl Iterator<Item = u16>,
+                          pos: Position) -> Result<Option<Cmd>> {
+    let c = match Cmd::try_from(cmd) {
+        Ok(Cmd::UNSPEC) | Err(_) => {
+            if opts.strict {
+                return Err(Error::UnknownCommand {cmd, pos});
+            }
+            return Ok(None);
+        },
+        Ok(c) => c,
+    };
+    if opts.strict {
+        for key in keys {
+            match Attr::try_from(key) {
+                Ok(Attr::UNSPEC) | Err(_) =>
+                    return Err(Error::UnknownAttribute {cmd: c, attr: key, pos}),
+                Ok(_) => {},
+            }
+        }
+    }
+    Ok(Some(c))
+}
+
+/* Known commands are encoded again for the borrowing parser, as they were read */
+pub(crate) fn decode_command(opts: &ReaderOptions, version: u32, pos: Position, cmd: commands::Unknown) -> Result<Command> {
+    let c = match check_known(opts, cmd.header.cmd, cmd.data.entries.iter().map(|e| e.key), pos)? {
+        Some(c) => c,
+        None => return Ok(Command::Unknown(cmd)),
+    };
+    let payload = writer::encode_tlv_data(&cmd.data, version).map_err(|e| e.in_command(c, pos))?;
+    let cmd = borrowed::Unknown {header: cmd.header, data: borrowed::Tlvs::new(&payload, version)};
+    borrowed::parse_known_command(c, cmd)
+        .map(borrowed::Command::into_owned)
+        .map_err(|e| e.in_command(c, pos))
 }
 
 /* Returns if eof was encountered on first read (true = eof) */
-fn read_exact_with_eof(r: &mut io::Read, buf: &mut [u8]) -> Result<bool>{
-    if buf.len() == 0 {
+fn read_exact_with_eof(r: &mut dyn io::Read, buf: &mut [u8]) -> io::Result<bool>{
+    if buf.is_empty() {
         return Ok(false)
     }
-    let size = try!(r.read(buf));
+    let size = r.read(buf)?;
     if size == 0 {
         return Ok(true)
     }
-    try!(r.read_exact(&mut buf[size..]));
+    r.read_exact(&mut buf[size..])?;
     Ok(false)
 }
 
-impl<'a> BtrfsReader<'a> {
-    pub fn new(r: &mut Read) -> Result<BtrfsReader> {
-        let mut magic_buf = [0u8; MAGIC_LEN];
-        try!(r.read_exact(&mut magic_buf));
-        if magic_buf != MAGIC.as_bytes() {
-            return invalid_data("btrfs stream header does not match");
-        }
-        let version = try!(r.read_u32::<LittleEndian>());
-        if version != 1 {
-            return invalid_data("unsupported btrfs stream version");
-        }
-        Ok(BtrfsReader{r: r, version: version})
+impl<R: Read> BtrfsReader<R> {
+    pub fn new(r: R) -> Result<BtrfsReader<R>> {
+        BtrfsReader::with_options(r, ReaderOptions::default())
+    }
+    pub fn with_options(r: R, opts: ReaderOptions) -> Result<BtrfsReader<R>> {
+        let pos = Position::default();
+        let mut r = io::BufReader::with_capacity(READ_BUF_SIZE, r);
+        let mut buf = [0u8; STREAM_HEADER_LEN];
+        r.read_exact(&mut buf).map_err(|e| Error::from_io(e, pos))?;
+        let version = parse_stream_header(&buf, pos)?;
+        Ok(BtrfsReader{r, opts, buf: Vec::new(), skipped: None, pending: 0, stream_crc: None, seek: None,
+                       segment: Segment::start(0, 0, version),
+                       offset: STREAM_HEADER_LEN as u64, cmd_count: 0, cmd_pos: pos,
+                       cmd_loc: CommandLocation::default(), failed: false})
+    }
+    pub fn get_ref(&self) -> &R {
+        self.r.get_ref()
+    }
+    /// The input is buffered, reading from it directly skips the buffered data.
+    pub fn get_mut(&mut self) -> &mut R {
+        self.r.get_mut()
+    }
+    /// Data buffered, but not read yet, is lost.
+    pub fn into_inner(self) -> R {
+        self.r.into_inner()
     }
+    /// Version of the current segment.
     pub fn version(&self) -> u32 {
-        self.version
+        self.segment.version
+    }
+    /// Segment of the last command read.
+    pub fn segment(&self) -> &Segment {
+        &self.segment
+    }
+    /// Location of the last command whose header was read.
+    pub fn command_location(&self) -> CommandLocation {
+        self.cmd_loc
+    }
+    /// Number of bytes consumed from the input so far.
+    pub fn bytes_consumed(&self) -> u64 {
+        self.offset
+    }
+    /// Call after the last command was read. A clean end of input is not
+    /// enough to tell a complete stream from one that was cut off at a
+    /// command boundary, so this fails unless the END command was read.
+    /// Data of the last command that was not read is verified first.
+    pub fn finish(&mut self) -> Result<()> {
+        if self.pending > 0 || self.stream_crc.is_some() {
+            self.skip_streamed()?;
+        }
+        self.segment.check_end(Position {offset: self.offset, command: self.cmd_count})
     }
     pub fn read_command(&mut self) -> Result<Option<Command>> {
-        let cmd = try!(self.read_generic_command());
-        match cmd {
-            Some(cmd) => self.parse_command(cmd).map(|x| Some(x)),
-            None => Ok(None)
+        Ok(self.read_command_ref()?.map(borrowed::Command::into_owned))
+    }
+    /// Reads the next command without copying its paths and data.
+    ///
+    /// The command borrows from a buffer that is reused for the next one, so
+    /// nothing is allocated per command.
+    pub fn read_command_ref(&mut self) -> Result<Option<borrowed::Command<'_>>> {
+        let mode = if self.opts.skip_payload { DataMode::Skip } else { DataMode::Read };
+        match self.read_command_buf(mode)? {
+            Some(header) => self.decode_command_buf(header).map(Some),
+            None => Ok(None),
         }
     }
-    pub fn parse_command(&self, cmd: commands::Unknown) -> Result<Command> {
-        {
-            // println!("Parsing {}", cmd.header.cmd);
-            let t = &cmd.data;
-            match Cmd::from(cmd.header.cmd) {
-                Cmd::SUBVOL => return Ok(Command::Subvol(commands::Subvol {
-                    path: try!(t.path()),
-                    uuid: try!(t.uuid()),
-                    ctransid: try!(t.ctransid()),
-                })),
-                Cmd::SNAPSHOT => return Ok(Command::Snapshot(commands::Snapshot {
-                    path: try!(t.path()),
-                    uuid: try!(t.uuid()),
-                    ctransid: try!(t.ctransid()),
-                    clone_ctransid: try!(t.clone_ctransid()),
-                    clone_uuid: try!(t.clone_uuid()),
-                })),
-                Cmd::MKFILE => return Ok(Command::MkFile(commands::MkFile {
-                    path: try!(t.path()),
-                })),
-                Cmd::MKNOD => return Ok(Command::MkNod(commands::MkNod {
-                    path: try!(t.path()),
-                    mode: try!(t.mode()),
-                    rdev: try!(t.rdev()),
-                })),
-                Cmd::MKFIFO => return Ok(Command::MkFifo(commands::MkFifo {
-                    path: try!(t.path())
-                })),
-                Cmd::MKSOCK => return Ok(Command::MkSock(commands::MkSock {
-                    path: try!(t.path())
-                })),
-                Cmd::SYMLINK => return Ok(Command::SymLink(commands::SymLink {
-                    path: try!(t.path()),
-                    path_link: try!(t.path_link()),
-                })),
-                Cmd::RENAME => return Ok(Command::Rename(commands::Rename {
-                    path: try!(t.path()),
-                    path_to: try!(t.path_to()),
-                })),
-                Cmd::LINK => return Ok(Command::Link(commands::Link {
-                    path: try!(t.path()),
-                    path_link: try!(t.path_link()),
-                })),
-                Cmd::UNLINK => return Ok(Command::UnLink(commands::UnLink {
-                    path: try!(t.path()),
-                })),
-                Cmd::RMDIR => return Ok(Command::RmDir(commands::RmDir {
-                    path: try!(t.path()),
-                })),
-                Cmd::WRITE => return Ok(Command::Write(commands::Write {
-                    path: try!(t.path()),
-                    file_offset: try!(t.file_offset()),
-                    data: try!(t.data()).clone(),
-                })),
-                Cmd::CLONE => return Ok(Command::Clone(commands::Clone {
-                    path: try!(t.path()),
-                    file_offset: try!(t.file_offset()),
-                    clone_len: try!(t.clone_len()),
-                    clone_uuid: try!(t.clone_uuid()),
-                    clone_ctransid: try!(t.clone_ctransid()),
-                    clone_path: try!(t.clone_path()),
-                    clone_offset: try!(t.clone_offset()),
-                })),
-                Cmd::SET_XATTR => return Ok(Command::SetXattr(commands::SetXattr {
-                    path: try!(t.path()),
-                    xattr_name: try!(t.xattr_name()),
-                    xattr_data: try!(t.xattr_data()).clone(),
-                })),
-                Cmd::REMOVE_XATTR => return Ok(Command::RemoveXattr(commands::RemoveXattr {
-                    path: try!(t.path()),
-                    xattr_name: try!(t.xattr_name()),
-                })),
-                Cmd::TRUNCATE => return Ok(Command::Truncate(commands::Truncate {
-                    path: try!(t.path()),
-                    size: try!(t.size()),
-                })),
-                Cmd::CHMOD => return Ok(Command::Chmod(commands::Chmod {
-                    path: try!(t.path()),
-                    mode: try!(t.mode()),
-                    
-                })),
-                Cmd::CHOWN => return Ok(Command::Chown(commands::Chown {
-                    path: try!(t.path()),
-                    uid: try!(t.uid()),
-                    gid: try!(t.gid()),
-                    
-                })),
-                Cmd::UTIMES => return Ok(Command::Utimes(commands::Utimes {
-                    path: try!(t.path()),
-                    atime: try!(t.atime()),
-                    mtime: try!(t.mtime()),
-                    ctime: try!(t.ctime()),
-                })),
-                Cmd::UPDATE_EXTENT => return Ok(Command::UpdateExtent(commands::UpdateExtent {
-                    path: try!(t.path()),
-                    file_offset: try!(t.file_offset()),
-                    size: try!(t.size()),
-                })),
-                Cmd::END => return Ok(Command::End(commands::End{})),
-                _ => {}
+    /* Reads and checks the next command, leaving it in the buffer */
+    pub(crate) fn read_command_buf(&mut self, mode: DataMode) -> Result<Option<CommandHeader>> {
+        let header = match self.read_payload(mode)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let pos = self.cmd_pos;
+        let version = self.segment.version;
+        /* The checksum covers the skipped value */
+        if self.skipped.is_none() {
+            verify_crc(&self.opts, &header, &self.buf, pos)?;
+        }
+        borrowed::check_tlvs(&self.buf, version, &self.opts.limits, self.opts.paths.as_ref(), pos)?;
+        let cmd = borrowed::Unknown {header, data: borrowed::Tlvs::new(&self.buf, version)};
+        self.segment.note_command(&cmd, pos);
+        self.cmd_count += 1;
+        Ok(Some(header))
+    }
+    pub(crate) fn decode_command_buf(&self, header: CommandHeader) -> Result<borrowed::Command<'_>> {
+        let cmd = borrowed::Unknown {header, data: borrowed::Tlvs::new(&self.buf, self.segment.version)};
+        let mut cmd = borrowed::decode_command(&self.opts, self.cmd_pos, cmd)?;
+        if let Some(len) = self.skipped {
+            match cmd {
+                borrowed::Command::Write(ref mut c) => c.data = borrowed::Payload::Skipped(len),
+                borrowed::Command::SetXattr(ref mut c) => c.xattr_data = borrowed::Payload::Skipped(len),
+                borrowed::Command::EncodedWrite(ref mut c) => c.data = borrowed::Payload::Skipped(len),
+                _ => {},
             }
         }
-        Ok(Command::Unknown(cmd))
+        Ok(cmd)
+    }
+    pub fn parse_command(&self, cmd: commands::Unknown) -> Result<Command> {
+        decode_command(&self.opts, self.segment.version, self.cmd_pos, cmd)
     }
     pub fn read_generic_command(&mut self) -> Result<Option<commands::Unknown>> {
-        Ok(match try!(self.read_command_header()) {
-            Some(header) => Some(commands::Unknown {
-                header: header.clone(),
-                data: try!(self.read_tlvs(header.len)),
-            }),
-            None => None,
-        })  
+        let header = match self.read_payload(DataMode::Read)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let pos = self.cmd_pos;
+        let version = self.segment.version;
+        let cmd = decode_generic_command(&self.opts, version, pos, header, &self.buf)?;
+        self.segment.note_command(&borrowed::Unknown {header, data: borrowed::Tlvs::new(&self.buf, version)}, pos);
+        self.cmd_count += 1;
+        Ok(Some(cmd))
+    }
+    /* Reads the next command into the reusable buffer */
+    fn read_payload(&mut self, mode: DataMode) -> Result<Option<CommandHeader>> {
+        let header = match self.read_command_header()? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let pos = self.cmd_pos;
+        self.skipped = None;
+        match skipped_attr(header.cmd) {
+            Some(attr) if mode != DataMode::Read => self.read_skipping(&header, attr, mode)?,
+            _ => {
+                self.buf.resize(header.len as usize, 0);
+                self.r.read_exact(&mut self.buf).map_err(|e| Error::from_io(e, pos))?;
+            },
+        }
+        self.offset += header.len as u64;
+        Ok(Some(header))
+    }
+    /* Reads the attributes one by one and leaves out the value of `attr`,
+     * which is kept in the buffer as an empty attribute. When streaming, the
+     * value is left in the input, unless other attributes follow it. */
+    fn read_skipping(&mut self, header: &CommandHeader, attr: Attr, mode: DataMode) -> Result<()> {
+        let pos = self.cmd_pos;
+        let version = self.segment.version;
+        let mut remaining = header.len as u64;
+        let mut crc = command_crc32_start(header);
+        self.buf.clear();
+        while remaining > 0 {
+            let mut tlv = [0u8; 4];
+            if remaining < 2 {
+                return Err(Error::BadLength {pos});
+            }
+            self.r.read_exact(&mut tlv[..2]).map_err(|e| Error::from_io(e, pos))?;
+            remaining -= 2;
+            let key = LittleEndian::read_u16(&tlv[..2]);
+            /* Since v2, DATA has no length and spans the rest of the command */
+            let unsized_data = version >= 2 && key == Attr::DATA as u16;
+            let len = if unsized_data {
+                remaining
+            } else {
+                if remaining < 2 {
+                    return Err(Error::BadLength {pos});
+                }
+                self.r.read_exact(&mut tlv[2..]).map_err(|e| Error::from_io(e, pos))?;
+                remaining -= 2;
+                LittleEndian::read_u16(&tlv[2..]) as u64
+            };
+            if len > remaining {
+                return Err(Error::BadLength {pos});
+            }
+            remaining -= len;
+            let hdr_len = if unsized_data { 2 } else { 4 };
+            let leave_out = match mode {
+                DataMode::Stream => remaining == 0,
+                _ => true,
+            };
+            if key == attr as u16 && self.skipped.is_none() && leave_out {
+                crc = crc32c::crc32c_append(crc, &tlv[..hdr_len]);
+                LittleEndian::write_u16(&mut tlv[2..], 0);
+                self.buf.extend_from_slice(&tlv[..hdr_len]);
+                if mode == DataMode::Stream {
+                    self.pending = len;
+                    self.stream_crc = if self.opts.verify_crc { Some((crc, header.crc32)) } else { None };
+                } else {
+                    self.skip(len).map_err(|e| Error::from_io(e, pos))?;
+                }
+                self.skipped = Some(len);
+            } else {
+                self.buf.extend_from_slice(&tlv[..hdr_len]);
+                let start = self.buf.len();
+                self.buf.resize(start + len as usize, 0);
+                self.r.read_exact(&mut self.buf[start..]).map_err(|e| Error::from_io(e, pos))?;
+                crc = crc32c::crc32c_append(crc, &self.buf[start - hdr_len..]);
+            }
+        }
+        Ok(())
+    }
+    /* Seeking past the end of input succeeds, a stream cut off in a skipped
+     * value is found by `finish` instead */
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        if let Some(seek) = self.seek {
+            match seek(&mut self.r, len as i64) {
+                Ok(()) => return Ok(()),
+                /* Pipes and the like, read instead */
+                Err(_) => self.seek = None,
+            }
+        }
+        let n = io::copy(&mut self.r.by_ref().take(len), &mut io::sink())?;
+        if n < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+    /* The rest of a streamed value, which is read instead of seeked over
+     * when its checksum is verified */
+    fn skip_streamed(&mut self) -> Result<()> {
+        let pos = self.cmd_pos;
+        let mut pending = std::mem::take(&mut self.pending);
+        let (mut crc, expected) = match self.stream_crc {
+            Some(crc) => crc,
+            None => return self.skip(pending).map_err(|e| Error::from_io(e, pos)),
+        };
+        while pending > 0 {
+            let buf = self.r.fill_buf().map_err(|e| Error::from_io(e, pos))?;
+            if buf.is_empty() {
+                return Err(Error::Truncated {pos});
+            }
+            let n = buf.len().min(usize::try_from(pending).unwrap_or(usize::MAX));
+            crc = crc32c::crc32c_append(crc, &buf[..n]);
+            self.r.consume(n);
+            pending -= n as u64;
+        }
+        self.stream_crc = Some((crc, expected));
+        payload::check_crc(&mut self.stream_crc, pos)
     }
     pub fn read_command_header(&mut self) -> Result<Option<CommandHeader>> {
-        let mut len_buf = [0u8; 4];
-        let eof = try!(read_exact_with_eof(self.r, &mut len_buf))   ;
+        if self.pending > 0 || self.stream_crc.is_some() {
+            self.skip_streamed()?;
+        }
+        if self.segment.expects_header(&self.opts) && !self.read_stream_header()? {
+            return Ok(None)
+        }
+        self.cmd_pos = Position {offset: self.offset, command: self.cmd_count};
+        let pos = self.cmd_pos;
+        let mut buf = [0u8; CMD_HEADER_LEN];
+        let eof = read_exact_with_eof(&mut self.r, &mut buf).map_err(|e| Error::from_io(e, pos))?;
         if eof {
             return Ok(None)
         }
-        Ok(Some(CommandHeader {
-            len: try!(Cursor::new(len_buf).read_u32::<LittleEndian>()),
-            cmd: try!(self.r.read_u16::<LittleEndian>()),
-            crc32: try!(self.r.read_u32::<LittleEndian>()),
-        }))
+        self.offset += CMD_HEADER_LEN as u64;
+        let header = parse_command_header(&buf);
+        check_command_header(&self.opts, &header, pos)?;
+        self.cmd_loc = CommandLocation::new(pos, &header);
+        self.segment.note_header(&header);
+        Ok(Some(header))
     }
-    pub fn read_tlvs(&mut self, len_to_read: u32) -> Result<TLVData> {
-        let mut tlv_data = TLVData {entries:Vec::new()};
-        let mut remaining = len_to_read;
-        while remaining > 0 {
-            let key = try!(self.r.read_u16::<LittleEndian>());
-            let len = try!(self.r.read_u16::<LittleEndian>());
-            let mut data = vec![0; len as usize];
-            try!(self.r.read_exact(data.as_mut_slice()));
-            tlv_data.entries.push(TLVEntry{key: key, value: data});
-            remaining -= 2*2 + len as u32;
+    /* Header of a further segment, returns false on EOF */
+    fn read_stream_header(&mut self) -> Result<bool> {
+        let pos = Position {offset: self.offset, command: self.cmd_count};
+        let mut buf = [0u8; STREAM_HEADER_LEN];
+        let eof = read_exact_with_eof(&mut self.r, &mut buf).map_err(|e| Error::from_io(e, pos))?;
+        if eof {
+            return Ok(false)
         }
-        Ok(tlv_data)
+        let version = parse_stream_header(&buf, pos)?;
+        self.segment = Segment::start(self.segment.index + 1, self.offset, version);
+        self.offset += STREAM_HEADER_LEN as u64;
+        Ok(true)
+    }
+    pub fn read_tlvs(&mut self, len_to_read: u32) -> Result<TLVData> {
+        let pos = self.cmd_pos;
+        let data = read_tlvs_from(&mut self.r, self.segment.version, len_to_read, &self.opts.limits,
+                                  self.opts.paths.as_ref(), 0, pos)?;
+        self.offset += len_to_read as u64;
+        Ok(data)
     }
 }
 
-pub struct CommandPrintOptions {
+impl<R: Read + Seek> BtrfsReader<R> {
+    /// Like `with_options`, but values skipped due to
+    /// `ReaderOptions::skip_payload` are seeked over instead of read, if the
+    /// input supports it.
+    pub fn with_seek(r: R, opts: ReaderOptions) -> Result<BtrfsReader<R>> {
+        let mut reader = BtrfsReader::with_options(r, opts)?;
+        reader.seek = Some(io::BufReader::seek_relative);
+        Ok(reader)
+    }
 }
 
-impl Default for CommandPrintOptions {
-    fn default() -> Self {
-        CommandPrintOptions {}
+/* How BtrfsReader reads the value of Write, EncodedWrite and SetXattr */
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DataMode {
+    Read,
+    Skip,
+    Stream,
+}
+
+/* Attribute left out by ReaderOptions::skip_payload */
+fn skipped_attr(cmd: u16) -> Option<Attr> {
+    match Cmd::try_from(cmd) {
+        Ok(Cmd::WRITE) | Ok(Cmd::ENCODED_WRITE) => Some(Attr::DATA),
+        Ok(Cmd::SET_XATTR) => Some(Attr::XATTR_DATA),
+        _ => None,
     }
 }
 
+impl<R: Read> Iterator for BtrfsReader<R> {
+    type Item = Result<Command>;
+    fn next(&mut self) -> Option<Result<Command>> {
+        if self.failed {
+            return None;
+        }
+        let r = self.read_command().transpose();
+        if let Some(Err(_)) = r {
+            self.failed = true;
+        }
+        r
+    }
+}
+
+#[derive(Default)]
+pub struct CommandPrintOptions {
+}
+
+
 impl Command {
-    pub fn print(&self, f: &mut std::io::Write, _opts: &CommandPrintOptions) -> std::io::Result<()> {
+    pub fn print(&self, f: &mut dyn std::io::Write, _opts: &CommandPrintOptions) -> std::io::Result<()> {
         match self {
             Command::Write(w) => 
-                write!(f, "Write {{ path = {:?}, file_offset = {}, data_len = {} }}\n", w.path, w.file_offset, w.data.len()),   
-            _ => write!(f, "{:?}\n", self)
+                writeln!(f, "Write {{ path = {:?}, file_offset = {}, data_len = {} }}", w.path, w.file_offset, w.data.len()),
+            Command::EncodedWrite(w) =>
+                writeln!(f, "EncodedWrite {{ path = {:?}, file_offset = {}, unencoded_file_len = {}, unencoded_len = {}, \
+                          unencoded_offset = {}, compression = {}, encryption = {}, data_len = {} }}",
+                         w.path, w.file_offset, w.unencoded_file_len, w.unencoded_len,
+                         w.unencoded_offset, w.compression, w.encryption, w.data.len()),
+            _ => writeln!(f, "{:?}", self)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    const POS: Position = Position {offset: 17, command: 0};
+
+    fn tlv(key: Attr, value: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_u16::<LittleEndian>(key as u16).unwrap();
+        buf.write_u16::<LittleEndian>(value.len() as u16).unwrap();
+        buf.extend_from_slice(value);
+        buf
+    }
+
+    fn limits(f: impl FnOnce(&mut Limits)) -> Limits {
+        let mut limits = Limits::default();
+        f(&mut limits);
+        limits
+    }
+
+    /* Runs both the copying and the borrowing decoder, they must agree */
+    fn read(payload: &[u8], version: u32, limits: &Limits) -> Result<TLVData> {
+        let checked = borrowed::check_tlvs(payload, version, limits, None, POS);
+        let read = read_tlvs_from(&mut Cursor::new(payload), version, payload.len() as u32, limits, None, 0, POS);
+        assert_eq!(format!("{:?}", checked.as_ref().err()), format!("{:?}", read.as_ref().err()));
+        read
+    }
+
+    fn limit_exceeded(r: Result<TLVData>) -> (Limit, u64, u64) {
+        match r {
+            Err(Error::LimitExceeded {limit, value, max, pos}) => {
+                assert_eq!(pos, POS);
+                (limit, value, max)
+            },
+            r => panic!("{:?}", r),
+        }
+    }
+
+    fn bad_length(r: Result<TLVData>) {
+        match r {
+            Err(Error::BadLength {pos}) => assert_eq!(pos, POS),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    fn stream(version: u32, header: &CommandHeader, payload: &[u8]) -> Vec<u8> {
+        let mut buf = MAGIC.as_bytes().to_vec();
+        buf.write_u32::<LittleEndian>(version).unwrap();
+        buf.write_u32::<LittleEndian>(header.len).unwrap();
+        buf.write_u16::<LittleEndian>(header.cmd).unwrap();
+        buf.write_u32::<LittleEndian>(header.crc32).unwrap();
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    #[test]
+    fn too_many_attributes() {
+        let payload = [tlv(Attr::SIZE, &[0; 8]), tlv(Attr::MODE, &[0; 8]), tlv(Attr::UID, &[0; 8])].concat();
+        let two = limits(|l| l.max_attributes = 2);
+        assert_eq!(limit_exceeded(read(&payload, 1, &two)), (Limit::Attributes, 3, 2));
+        let three = limits(|l| l.max_attributes = 3);
+        assert_eq!(read(&payload, 1, &three).unwrap().entries.len(), 3);
+    }
+
+    #[test]
+    fn path_too_long() {
+        let l = limits(|l| l.max_path_len = 10);
+        for &attr in [Attr::PATH, Attr::PATH_TO, Attr::PATH_LINK, Attr::CLONE_PATH].iter() {
+            let payload = tlv(attr, b"0123456789a");
+            assert_eq!(limit_exceeded(read(&payload, 1, &l)), (Limit::PathLen, 11, 10));
+            read(&tlv(attr, b"0123456789"), 1, &l).unwrap();
+        }
+        /* Only paths are limited */
+        read(&tlv(Attr::XATTR_NAME, b"0123456789a"), 1, &l).unwrap();
+    }
+
+    #[test]
+    fn memory_cap() {
+        let entry = std::mem::size_of::<TLVEntry>() as u64;
+        let payload = [tlv(Attr::XATTR_NAME, &[0; 100]), tlv(Attr::XATTR_DATA, &[0; 100])].concat();
+        /* 50 bytes are already held, the two values and their entries add 200 */
+        let max = 250 + 2 * entry;
+        let r = read_tlvs_from(&mut Cursor::new(&payload), 1, payload.len() as u32,
+                               &limits(|l| l.max_memory = max - 1), None, 50, POS);
+        assert_eq!(limit_exceeded(r), (Limit::Memory, max, max - 1));
+        read_tlvs_from(&mut Cursor::new(&payload), 1, payload.len() as u32,
+                       &limits(|l| l.max_memory = max), None, 50, POS).unwrap();
+    }
+
+    #[test]
+    fn attribute_longer_than_command() {
+        let mut payload = tlv(Attr::PATH, b"abcd");
+        payload.pop();
+        bad_length(read(&payload, 1, &Limits::default()));
+    }
+
+    #[test]
+    fn attribute_header_cut_off() {
+        /* Not even a key */
+        bad_length(read(&[0x0f], 1, &Limits::default()));
+        /* Key without length */
+        bad_length(read(&[0x0f, 0x00, 0x04], 1, &Limits::default()));
+        /* After a complete attribute */
+        let payload = [tlv(Attr::PATH, b"a"), vec![0x0f]].concat();
+        bad_length(read(&payload, 1, &Limits::default()));
+    }
+
+    #[test]
+    fn v2_data_spans_rest_of_command() {
+        let mut payload = tlv(Attr::PATH, b"f");
+        payload.write_u16::<LittleEndian>(Attr::DATA as u16).unwrap();
+        payload.extend_from_slice(&[9; 70000]);
+        let data = read(&payload, 2, &Limits::default()).unwrap();
+        assert_eq!(data.entries[1].value.len(), 70000);
+        /* Empty DATA, nothing but the key */
+        let data = read(&(Attr::DATA as u16).to_le_bytes(), 2, &Limits::default()).unwrap();
+        assert!(data.entries[0].value.is_empty());
+    }
+
+    #[test]
+    fn command_too_long() {
+        /* The payload is not there, the header alone must be rejected */
+        let header = CommandHeader {len: 1 << 30, cmd: Cmd::WRITE as u16, crc32: 0};
+        let bytes = stream(1, &header, &[]);
+        let mut r = BtrfsReader::new(&bytes[..]).unwrap();
+        match r.read_command() {
+            Err(Error::LimitExceeded {limit: Limit::CommandLen, value, max, pos}) => {
+                assert_eq!((value, max), (1 << 30, 16 << 20));
+                assert_eq!(pos, POS);
+            },
+            r => panic!("{:?}", r),
+        }
+        let opts = ReaderOptions {limits: limits(|l| l.max_memory = 1 << 20), ..ReaderOptions::default()};
+        let header = CommandHeader {len: 2 << 20, cmd: Cmd::WRITE as u16, crc32: 0};
+        let bytes = stream(1, &header, &[]);
+        let mut r = BtrfsReader::with_options(&bytes[..], opts).unwrap();
+        match r.read_command() {
+            Err(Error::LimitExceeded {limit: Limit::Memory, value, max, ..}) =>
+                assert_eq!((value, max), (2 << 20, 1 << 20)),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let mut w = BtrfsWriter::new(Vec::new(), 1).unwrap();
+        let mkdir = |path: &str, ino| Command::MkDir(commands::MkDir {path: BtrfsString::from(path), ino});
+        w.write_command(&mkdir("a", 257)).unwrap();
+        w.write_command(&mkdir("b", 258)).unwrap();
+        w.write_command(&mkdir("c", 259)).unwrap();
+        let mut bytes = w.into_inner();
+        /* Each MkDir is 10 + 5 + 12 bytes, change the name of the second */
+        let second = (STREAM_HEADER_LEN + 27) as u64;
+        let name = second as usize + CMD_HEADER_LEN + 4;
+        assert_eq!(bytes[name], b'b');
+        bytes[name] = b'x';
+        let mut r = BtrfsReader::new(&bytes[..]).unwrap();
+        r.read_command().unwrap().unwrap();
+        match r.read_command() {
+            Err(Error::ChecksumMismatch {expected, computed, pos}) => {
+                assert_eq!(pos, Position {offset: second, command: 1});
+                assert_eq!(expected, LittleEndian::read_u32(&bytes[second as usize + 6..]));
+                assert_ne!(expected, computed);
+            },
+            r => panic!("{:?}", r),
+        }
+        /* Without verification, the changed name is read */
+        let opts = ReaderOptions {verify_crc: false, ..ReaderOptions::default()};
+        let mut r = BtrfsReader::with_options(&bytes[..], opts).unwrap();
+        r.read_command().unwrap().unwrap();
+        match r.read_command().unwrap() {
+            Some(Command::MkDir(c)) => assert_eq!(c.path.as_bytes(), b"x"),
+            c => panic!("{:?}", c),
+        }
+    }
+
+    fn subvol(path: &str) -> Command {
+        Command::Subvol(commands::Subvol {path: BtrfsString::from(path), ..commands::Subvol::default()})
+    }
+
+    fn mkfile(path: &str) -> Command {
+        Command::MkFile(commands::MkFile {path: BtrfsString::from(path), ino: 257})
+    }
+
+    fn write_stream(version: u32, cmds: &[Command]) -> Vec<u8> {
+        let mut w = BtrfsWriter::new(Vec::new(), version).unwrap();
+        for c in cmds {
+            w.write_command(c).unwrap();
+        }
+        w.into_inner()
+    }
+
+    /* Segment index, offset, version and path */
+    type SegmentInfo = (u32, u64, u32, String);
+
+    /* The segment after each command, and the final error */
+    fn segments(bytes: &[u8], opts: ReaderOptions) -> (Vec<SegmentInfo>, Result<()>) {
+        let mut r = BtrfsReader::with_options(bytes, opts).unwrap();
+        let mut found = Vec::new();
+        loop {
+            match r.read_command() {
+                Ok(Some(_)) => {
+                    let s = r.segment();
+                    found.push((s.index, s.offset, s.version, s.path().map_or(String::new(), |p| p.to_string())));
+                },
+                Ok(None) => return (found, r.finish()),
+                Err(e) => return (found, Err(e)),
+            }
+        }
+    }
+
+    #[test]
+    fn back_to_back_streams() {
+        let first = write_stream(1, &[subvol("a"), mkfile("f"), Command::End(commands::End {})]);
+        let second = write_stream(2, &[subvol("b"), Command::End(commands::End {})]);
+        let second_at = first.len() as u64;
+        let (found, end) = segments(&[first, second].concat(), ReaderOptions::default());
+        let a = (0, 0, 1, "a".to_string());
+        let b = (1, second_at, 2, "b".to_string());
+        assert_eq!(found, [a.clone(), a.clone(), a, b.clone(), b]);
+        end.unwrap();
+    }
+
+    #[test]
+    fn subvolumes_in_one_stream() {
+        let bytes = write_stream(2, &[subvol("a"), mkfile("f"), subvol("b"), mkfile("g"), Command::End(commands::End {})]);
+        /* Subvol with a one byte path is 10 + 5 + 20 + 12 bytes in v2, MkFile 10 + 5 + 12 */
+        let b_at = (STREAM_HEADER_LEN + 47 + 27) as u64;
+        let (found, end) = segments(&bytes, ReaderOptions::default());
+        let a = (0, 0, 2, "a".to_string());
+        let b = (1, b_at, 2, "b".to_string());
+        assert_eq!(found, [a.clone(), a, b.clone(), b.clone(), b]);
+        end.unwrap();
+    }
+
+    #[test]
+    fn garbage_after_end() {
+        let mut bytes = write_stream(1, &[subvol("a"), Command::End(commands::End {})]);
+        let end_at = bytes.len() as u64;
+        bytes.extend_from_slice(&[0xab; 20]);
+        let a = (0, 0, 1, "a".to_string());
+        let pos = Position {offset: end_at, command: 2};
+        /* Another stream header is expected */
+        let (found, end) = segments(&bytes, ReaderOptions::default());
+        assert_eq!(found, [a.clone(), a.clone()]);
+        match end {
+            Err(Error::BadMagic {pos: p}) => assert_eq!(p, pos),
+            r => panic!("{:?}", r),
+        }
+        /* Read as a further command */
+        let opts = ReaderOptions {concatenated: false, ..ReaderOptions::default()};
+        let (found, end) = segments(&bytes, opts);
+        assert_eq!(found, [a.clone(), a]);
+        match end {
+            Err(Error::LimitExceeded {limit: Limit::CommandLen, pos: p, ..}) => assert_eq!(p, pos),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn finish() {
+        let bytes = write_stream(2, &[subvol("a"), mkfile("f"), Command::End(commands::End {})]);
+        let read_all = |bytes: &[u8]| {
+            let mut r = BtrfsReader::new(bytes).unwrap();
+            while r.read_command().unwrap().is_some() {}
+            r.finish()
+        };
+        read_all(&bytes).unwrap();
+        /* Cut before END, MkFile is 27 bytes */
+        let cut = bytes.len() - CMD_HEADER_LEN;
+        match read_all(&bytes[..cut]) {
+            Err(Error::MissingEnd {pos}) => assert_eq!(pos, Position {offset: cut as u64, command: 2}),
+            r => panic!("{:?}", r),
+        }
+        match read_all(&bytes[..cut - 27]) {
+            Err(Error::MissingEnd {pos}) => assert_eq!(pos, Position {offset: (cut - 27) as u64, command: 1}),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    fn read_to_end<R: Read>(mut r: BtrfsReader<R>, len: usize) -> Vec<Command> {
+        let mut cmds = Vec::new();
+        while let Some(c) = r.read_command().unwrap() {
+            cmds.push(c);
+        }
+        assert_eq!(r.bytes_consumed(), len as u64);
+        r.finish().unwrap();
+        cmds
+    }
+
+    /* Reads with skip_payload, with and without seeking */
+    fn read_skipped(bytes: &[u8]) -> [Vec<Command>; 2] {
+        let opts = ReaderOptions {skip_payload: true, ..ReaderOptions::default()};
+        [
+            read_to_end(BtrfsReader::with_options(bytes, opts.clone()).unwrap(), bytes.len()),
+            read_to_end(BtrfsReader::with_seek(Cursor::new(bytes), opts).unwrap(), bytes.len()),
+        ]
+    }
+
+    #[test]
+    fn skip_payload() {
+        let path = || BtrfsString::from("f");
+        let truncate = Command::Truncate(commands::Truncate {path: path(), size: 7});
+        let write = Command::Write(commands::Write {path: path(), file_offset: 0, data: commands::Payload::from(vec![1; 3000])});
+        let encoded = Command::EncodedWrite(commands::EncodedWrite {
+            path: path(), file_offset: 0, unencoded_file_len: 8192, unencoded_len: 8192,
+            compression: 1, data: commands::Payload::from(vec![2; 500]), ..commands::EncodedWrite::default()
+        });
+        let end = Command::End(commands::End {});
+        for (version, cmds) in [
+            (1, vec![subvol("a"), write.clone(), truncate.clone(), end.clone()]),
+            (2, vec![subvol("a"), write, truncate.clone(), encoded, truncate, end]),
+        ] {
+            let bytes = write_stream(version, &cmds);
+            for read in read_skipped(&bytes) {
+                assert_eq!(read.len(), cmds.len());
+                for (c, expected) in read.iter().zip(cmds.iter()) {
+                    match (c, expected) {
+                        (Command::Write(c), Command::Write(e)) => {
+                            assert!(matches!(c.data, commands::Payload::Skipped(3000)), "{:?}", c.data);
+                            assert_eq!(c.file_offset, e.file_offset);
+                        },
+                        (Command::EncodedWrite(c), Command::EncodedWrite(e)) => {
+                            assert!(matches!(c.data, commands::Payload::Skipped(500)), "{:?}", c.data);
+                            assert_eq!((c.unencoded_file_len, c.compression), (e.unencoded_file_len, e.compression));
+                        },
+                        (c, e) => assert_eq!(format!("{:?}", c), format!("{:?}", e)),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn path_policy() {
+        let root = Command::Utimes(commands::Utimes {path: BtrfsString::new(), ..commands::Utimes::default()});
+        let rename = Command::Rename(commands::Rename {path: BtrfsString::from("f"), path_to: BtrfsString::from("d/../x")});
+        let cmds = [subvol("a"), mkfile("f"), root, rename, Command::End(commands::End {})];
+        let bytes = write_stream(2, &cmds);
+        let pos = Position {offset: write_stream(2, &cmds[..3]).len() as u64, command: 3};
+        let check = |r: Result<()>| match r {
+            Err(Error::InvalidPath {attr: Attr::PATH_TO, error: PathError::Parent, pos: p}) => assert_eq!(p, pos),
+            r => panic!("{:?}", r),
+        };
+        for paths in [None, Some(PathPolicy {allow_parent: true, ..PathPolicy::default()}), Some(PathPolicy::default())] {
+            let rejected = matches!(paths, Some(PathPolicy {allow_parent: false, ..}));
+            let opts = ReaderOptions {paths, ..ReaderOptions::default()};
+            let mut results: Vec<Result<()>> = Vec::new();
+            let mut r = BtrfsReader::with_options(&bytes[..], opts.clone()).unwrap();
+            results.push((|| { while r.read_command()?.is_some() {} Ok(()) })());
+            let mut r = BtrfsReader::with_options(&bytes[..], opts.clone()).unwrap();
+            results.push((|| { while r.read_generic_command()?.is_some() {} Ok(()) })());
+            let mut r = SliceReader::with_options(&bytes, opts.clone()).unwrap();
+            results.push((|| { while r.read_command()?.is_some() {} Ok(()) })());
+            let mut d = Decoder::with_options(opts);
+            d.feed(&bytes);
+            results.push((|| { while d.decode()?.is_some() {} Ok(()) })());
+            for r in results {
+                if rejected {
+                    check(r);
+                } else {
+                    r.unwrap();
+                }
+            }
         }
     }
 }
@@ -0,0 +1,224 @@
+use std::io;
+use std::io::Read;
+use crate::*;
+
+/// Command read by `BtrfsReader::read_command_streamed`.
+pub struct StreamedCommand<'a, R: Read> {
+    /// The command, with the value of Write, EncodedWrite and SetXattr
+    /// commands as `Payload::Skipped` if it is read through `payload`.
+    pub command: borrowed::Command<'a>,
+    /// Reader over the data or xattr value, None for other commands.
+    pub payload: Option<PayloadReader<'a, R>>,
+}
+
+/// Reader over the data of one command, straight from the input.
+///
+/// Data that is not read is skipped when the next command is read. The
+/// checksum of the command is verified once all of its data was read, a
+/// mismatch is returned as an `InvalidData` error from the last `read`. If
+/// the data is not read to the end, it is verified when the next command is
+/// read, or by `finish`.
+pub struct PayloadReader<'a, R: Read> {
+    source: Source<'a, R>,
+    pos: Position,
+}
+
+enum Source<'a, R: Read> {
+    /* The checksum state and expected value belong to the reader, which
+     * verifies what is left if the payload is dropped before the end */
+    Stream { r: &'a mut io::BufReader<R>, pending: &'a mut u64, crc: &'a mut Option<(u32, u32)> },
+    /* The value was read with the command, because other attributes follow it */
+    Buffered(&'a [u8]),
+}
+
+impl<'a, R: Read> PayloadReader<'a, R> {
+    /// Number of bytes not read yet.
+    pub fn remaining(&self) -> u64 {
+        match self.source {
+            Source::Stream {ref pending, ..} => **pending,
+            Source::Buffered(data) => data.len() as u64,
+        }
+    }
+    /// Reads the rest of the data and verifies the checksum of the command.
+    pub fn finish(mut self) -> Result<()> {
+        let mut buf = [0u8; 8192];
+        while self.read_data(&mut buf)? > 0 {}
+        Ok(())
+    }
+    fn read_data(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let pos = self.pos;
+        let (r, pending, crc) = match self.source {
+            Source::Stream {ref mut r, ref mut pending, ref mut crc} => (r, pending, crc),
+            Source::Buffered(ref mut data) => return Ok(data.read(buf).unwrap()),
+        };
+        if **pending == 0 {
+            check_crc(crc, pos)?;
+            return Ok(0);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let max = buf.len().min(usize::try_from(**pending).unwrap_or(usize::MAX));
+        let n = r.read(&mut buf[..max]).map_err(|e| Error::from_io(e, pos))?;
+        if n == 0 {
+            return Err(Error::Truncated {pos});
+        }
+        **pending -= n as u64;
+        if let Some((ref mut state, _)) = **crc {
+            *state = crc32c::crc32c_append(*state, &buf[..n]);
+        }
+        if **pending == 0 {
+            check_crc(crc, pos)?;
+        }
+        Ok(n)
+    }
+}
+
+/* Verifies once, the state is cleared afterwards */
+pub(crate) fn check_crc(crc: &mut Option<(u32, u32)>, pos: Position) -> Result<()> {
+    match crc.take() {
+        Some((state, expected)) if !state != expected =>
+            Err(Error::ChecksumMismatch {expected, computed: !state, pos}),
+        _ => Ok(()),
+    }
+}
+
+impl<'a, R: Read> Read for PayloadReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_data(buf).map_err(io::Error::from)
+    }
+}
+
+impl<R: Read> BtrfsReader<R> {
+    /// Reads the next command, leaving the data of Write and EncodedWrite
+    /// commands and the value of SetXattr commands in the input to be read
+    /// through `StreamedCommand::payload`.
+    ///
+    /// Only the attributes are held in memory, so payloads of any size can be
+    /// copied or hashed without buffering them. The rest of the command is
+    /// checked like by `read_command_ref`.
+    pub fn read_command_streamed(&mut self) -> Result<Option<StreamedCommand<'_, R>>> {
+        let header = match self.read_command_buf(DataMode::Stream)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let pos = self.cmd_pos;
+        let cmd = borrowed::Unknown {header, data: borrowed::Tlvs::new(&self.buf, self.segment.version)};
+        let mut command = borrowed::decode_command(&self.opts, pos, cmd)?;
+        let data = match command {
+            borrowed::Command::Write(ref mut c) => Some(&mut c.data),
+            borrowed::Command::SetXattr(ref mut c) => Some(&mut c.xattr_data),
+            borrowed::Command::EncodedWrite(ref mut c) => Some(&mut c.data),
+            _ => None,
+        };
+        let source = match (data, self.skipped) {
+            (Some(data), Some(len)) => {
+                *data = borrowed::Payload::Skipped(len);
+                Some(Source::Stream {r: &mut self.r, pending: &mut self.pending, crc: &mut self.stream_crc})
+            },
+            (Some(&mut borrowed::Payload::Data(d)), None) => Some(Source::Buffered(d)),
+            _ => None,
+        };
+        let payload = source.map(|source| PayloadReader {source, pos});
+        Ok(Some(StreamedCommand {command, payload}))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::definitions::*;
+
+    /* A Write of `len` bytes followed by a Truncate, the second byte of the
+     * data or, without data, the checksum is changed if `corrupt` is set */
+    fn stream(len: usize, corrupt: bool) -> Vec<u8> {
+        let path = BtrfsString::from("f");
+        let mut w = BtrfsWriter::new(Vec::new(), 2).unwrap();
+        w.write_command(&Command::Write(commands::Write {path: path.clone(), file_offset: 0,
+                                                         data: commands::Payload::from(vec![5; len])})).unwrap();
+        w.write_command(&Command::Truncate(commands::Truncate {path, size: 1})).unwrap();
+        let mut bytes = w.into_inner();
+        if corrupt {
+            let i = if len > 1 { bytes.len() - 27 - len + 1 } else { STREAM_HEADER_LEN + 6 };
+            bytes[i] ^= 1;
+        }
+        bytes
+    }
+
+    const WRITE: Position = Position {offset: STREAM_HEADER_LEN as u64, command: 0};
+
+    fn mismatch<T: std::fmt::Debug>(r: Result<T>) {
+        match r {
+            Err(Error::ChecksumMismatch {pos, ..}) => assert_eq!(pos, WRITE),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    fn streamed_payload<'a, 'b>(r: &'a mut BtrfsReader<&'b [u8]>) -> PayloadReader<'a, &'b [u8]> {
+        r.read_command_streamed().unwrap().unwrap().payload.unwrap()
+    }
+
+    #[test]
+    fn read_to_end() {
+        let bytes = stream(1000, false);
+        let mut r = BtrfsReader::new(&bytes[..]).unwrap();
+        let mut data = Vec::new();
+        streamed_payload(&mut r).read_to_end(&mut data).unwrap();
+        assert_eq!(data, vec![5; 1000]);
+        let bytes = stream(1000, true);
+        let mut r = BtrfsReader::new(&bytes[..]).unwrap();
+        let e = streamed_payload(&mut r).read_to_end(&mut data).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn empty_payload() {
+        let bytes = stream(0, true);
+        let mut r = BtrfsReader::new(&bytes[..]).unwrap();
+        let mut p = streamed_payload(&mut r);
+        assert_eq!(p.remaining(), 0);
+        assert!(p.read(&mut [0; 16]).is_err());
+        /* Reported once */
+        assert_eq!(p.read(&mut [0; 16]).unwrap(), 0);
+        let mut r = BtrfsReader::new(&bytes[..]).unwrap();
+        mismatch(streamed_payload(&mut r).finish());
+        /* Also if the payload is not looked at */
+        let mut r = BtrfsReader::new(&bytes[..]).unwrap();
+        r.read_command_streamed().unwrap();
+        mismatch(r.read_command_streamed().map(|_| ()));
+    }
+
+    #[test]
+    fn partly_read() {
+        let bytes = stream(1000, true);
+        let mut r = BtrfsReader::new(&bytes[..]).unwrap();
+        let mut p = streamed_payload(&mut r);
+        p.read_exact(&mut [0; 10]).unwrap();
+        mismatch(p.finish());
+        /* Dropped, verified when the next command is read */
+        let mut r = BtrfsReader::new(&bytes[..]).unwrap();
+        streamed_payload(&mut r).read_exact(&mut [0; 10]).unwrap();
+        mismatch(r.read_command_streamed().map(|_| ()));
+        /* Or by finish */
+        let mut r = BtrfsReader::new(&bytes[..]).unwrap();
+        streamed_payload(&mut r).read_exact(&mut [0; 10]).unwrap();
+        mismatch(r.finish());
+        /* Intact data is skipped */
+        let bytes = stream(1000, false);
+        let mut r = BtrfsReader::new(&bytes[..]).unwrap();
+        streamed_payload(&mut r).read_exact(&mut [0; 10]).unwrap();
+        match r.read_command_streamed().unwrap().unwrap().command {
+            borrowed::Command::Truncate(c) => assert_eq!(c.size, 1),
+            c => panic!("{:?}", c),
+        }
+    }
+
+    #[test]
+    fn not_verified() {
+        let bytes = stream(1000, true);
+        let opts = ReaderOptions {verify_crc: false, ..ReaderOptions::default()};
+        let mut r = BtrfsReader::with_options(&bytes[..], opts).unwrap();
+        streamed_payload(&mut r).read_exact(&mut [0; 10]).unwrap();
+        assert!(r.read_command_streamed().unwrap().is_some());
+    }
+}
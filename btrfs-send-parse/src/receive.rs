@@ -0,0 +1,741 @@
+use std::error;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use crate::commands::Payload;
+use crate::tracker::Origins;
+use crate::*;
+
+/* From linux/fs.h */
+const FICLONE: libc::c_ulong = 0x4004_9409;
+const FICLONERANGE: libc::c_ulong = 0x4020_940d;
+const COPY_CHUNK: usize = 64 * 1024;
+/* Directories on the way to a file are opened without following symlinks */
+const DIR_FLAGS: libc::c_int = libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW;
+
+#[repr(C)]
+struct FileCloneRange {
+    src_fd: i64,
+    src_offset: u64,
+    src_length: u64,
+    dest_offset: u64,
+}
+
+/// Options of `Receiver`.
+#[derive(Clone, Debug)]
+pub struct ReceiveOptions {
+    /// Receive into the destination directory itself, instead of creating a
+    /// directory named after the subvolume in it. Incremental streams need
+    /// this, the destination then has to hold a copy of the parent snapshot.
+    /// Streams with several subvolumes can not be received in place.
+    pub in_place: bool,
+    /// Set owners of files. Failures for lack of permission are ignored.
+    pub chown: bool,
+    pub xattrs: bool,
+    /// Share the data of cloned ranges (FICLONE) if the filesystem can,
+    /// otherwise the data is copied.
+    pub reflink: bool,
+    /// Which paths in the stream are accepted. Whatever the policy, paths
+    /// are resolved without following symlinks, so they can not lead
+    /// outside of the subvolume.
+    pub paths: PathPolicy,
+}
+
+impl Default for ReceiveOptions {
+    fn default() -> Self {
+        ReceiveOptions {in_place: false, chown: true, xattrs: true, reflink: true, paths: PathPolicy::default()}
+    }
+}
+
+#[derive(Debug)]
+pub enum ReceiveError {
+    /// The stream could not be read.
+    Stream(Error),
+    /// A command could not be applied to the destination.
+    Apply { path: BtrfsString, source: io::Error, pos: Position },
+}
+
+impl ReceiveError {
+    fn apply(path: &BtrfsString, source: io::Error) -> ReceiveError {
+        ReceiveError::Apply {path: path.clone(), source, pos: Position::default()}
+    }
+    fn at(self, p: Position) -> ReceiveError {
+        match self {
+            ReceiveError::Apply {path, source, ..} => ReceiveError::Apply {path, source, pos: p},
+            e => e,
+        }
+    }
+}
+
+impl fmt::Display for ReceiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReceiveError::Stream(e) => write!(f, "{}", e),
+            ReceiveError::Apply {path, source, pos} =>
+                write!(f, "{:?}: {} (command {} at offset {})", path, source, pos.command, pos.offset),
+        }
+    }
+}
+
+impl error::Error for ReceiveError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ReceiveError::Stream(e) => Some(e),
+            ReceiveError::Apply {source, ..} => Some(source),
+        }
+    }
+}
+
+impl From<Error> for ReceiveError {
+    fn from(e: Error) -> ReceiveError {
+        ReceiveError::Stream(e)
+    }
+}
+
+fn unsupported(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, msg)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn cstring(name: &[u8]) -> io::Result<CString> {
+    CString::new(name).map_err(|_| invalid("name contains a NUL byte"))
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn timespec(t: &Timespec) -> libc::timespec {
+    libc::timespec {tv_sec: t.sec as libc::time_t, tv_nsec: t.nsec as _}
+}
+
+fn openat(dir: &fs::File, name: &CStr, flags: libc::c_int, mode: libc::mode_t) -> io::Result<fs::File> {
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags | libc::O_CLOEXEC, mode as libc::c_uint) };
+    check(fd)?;
+    Ok(unsafe { fs::File::from_raw_fd(fd) })
+}
+
+fn open_dir(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new().read(true).custom_flags(libc::O_PATH | libc::O_DIRECTORY).open(path)
+}
+
+/* A path of the stream resolved below the subvolume: the directory it is
+ * in and its name there. The subvolume itself is "." in its directory. */
+struct At {
+    dir: fs::File,
+    name: CString,
+}
+
+impl At {
+    /* Opens every directory on the way from `root`, none of them can be a
+     * symlink. The name itself is left to the call using it. */
+    fn resolve(root: &fs::File, path: &SafePath) -> io::Result<At> {
+        let name = match path.file_name() {
+            Some(name) => cstring(name.as_bytes())?,
+            None => return Ok(At {dir: root.try_clone()?, name: CString::new(".").unwrap()}),
+        };
+        let mut dir = root.try_clone()?;
+        for c in path.parent().unwrap_or_default().components() {
+            dir = openat(&dir, &cstring(c.as_bytes())?, DIR_FLAGS, 0)?;
+        }
+        Ok(At {dir, name})
+    }
+    fn fd(&self) -> libc::c_int {
+        self.dir.as_raw_fd()
+    }
+    fn open(&self, flags: libc::c_int, mode: libc::mode_t) -> io::Result<fs::File> {
+        openat(&self.dir, &self.name, flags | libc::O_NOFOLLOW, mode)
+    }
+    /* There are no *at calls for xattrs. The directory is reached through its
+     * descriptor in /proc and the name is not followed by the l* calls. */
+    fn proc_path(&self) -> io::Result<CString> {
+        let mut path = format!("/proc/self/fd/{}/", self.fd()).into_bytes();
+        path.extend_from_slice(self.name.as_bytes());
+        cstring(&path)
+    }
+}
+
+/// Applies a send stream to an ordinary directory, like `btrfs receive`
+/// does to a btrfs filesystem. Linux only.
+///
+/// Paths in the stream are checked with `SafePath` and resolved from a
+/// descriptor of the subvolume directory, one directory at a time and
+/// without following symlinks, so symlinks received earlier can not lead
+/// later commands outside of the subvolume. Extended attributes are set
+/// through `/proc/self/fd`. Clones are supported from the subvolume being
+/// received and, with `in_place`, from its parent.
+/// File attributes and fs-verity are not applied, compressed data
+/// (`--compressed-data`) and streams without data (`--no-data`) can not be
+/// received.
+pub struct Receiver {
+    dest: PathBuf,
+    opts: ReceiveOptions,
+    /* Directory of the subvolume being received, paths are resolved from its descriptor */
+    dir: Option<(PathBuf, fs::File)>,
+    uuid: Uuid,
+    parent_uuid: Option<Uuid>,
+    /* Find files of the parent snapshot after renames, for clone sources */
+    paths: PathTracker,
+    origins: Origins,
+    /* Last file written to, by its path in the stream */
+    file: Option<(BtrfsString, fs::File)>,
+}
+
+impl Receiver {
+    pub fn new<P: Into<PathBuf>>(dest: P) -> Receiver {
+        Receiver::with_options(dest, ReceiveOptions::default())
+    }
+    pub fn with_options<P: Into<PathBuf>>(dest: P, opts: ReceiveOptions) -> Receiver {
+        Receiver {
+            dest: dest.into(), opts, dir: None, uuid: Uuid::default(), parent_uuid: None,
+            paths: PathTracker::new(), origins: Origins::default(), file: None,
+        }
+    }
+    /// Reads the whole stream and applies its commands.
+    pub fn receive<R: Read>(&mut self, reader: &mut BtrfsReader<R>) -> std::result::Result<(), ReceiveError> {
+        while let Some(cmd) = reader.read_command()? {
+            let loc = reader.command_location();
+            self.apply(&cmd).map_err(|e| e.at(Position {offset: loc.offset, command: loc.command}))?;
+        }
+        reader.finish()?;
+        Ok(())
+    }
+    /// Directory the current subvolume is received to.
+    pub fn subvol_dir(&self) -> Option<&Path> {
+        self.dir.as_ref().map(|(path, _)| path.as_path())
+    }
+    pub fn apply(&mut self, cmd: &Command) -> std::result::Result<(), ReceiveError> {
+        match cmd {
+            Command::Subvol(commands::Subvol {path, ..}) | Command::Snapshot(commands::Snapshot {path, ..})
+                    if self.opts.in_place && self.dir.is_some() => {
+                let e = unsupported("only one subvolume can be received in place");
+                return Err(ReceiveError::apply(path, e));
+            },
+            _ => {},
+        }
+        match cmd {
+            Command::Rename(_) | Command::UnLink(_) | Command::RmDir(_) | Command::End(_) => self.file = None,
+            _ => {},
+        }
+        match cmd {
+            Command::Subvol(c) => {
+                let dir = if self.opts.in_place {
+                    fs::create_dir_all(&self.dest).and_then(|_| open_dir(&self.dest))
+                        .map(|dir| (self.dest.clone(), dir))
+                } else {
+                    let policy = PathPolicy {allow_empty: false, ..self.opts.paths};
+                    let name = self.safe_path(&c.path, &policy)?;
+                    open_dir(&self.dest).and_then(|dest| {
+                        let at = At::resolve(&dest, &name)?;
+                        check(unsafe { libc::mkdirat(at.fd(), at.name.as_ptr(), 0o777) })?;
+                        at.open(DIR_FLAGS, 0)
+                    }).map(|dir| (name.under(&self.dest), dir))
+                };
+                self.dir = Some(dir.map_err(|e| ReceiveError::apply(&c.path, e))?);
+                self.uuid = c.uuid;
+                self.parent_uuid = None;
+                self.file = None;
+            },
+            Command::Snapshot(c) => {
+                if !self.opts.in_place {
+                    let e = unsupported("incremental streams can only be received in place");
+                    return Err(ReceiveError::apply(&c.path, e));
+                }
+                let dir = open_dir(&self.dest).map_err(|e| ReceiveError::apply(&c.path, e))?;
+                self.dir = Some((self.dest.clone(), dir));
+                self.uuid = c.uuid;
+                self.parent_uuid = Some(c.clone_uuid);
+                self.file = None;
+            },
+            Command::MkFile(c) => {
+                let at = self.resolve(&c.path)?;
+                let r = at.open(libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL, 0o666);
+                r.map_err(|e| ReceiveError::apply(&c.path, e))?;
+            },
+            Command::MkDir(c) => {
+                let at = self.resolve(&c.path)?;
+                let r = check(unsafe { libc::mkdirat(at.fd(), at.name.as_ptr(), 0o777) });
+                r.map_err(|e| ReceiveError::apply(&c.path, e))?;
+            },
+            Command::MkNod(c) => self.mknod(&c.path, c.mode, c.rdev)?,
+            Command::MkFifo(c) => self.mknod(&c.path, c.mode | libc::S_IFIFO as u64, c.rdev)?,
+            Command::MkSock(c) => self.mknod(&c.path, c.mode | libc::S_IFSOCK as u64, c.rdev)?,
+            Command::SymLink(c) => {
+                let at = self.resolve(&c.path)?;
+                let r = cstring(c.path_link.as_bytes())
+                    .and_then(|target| check(unsafe { libc::symlinkat(target.as_ptr(), at.fd(), at.name.as_ptr()) }));
+                r.map_err(|e| ReceiveError::apply(&c.path, e))?;
+            },
+            Command::Rename(c) => {
+                let (from, to) = (self.resolve(&c.path)?, self.resolve(&c.path_to)?);
+                let r = check(unsafe { libc::renameat(from.fd(), from.name.as_ptr(), to.fd(), to.name.as_ptr()) });
+                r.map_err(|e| ReceiveError::apply(&c.path, e))?;
+            },
+            Command::Link(c) => {
+                let (target, at) = (self.resolve(&c.path_link)?, self.resolve(&c.path)?);
+                let r = check(unsafe { libc::linkat(target.fd(), target.name.as_ptr(), at.fd(), at.name.as_ptr(), 0) });
+                r.map_err(|e| ReceiveError::apply(&c.path, e))?;
+            },
+            Command::UnLink(c) => {
+                let at = self.resolve(&c.path)?;
+                let r = check(unsafe { libc::unlinkat(at.fd(), at.name.as_ptr(), 0) });
+                r.map_err(|e| ReceiveError::apply(&c.path, e))?;
+            },
+            Command::RmDir(c) => {
+                let at = self.resolve(&c.path)?;
+                let r = check(unsafe { libc::unlinkat(at.fd(), at.name.as_ptr(), libc::AT_REMOVEDIR) });
+                r.map_err(|e| ReceiveError::apply(&c.path, e))?;
+            },
+            Command::Write(c) => {
+                let data = payload(&c.data).map_err(|e| ReceiveError::apply(&c.path, e))?;
+                self.write(&c.path, c.file_offset, data)?;
+            },
+            Command::EncodedWrite(c) => {
+                if c.compression != 0 || c.encryption != 0 {
+                    return Err(ReceiveError::apply(&c.path, unsupported("encoded data can not be decoded")));
+                }
+                let data = payload(&c.data).map_err(|e| ReceiveError::apply(&c.path, e))?;
+                let start = (c.unencoded_offset as usize).min(data.len());
+                let end = start.saturating_add(c.unencoded_file_len as usize).min(data.len());
+                self.write(&c.path, c.file_offset, &data[start..end])?;
+            },
+            Command::Clone(c) => self.clone_range(c)?,
+            Command::SetXattr(c) => {
+                if self.opts.xattrs {
+                    let data = payload(&c.xattr_data).map_err(|e| ReceiveError::apply(&c.path, e))?;
+                    let at = self.resolve(&c.path)?;
+                    set_xattr(&at, &c.xattr_name, data).map_err(|e| ReceiveError::apply(&c.path, e))?;
+                }
+            },
+            Command::RemoveXattr(c) => {
+                if self.opts.xattrs {
+                    let at = self.resolve(&c.path)?;
+                    remove_xattr(&at, &c.xattr_name).map_err(|e| ReceiveError::apply(&c.path, e))?;
+                }
+            },
+            Command::Truncate(c) => {
+                let file = self.open(&c.path)?;
+                file.set_len(c.size).map_err(|e| ReceiveError::apply(&c.path, e))?;
+            },
+            Command::Chmod(c) => {
+                let at = self.resolve(&c.path)?;
+                set_mode(&at, c.mode as libc::mode_t & 0o7777).map_err(|e| ReceiveError::apply(&c.path, e))?;
+            },
+            Command::Chown(c) => {
+                if self.opts.chown {
+                    let at = self.resolve(&c.path)?;
+                    match chown(&at, c.uid, c.gid) {
+                        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {},
+                        r => r.map_err(|e| ReceiveError::apply(&c.path, e))?,
+                    }
+                }
+            },
+            Command::Utimes(c) => {
+                let at = self.resolve(&c.path)?;
+                set_times(&at, &c.atime, &c.mtime).map_err(|e| ReceiveError::apply(&c.path, e))?;
+            },
+            Command::Fallocate(c) => {
+                let file = self.open(&c.path)?;
+                fallocate(file, c.mode, c.file_offset, c.size).map_err(|e| ReceiveError::apply(&c.path, e))?;
+            },
+            Command::UpdateExtent(c) => {
+                return Err(ReceiveError::apply(&c.path, unsupported("the stream does not contain file data")));
+            },
+            Command::FileAttr(_) | Command::EnableVerity(_) | Command::End(_) | Command::Unknown(_) => {},
+        }
+        self.track(cmd);
+        Ok(())
+    }
+    /* Only the names matter, and only in incremental streams */
+    fn track(&mut self, cmd: &Command) {
+        match cmd {
+            Command::Subvol(_) | Command::Snapshot(_) => {
+                self.paths = PathTracker::new();
+                self.origins = Origins::default();
+                return;
+            },
+            _ if self.parent_uuid.is_none() => return,
+            Command::Rename(c) => {
+                let node = self.paths.lookup(c.path.as_bytes());
+                self.origins.see(&self.paths, node);
+                if let Some(to) = self.paths.get(c.path_to.as_bytes()) {
+                    self.origins.see(&self.paths, to);
+                }
+            },
+            Command::UnLink(c) => {
+                let node = self.paths.lookup(c.path.as_bytes());
+                self.origins.see(&self.paths, node);
+            },
+            Command::RmDir(c) => {
+                let node = self.paths.lookup(c.path.as_bytes());
+                self.origins.see(&self.paths, node);
+            },
+            Command::MkFile(_) | Command::MkDir(_) | Command::MkNod(_) | Command::MkFifo(_)
+                | Command::MkSock(_) | Command::SymLink(_) | Command::Link(_) => {},
+            _ => return,
+        }
+        self.paths.apply(cmd);
+    }
+    /* Checks that a path from the stream stays inside the subvolume */
+    fn safe_path(&self, path: &BtrfsString, policy: &PathPolicy) -> std::result::Result<SafePath, ReceiveError> {
+        SafePath::with_policy(path.as_bytes(), policy)
+            .map_err(|e| ReceiveError::apply(path, io::Error::new(io::ErrorKind::InvalidData, e)))
+    }
+    fn resolve(&self, path: &BtrfsString) -> std::result::Result<At, ReceiveError> {
+        let dir = match self.dir {
+            Some((_, ref dir)) => dir,
+            None => return Err(ReceiveError::apply(path, invalid("command outside of a subvolume"))),
+        };
+        let safe = self.safe_path(path, &self.opts.paths)?;
+        At::resolve(dir, &safe).map_err(|e| ReceiveError::apply(path, e))
+    }
+    /* File for writing, kept open for the following commands */
+    fn open(&mut self, path: &BtrfsString) -> std::result::Result<&fs::File, ReceiveError> {
+        match self.file {
+            Some((ref p, _)) if p == path => {},
+            _ => {
+                let file = self.resolve(path)?.open(libc::O_WRONLY, 0)
+                    .map_err(|e| ReceiveError::apply(path, e))?;
+                self.file = Some((path.clone(), file));
+            },
+        }
+        Ok(&self.file.as_ref().unwrap().1)
+    }
+    fn write(&mut self, path: &BtrfsString, offset: u64, data: &[u8]) -> std::result::Result<(), ReceiveError> {
+        let file = self.open(path)?;
+        file.write_all_at(data, offset).map_err(|e| ReceiveError::apply(path, e))
+    }
+    fn mknod(&self, path: &BtrfsString, mode: u64, rdev: u64) -> std::result::Result<(), ReceiveError> {
+        let at = self.resolve(path)?;
+        let r = check(unsafe { libc::mknodat(at.fd(), at.name.as_ptr(), mode as libc::mode_t, rdev as libc::dev_t) });
+        r.map_err(|e| ReceiveError::apply(path, e))
+    }
+    fn clone_range(&mut self, c: &commands::Clone) -> std::result::Result<(), ReceiveError> {
+        let source = if c.clone_uuid.data == self.uuid.data {
+            Some(c.clone_path.clone())
+        } else if self.parent_uuid.is_some_and(|p| p.data == c.clone_uuid.data) {
+            self.origins.current_path(&self.paths, c.clone_path.as_bytes())
+        } else {
+            None
+        };
+        let source = match source {
+            Some(s) => self.resolve(&s)?,
+            None => return Err(ReceiveError::apply(&c.clone_path, unsupported("clone source is not available"))),
+        };
+        let src = source.open(libc::O_RDONLY, 0).map_err(|e| ReceiveError::apply(&c.clone_path, e))?;
+        let reflink = self.opts.reflink;
+        let dst = self.open(&c.path)?;
+        let mut cloned = false;
+        if reflink && c.clone_len > 0 {
+            cloned = reflink_range(&src, dst, c.clone_offset, c.clone_len, c.file_offset).is_ok();
+        }
+        if !cloned {
+            copy_range(&src, dst, c.clone_offset, c.clone_len, c.file_offset)
+                .map_err(|e| ReceiveError::apply(&c.path, e))?;
+        }
+        Ok(())
+    }
+}
+
+fn payload(data: &Payload) -> io::Result<&[u8]> {
+    data.data().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "data was skipped when reading"))
+}
+
+fn reflink_range(src: &fs::File, dst: &fs::File, src_offset: u64, len: u64, dst_offset: u64) -> io::Result<()> {
+    /* FICLONE for whole files, some filesystems only support that */
+    let whole = src_offset == 0 && dst_offset == 0 && src.metadata()?.len() == len && dst.metadata()?.len() == 0;
+    if whole && check(unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE as _, src.as_raw_fd()) }).is_ok() {
+        return Ok(());
+    }
+    let range = FileCloneRange {src_fd: src.as_raw_fd() as i64, src_offset, src_length: len, dest_offset: dst_offset};
+    check(unsafe { libc::ioctl(dst.as_raw_fd(), FICLONERANGE as _, &range) })
+}
+
+fn copy_range(src: &fs::File, dst: &fs::File, mut src_offset: u64, len: u64, mut dst_offset: u64) -> io::Result<()> {
+    let mut buf = vec![0u8; COPY_CHUNK.min(len as usize)];
+    let mut left = len;
+    while left > 0 {
+        let chunk = (left as usize).min(buf.len());
+        let n = src.read_at(&mut buf[..chunk], src_offset)?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "clone source is too short"));
+        }
+        dst.write_all_at(&buf[..n], dst_offset)?;
+        src_offset += n as u64;
+        dst_offset += n as u64;
+        left -= n as u64;
+    }
+    Ok(())
+}
+
+fn fallocate(file: &fs::File, mode: u32, offset: u64, len: u64) -> io::Result<()> {
+    let r = check(unsafe { libc::fallocate(file.as_raw_fd(), mode as libc::c_int, offset as libc::off_t, len as libc::off_t) });
+    match r {
+        Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {},
+        r => return r,
+    }
+    /* Not supported by the filesystem, zero the range instead */
+    let size = file.metadata()?.len();
+    let end = offset.saturating_add(len);
+    let zero_end = if mode & FALLOC_FL_KEEP_SIZE != 0 { end.min(size) } else { end };
+    if mode & !FALLOC_FL_KEEP_SIZE != 0 && offset < zero_end {
+        let zeros = vec![0u8; COPY_CHUNK];
+        let mut pos = offset;
+        while pos < zero_end {
+            let n = ((zero_end - pos) as usize).min(zeros.len());
+            file.write_all_at(&zeros[..n], pos)?;
+            pos += n as u64;
+        }
+    } else if end > size && mode & FALLOC_FL_KEEP_SIZE == 0 {
+        file.set_len(end)?;
+    }
+    Ok(())
+}
+
+fn set_xattr(at: &At, name: &BtrfsString, value: &[u8]) -> io::Result<()> {
+    let (p, n) = (at.proc_path()?, cstring(name.as_bytes())?);
+    check(unsafe { libc::lsetxattr(p.as_ptr(), n.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0) })
+}
+
+fn remove_xattr(at: &At, name: &BtrfsString) -> io::Result<()> {
+    let (p, n) = (at.proc_path()?, cstring(name.as_bytes())?);
+    check(unsafe { libc::lremovexattr(p.as_ptr(), n.as_ptr()) })
+}
+
+/* fchmodat with AT_SYMLINK_NOFOLLOW needs glibc 2.32, so files are opened
+ * without following symlinks instead. Opening devices and FIFOs could block
+ * or have side effects, and they can not be symlinks anyway. */
+fn set_mode(at: &At, mode: libc::mode_t) -> io::Result<()> {
+    let mut st = std::mem::MaybeUninit::<libc::stat>::uninit();
+    check(unsafe { libc::fstatat(at.fd(), at.name.as_ptr(), st.as_mut_ptr(), libc::AT_SYMLINK_NOFOLLOW) })?;
+    match unsafe { st.assume_init() }.st_mode & libc::S_IFMT {
+        /* Symlinks have no mode of their own */
+        libc::S_IFLNK => Ok(()),
+        libc::S_IFREG | libc::S_IFDIR => {
+            let file = at.open(libc::O_RDONLY | libc::O_NONBLOCK | libc::O_NOCTTY, 0)?;
+            check(unsafe { libc::fchmod(file.as_raw_fd(), mode) })
+        },
+        _ => check(unsafe { libc::fchmodat(at.fd(), at.name.as_ptr(), mode, 0) }),
+    }
+}
+
+fn chown(at: &At, uid: u64, gid: u64) -> io::Result<()> {
+    check(unsafe { libc::fchownat(at.fd(), at.name.as_ptr(), uid as libc::uid_t, gid as libc::gid_t,
+                                  libc::AT_SYMLINK_NOFOLLOW) })
+}
+
+fn set_times(at: &At, atime: &Timespec, mtime: &Timespec) -> io::Result<()> {
+    let times = [timespec(atime), timespec(mtime)];
+    check(unsafe { libc::utimensat(at.fd(), at.name.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use crate::commands::*;
+
+    /* Removed again when the test ends */
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("btrfs-receive-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("dest")).unwrap();
+            fs::create_dir_all(dir.join("outside")).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn s(path: &str) -> BtrfsString {
+        BtrfsString::from(path)
+    }
+
+    /* Receives the subvolume "sv", with a symlink "l" to `target` */
+    fn receiver(tmp: &TempDir, target: &Path) -> Receiver {
+        let mut r = Receiver::new(tmp.0.join("dest"));
+        r.apply(&Command::Subvol(Subvol {path: s("sv"), uuid: Uuid {data: [1; 16]}, ctransid: 1})).unwrap();
+        let link = BtrfsString::from(target.as_os_str());
+        r.apply(&Command::SymLink(SymLink {path: s("l"), ino: 257, path_link: link})).unwrap();
+        r
+    }
+
+    fn write(path: &str, data: &[u8]) -> Command {
+        Command::Write(commands::Write {path: s(path), file_offset: 0, data: Payload::from(data.to_vec())})
+    }
+
+    fn fails(r: &mut Receiver, cmd: Command) {
+        match r.apply(&cmd) {
+            Err(ReceiveError::Apply {..}) => {},
+            res => panic!("{:?}: {:?}", cmd, res),
+        }
+    }
+
+    #[test]
+    fn receives_files() {
+        let tmp = TempDir::new("files");
+        let mut r = receiver(&tmp, Path::new("d/f"));
+        for cmd in vec![
+            Command::MkDir(MkDir {path: s("o258-1-0"), ino: 258}),
+            Command::Rename(Rename {path: s("o258-1-0"), path_to: s("d")}),
+            Command::MkFile(MkFile {path: s("d/f"), ino: 259}),
+            write("d/f", b"hello"),
+            Command::Clone(commands::Clone {path: s("d/f"), file_offset: 5, clone_len: 5, clone_uuid: Uuid {data: [1; 16]},
+                                            clone_ctransid: 1, clone_path: s("d/f"), clone_offset: 0}),
+            Command::Link(Link {path: s("h"), path_link: s("d/f")}),
+            Command::Chmod(Chmod {path: s("d/f"), mode: 0o640}),
+            Command::Utimes(Utimes {path: s(""), mtime: Timespec {sec: 1000, nsec: 0}, ..Utimes::default()}),
+            Command::End(End {}),
+        ] {
+            r.apply(&cmd).unwrap();
+        }
+        let sv = tmp.0.join("dest/sv");
+        assert_eq!(r.subvol_dir(), Some(sv.as_path()));
+        assert_eq!(fs::read(sv.join("h")).unwrap(), b"hellohello");
+        assert_eq!(fs::metadata(sv.join("d/f")).unwrap().permissions().mode() & 0o7777, 0o640);
+        assert_eq!(fs::read_link(sv.join("l")).unwrap(), Path::new("d/f"));
+        let mtime = fs::metadata(&sv).unwrap().modified().unwrap();
+        assert_eq!(mtime, std::time::UNIX_EPOCH + std::time::Duration::from_secs(1000));
+    }
+
+    #[test]
+    fn chmod() {
+        let tmp = TempDir::new("chmod");
+        let mut r = receiver(&tmp, Path::new("f"));
+        for cmd in [
+            Command::MkFile(MkFile {path: s("f"), ino: 258}),
+            Command::MkDir(MkDir {path: s("d"), ino: 259}),
+            Command::MkFifo(MkFifo {path: s("p"), ino: 260, mode: 0o644, rdev: 0}),
+            Command::Chmod(Chmod {path: s("f"), mode: 0o100604}),
+            Command::Chmod(Chmod {path: s("d"), mode: 0o2750}),
+            /* Neither blocks on the FIFO nor follows the symlink */
+            Command::Chmod(Chmod {path: s("p"), mode: 0o600}),
+            Command::Chmod(Chmod {path: s("l"), mode: 0o640}),
+        ] {
+            r.apply(&cmd).unwrap();
+        }
+        let sv = tmp.0.join("dest/sv");
+        let mode = |name| fs::symlink_metadata(sv.join(name)).unwrap().permissions().mode() & 0o7777;
+        assert_eq!((mode("f"), mode("d"), mode("p")), (0o604, 0o2750, 0o600));
+        fails(&mut r, Command::Chmod(Chmod {path: s("missing"), mode: 0o600}));
+    }
+
+    #[test]
+    fn clone_from_parent() {
+        let tmp = TempDir::new("clone-parent");
+        let dest = tmp.0.join("dest");
+        fs::create_dir_all(dest.join("d")).unwrap();
+        fs::write(dest.join("d/x"), b"parent").unwrap();
+        let opts = ReceiveOptions {in_place: true, ..ReceiveOptions::default()};
+        let mut r = Receiver::with_options(&dest, opts);
+        let parent = Uuid {data: [2; 16]};
+        let clone = |path: &str, clone_path: &str| Command::Clone(commands::Clone {
+            path: s(path), file_offset: 0, clone_len: 6, clone_uuid: parent, clone_ctransid: 1,
+            clone_path: s(clone_path), clone_offset: 0,
+        });
+        for cmd in [
+            Command::Snapshot(Snapshot {path: s("sv"), uuid: Uuid {data: [3; 16]}, clone_uuid: parent, ..Snapshot::default()}),
+            Command::Rename(Rename {path: s("d"), path_to: s("e")}),
+            Command::MkFile(MkFile {path: s("o257-1-0"), ino: 257}),
+            Command::Rename(Rename {path: s("o257-1-0"), path_to: s("f")}),
+            /* Where d/x is now */
+            clone("f", "d/x"),
+        ] {
+            r.apply(&cmd).unwrap();
+        }
+        assert_eq!(fs::read(dest.join("f")).unwrap(), b"parent");
+        r.apply(&Command::UnLink(UnLink {path: s("e/x")})).unwrap();
+        r.apply(&Command::MkFile(MkFile {path: s("e/x"), ino: 258})).unwrap();
+        match r.apply(&clone("f", "d/x")) {
+            Err(ReceiveError::Apply {path, source, ..}) => {
+                assert_eq!(path, s("d/x"));
+                assert_eq!(source.kind(), io::ErrorKind::Unsupported);
+            },
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn several_subvolumes() {
+        let tmp = TempDir::new("several");
+        let subvol = |path: &str| Command::Subvol(Subvol {path: s(path), ..Subvol::default()});
+        let mut r = Receiver::new(tmp.0.join("dest"));
+        for path in ["a", "b"] {
+            r.apply(&subvol(path)).unwrap();
+            r.apply(&Command::MkFile(MkFile {path: s("f"), ino: 257})).unwrap();
+        }
+        assert!(tmp.0.join("dest/a/f").exists() && tmp.0.join("dest/b/f").exists());
+        /* In place, the second one would be mixed into the first */
+        let opts = ReceiveOptions {in_place: true, ..ReceiveOptions::default()};
+        let mut r = Receiver::with_options(tmp.0.join("dest/c"), opts);
+        r.apply(&subvol("c")).unwrap();
+        fails(&mut r, subvol("d"));
+        fails(&mut r, Command::Snapshot(Snapshot {path: s("e"), ..Snapshot::default()}));
+        assert_eq!(r.subvol_dir(), Some(tmp.0.join("dest/c").as_path()));
+    }
+
+    #[test]
+    fn symlink_then_write() {
+        let tmp = TempDir::new("symlink-dir");
+        let outside = tmp.0.join("outside");
+        let mut r = receiver(&tmp, &outside);
+        fails(&mut r, Command::MkFile(MkFile {path: s("l/pwned"), ino: 258}));
+        fails(&mut r, write("l/pwned", b"x"));
+        fails(&mut r, Command::MkDir(MkDir {path: s("l/d"), ino: 259}));
+        fails(&mut r, Command::SymLink(SymLink {path: s("l/s"), ino: 260, path_link: s("/")}));
+        r.apply(&Command::MkFile(MkFile {path: s("f"), ino: 261})).unwrap();
+        fails(&mut r, Command::Rename(Rename {path: s("f"), path_to: s("l/f")}));
+        fails(&mut r, Command::Link(Link {path: s("l/h"), path_link: s("f")}));
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn symlink_to_file() {
+        let tmp = TempDir::new("symlink-file");
+        let victim = tmp.0.join("outside/victim");
+        fs::write(&victim, b"secret").unwrap();
+        fs::set_permissions(&victim, fs::Permissions::from_mode(0o600)).unwrap();
+        let mut r = receiver(&tmp, &victim);
+        fails(&mut r, write("l", b"x"));
+        fails(&mut r, Command::Truncate(Truncate {path: s("l"), size: 0}));
+        let _ = r.apply(&Command::Chmod(Chmod {path: s("l"), mode: 0o666}));
+        /* Cloning from the symlink would read the file it points to */
+        r.apply(&Command::MkFile(MkFile {path: s("f"), ino: 258})).unwrap();
+        fails(&mut r, Command::Clone(commands::Clone {path: s("f"), file_offset: 0, clone_len: 6, clone_uuid: Uuid {data: [1; 16]},
+                                                      clone_ctransid: 1, clone_path: s("l"), clone_offset: 0}));
+        assert_eq!(fs::read(&victim).unwrap(), b"secret");
+        assert_eq!(fs::metadata(&victim).unwrap().permissions().mode() & 0o7777, 0o600);
+        assert_eq!(fs::read(tmp.0.join("dest/sv/f")).unwrap(), b"");
+    }
+
+    #[test]
+    fn parent_rejected() {
+        let tmp = TempDir::new("parent");
+        let mut r = receiver(&tmp, Path::new("x"));
+        fails(&mut r, Command::MkFile(MkFile {path: s("../../outside/f"), ino: 258}));
+        let opts = ReceiveOptions {paths: PathPolicy {allow_parent: true, ..PathPolicy::default()}, ..ReceiveOptions::default()};
+        let mut r = Receiver::with_options(tmp.0.join("dest"), opts);
+        r.apply(&Command::Subvol(Subvol {path: s("sv2"), ..Subvol::default()})).unwrap();
+        fails(&mut r, Command::MkFile(MkFile {path: s("../f"), ino: 258}));
+        assert_eq!(fs::read_dir(tmp.0.join("outside")).unwrap().count(), 0);
+        assert!(!tmp.0.join("dest/f").exists());
+    }
+}
@@ -0,0 +1,276 @@
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use crate::*;
+
+/// Why `SafePath` rejected a path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathError {
+    Empty,
+    Absolute,
+    /// A `..` component, or one leading above the root.
+    Parent,
+    /// A `.` component or an empty one (`a//b`).
+    CurDir,
+    Nul,
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            PathError::Empty => "path is empty",
+            PathError::Absolute => "path is absolute",
+            PathError::Parent => "path leads outside of its root",
+            PathError::CurDir => "path contains a '.' or empty component",
+            PathError::Nul => "path contains a NUL byte",
+        })
+    }
+}
+
+impl error::Error for PathError {}
+
+/// What `SafePath::with_policy` accepts. NUL bytes are always rejected.
+///
+/// The default policy only allows plain relative paths, like those written
+/// by `btrfs send`, and the empty path.
+#[derive(Clone, Copy, Debug)]
+pub struct PathPolicy {
+    /// The empty path, which names the root itself.
+    pub allow_empty: bool,
+    /// Leading slashes are ignored instead of rejected.
+    pub allow_absolute: bool,
+    /// `.` and empty components are skipped instead of rejected.
+    pub allow_cur_dir: bool,
+    /// `..` components are resolved, as long as they do not lead above the
+    /// root, instead of rejected.
+    pub allow_parent: bool,
+}
+
+impl Default for PathPolicy {
+    fn default() -> Self {
+        PathPolicy {allow_empty: true, allow_absolute: false, allow_cur_dir: false, allow_parent: false}
+    }
+}
+
+/// Relative path without components leading above its root.
+///
+/// Stream paths are attacker controlled, so everything that creates files
+/// from a stream should go through this type rather than using the
+/// `BtrfsString` directly. The path is kept normalized: names separated by
+/// single slashes, none of them `.` or `..`.
+///
+/// The checks are lexical only. A symlink in the directory the path is used
+/// in can still lead outside of it, so files must be opened one component
+/// at a time without following symlinks, as `Receiver` does.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SafePath(BtrfsString);
+
+impl SafePath {
+    /// Checks a path with the default policy.
+    pub fn new(path: &[u8]) -> std::result::Result<SafePath, PathError> {
+        SafePath::with_policy(path, &PathPolicy::default())
+    }
+    pub fn with_policy(path: &[u8], policy: &PathPolicy) -> std::result::Result<SafePath, PathError> {
+        if path.contains(&0) {
+            return Err(PathError::Nul);
+        }
+        let mut rest = path;
+        if rest.first() == Some(&b'/') {
+            if !policy.allow_absolute {
+                return Err(PathError::Absolute);
+            }
+            while let Some(r) = rest.strip_prefix(b"/") {
+                rest = r;
+            }
+        }
+        let mut names: Vec<&[u8]> = Vec::new();
+        if !rest.is_empty() {
+            for name in rest.split(|&b| b == b'/') {
+                match name {
+                    b"" | b"." if policy.allow_cur_dir => {},
+                    b"" | b"." => return Err(PathError::CurDir),
+                    b".." if policy.allow_parent => {
+                        names.pop().ok_or(PathError::Parent)?;
+                    },
+                    b".." => return Err(PathError::Parent),
+                    name => names.push(name),
+                }
+            }
+        }
+        if names.is_empty() && !policy.allow_empty {
+            return Err(PathError::Empty);
+        }
+        Ok(SafePath(BtrfsString::from(names.join(&b'/'))))
+    }
+    /// The empty path.
+    pub fn root() -> SafePath {
+        SafePath::default()
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+    pub fn as_btrfs_string(&self) -> &BtrfsString {
+        &self.0
+    }
+    pub fn into_btrfs_string(self) -> BtrfsString {
+        self.0
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    /// Names in the path, from the root down.
+    pub fn components(&self) -> impl Iterator<Item = BtrfsStr<'_>> {
+        self.as_bytes().split(|&b| b == b'/').filter(|n| !n.is_empty()).map(BtrfsStr::new)
+    }
+    /// Last name in the path, None for the empty path.
+    pub fn file_name(&self) -> Option<BtrfsStr<'_>> {
+        self.components().last()
+    }
+    /// Path without the last name, None for the empty path.
+    pub fn parent(&self) -> Option<SafePath> {
+        if self.is_empty() {
+            return None;
+        }
+        let bytes = self.as_bytes();
+        let end = bytes.iter().rposition(|&b| b == b'/').unwrap_or(0);
+        Some(SafePath(BtrfsString::from(&bytes[..end])))
+    }
+    /// `other` below this path.
+    pub fn join(&self, other: &SafePath) -> SafePath {
+        match (self.is_empty(), other.is_empty()) {
+            (_, true) => self.clone(),
+            (true, false) => other.clone(),
+            (false, false) => {
+                let mut bytes = self.as_bytes().to_vec();
+                bytes.push(b'/');
+                bytes.extend_from_slice(other.as_bytes());
+                SafePath(BtrfsString::from(bytes))
+            },
+        }
+    }
+    #[cfg(unix)]
+    pub fn as_path(&self) -> &Path {
+        self.0.as_path()
+    }
+    /// The path below `root`, joined lexically. Symlinks below `root` are
+    /// followed when the result is used.
+    #[cfg(unix)]
+    pub fn under(&self, root: &Path) -> PathBuf {
+        if self.is_empty() {
+            return root.to_path_buf();
+        }
+        root.join(self.as_path())
+    }
+}
+
+impl<'a> TryFrom<&'a BtrfsString> for SafePath {
+    type Error = PathError;
+    fn try_from(path: &'a BtrfsString) -> std::result::Result<SafePath, PathError> {
+        SafePath::new(path.as_bytes())
+    }
+}
+
+impl<'a> TryFrom<BtrfsStr<'a>> for SafePath {
+    type Error = PathError;
+    fn try_from(path: BtrfsStr<'a>) -> std::result::Result<SafePath, PathError> {
+        SafePath::new(path.as_bytes())
+    }
+}
+
+impl From<SafePath> for BtrfsString {
+    fn from(path: SafePath) -> BtrfsString {
+        path.0
+    }
+}
+
+impl AsRef<[u8]> for SafePath {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl fmt::Debug for SafePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Display for SafePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(path: &str, policy: &PathPolicy) -> std::result::Result<String, PathError> {
+        SafePath::with_policy(path.as_bytes(), policy).map(|p| p.to_string())
+    }
+
+    fn path(p: &str) -> SafePath {
+        SafePath::new(p.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn default_policy() {
+        let policy = PathPolicy::default();
+        assert_eq!(check("a/b", &policy), Ok("a/b".to_string()));
+        assert_eq!(check("a/.../b", &policy), Ok("a/.../b".to_string()));
+        assert_eq!(check("", &policy), Ok(String::new()));
+        assert_eq!(check("/a", &policy), Err(PathError::Absolute));
+        assert_eq!(check("/", &policy), Err(PathError::Absolute));
+        assert_eq!(check("a/../b", &policy), Err(PathError::Parent));
+        assert_eq!(check("..", &policy), Err(PathError::Parent));
+        assert_eq!(check("a/./b", &policy), Err(PathError::CurDir));
+        assert_eq!(check("a//b", &policy), Err(PathError::CurDir));
+        assert_eq!(check("a/", &policy), Err(PathError::CurDir));
+        assert_eq!(check("a\0b", &policy), Err(PathError::Nul));
+    }
+
+    #[test]
+    fn lax_policy() {
+        let policy = PathPolicy {allow_empty: true, allow_absolute: true, allow_cur_dir: true, allow_parent: true};
+        assert_eq!(check("//a/./b/", &policy), Ok("a/b".to_string()));
+        assert_eq!(check("a/../b", &policy), Ok("b".to_string()));
+        assert_eq!(check("a/..", &policy), Ok(String::new()));
+        assert_eq!(check("/", &policy), Ok(String::new()));
+        assert_eq!(check("a/../..", &policy), Err(PathError::Parent));
+        assert_eq!(check("/../a", &policy), Err(PathError::Parent));
+        /* NUL bytes are rejected by every policy */
+        assert_eq!(check("a\0", &policy), Err(PathError::Nul));
+    }
+
+    #[test]
+    fn empty() {
+        let policy = PathPolicy {allow_empty: false, ..PathPolicy::default()};
+        assert_eq!(check("", &policy), Err(PathError::Empty));
+        let policy = PathPolicy {allow_empty: false, allow_parent: true, ..PathPolicy::default()};
+        assert_eq!(check("a/..", &policy), Err(PathError::Empty));
+        assert_eq!(check("a", &policy), Ok("a".to_string()));
+    }
+
+    #[test]
+    fn parent_and_join() {
+        assert_eq!(path("a/b/c").parent(), Some(path("a/b")));
+        assert_eq!(path("a").parent(), Some(SafePath::root()));
+        assert_eq!(SafePath::root().parent(), None);
+        assert_eq!(path("a/b").file_name().map(|n| n.as_bytes()), Some(&b"b"[..]));
+        assert_eq!(SafePath::root().file_name(), None);
+        assert_eq!(path("a").join(&path("b/c")), path("a/b/c"));
+        assert_eq!(SafePath::root().join(&path("b")), path("b"));
+        assert_eq!(path("a").join(&SafePath::root()), path("a"));
+        assert_eq!(path("a/b").components().map(|n| n.as_bytes().to_vec()).collect::<Vec<_>>(),
+                   vec![b"a".to_vec(), b"b".to_vec()]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn under() {
+        assert_eq!(path("a/b").under(Path::new("/r")), Path::new("/r/a/b"));
+        assert_eq!(SafePath::root().under(Path::new("/r")), Path::new("/r"));
+    }
+}
@@ -0,0 +1,195 @@
+use std::io;
+use std::io::Read;
+use std::ops::Range;
+use crate::*;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// What `SalvageReader` found next in the stream.
+#[derive(Debug)]
+pub enum Salvaged {
+    Command(Command),
+    /// Bytes that could not be decoded and were skipped, with the error that
+    /// started the skip.
+    Skipped { range: Range<u64>, error: Error },
+}
+
+/// Reader that recovers what it can from damaged streams.
+///
+/// When a command can not be decoded, the reader scans forward for the next
+/// stream header or command header with a matching checksum (see
+/// `Decoder::resync`), reports the skipped bytes and goes on from there.
+/// Only I/O errors are returned as errors.
+pub struct SalvageReader<R: Read> {
+    r: R,
+    decoder: Decoder,
+    eof: bool,
+    /* Start of the bytes being skipped and the reason */
+    skipping: Option<(u64, Error)>,
+    failed: bool,
+}
+
+impl<R: Read> SalvageReader<R> {
+    pub fn new(r: R) -> SalvageReader<R> {
+        SalvageReader::with_options(r, ReaderOptions::default())
+    }
+    pub fn with_options(r: R, opts: ReaderOptions) -> SalvageReader<R> {
+        SalvageReader {r, decoder: Decoder::with_options(opts), eof: false, skipping: None, failed: false}
+    }
+    pub fn get_ref(&self) -> &R {
+        &self.r
+    }
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+    /// Segment of the last command read, once a stream header or command was found.
+    pub fn segment(&self) -> Option<&Segment> {
+        self.decoder.segment()
+    }
+    pub fn command_location(&self) -> CommandLocation {
+        self.decoder.command_location()
+    }
+    pub fn bytes_consumed(&self) -> u64 {
+        self.decoder.bytes_consumed()
+    }
+    /// Fails unless the stream ended with the END command.
+    pub fn finish(&self) -> Result<()> {
+        self.decoder.finish()
+    }
+    pub fn read(&mut self) -> Result<Option<Salvaged>> {
+        loop {
+            if self.skipping.is_some() {
+                if self.decoder.resync(self.eof) || self.eof {
+                    let (start, error) = self.skipping.take().unwrap();
+                    let range = start..self.decoder.bytes_consumed();
+                    return Ok(Some(Salvaged::Skipped {range, error}));
+                }
+                self.fill()?;
+                continue;
+            }
+            match self.decoder.decode_generic() {
+                Ok(Some(cmd)) => {
+                    let loc = self.decoder.command_location();
+                    return Ok(Some(match self.decoder.parse_command(cmd) {
+                        Ok(cmd) => Salvaged::Command(cmd),
+                        Err(error) => Salvaged::Skipped {range: loc.offset..loc.end(), error},
+                    }));
+                },
+                Ok(None) if !self.eof => self.fill()?,
+                Ok(None) if self.decoder.buffered() == 0 => return Ok(None),
+                Ok(None) => {
+                    let error = Error::Truncated {pos: self.decoder.position()};
+                    self.skipping = Some((self.decoder.bytes_consumed(), error));
+                },
+                Err(error) => self.skipping = Some((self.decoder.bytes_consumed(), error)),
+            }
+        }
+    }
+    fn fill(&mut self) -> Result<()> {
+        let mut chunk = [0u8; CHUNK_SIZE];
+        loop {
+            match self.r.read(&mut chunk) {
+                Ok(0) => self.eof = true,
+                Ok(n) => self.decoder.feed(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::Io {source: e, pos: self.decoder.position()}),
+            }
+            return Ok(());
+        }
+    }
+}
+
+impl<R: Read> Iterator for SalvageReader<R> {
+    type Item = Result<Salvaged>;
+    fn next(&mut self) -> Option<Result<Salvaged>> {
+        if self.failed {
+            return None;
+        }
+        let r = self.read().transpose();
+        if let Some(Err(_)) = r {
+            self.failed = true;
+        }
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{End, MkFile, Subvol};
+
+    /* A stream of Subvol `name`, MkFile a, MkFile b and END, with the
+     * offsets of the commands from the start of the stream */
+    fn stream(name: &str) -> (Vec<u8>, Vec<u64>) {
+        let mut w = BtrfsWriter::new(Vec::new(), 1).unwrap();
+        let mut offsets = Vec::new();
+        for cmd in [
+            Command::Subvol(Subvol {path: BtrfsString::from(name), ..Subvol::default()}),
+            Command::MkFile(MkFile {path: BtrfsString::from("a"), ino: 257}),
+            Command::MkFile(MkFile {path: BtrfsString::from("b"), ino: 258}),
+            Command::End(End {}),
+        ] {
+            offsets.push(w.get_ref().len() as u64);
+            w.write_command(&cmd).unwrap();
+        }
+        (w.into_inner(), offsets)
+    }
+
+    /* Commands as their names, skipped ranges with the error */
+    fn salvage(bytes: &[u8]) -> (Vec<String>, SalvageReader<&[u8]>) {
+        let mut r = SalvageReader::new(bytes);
+        let mut found = Vec::new();
+        while let Some(s) = r.read().unwrap() {
+            found.push(match s {
+                Salvaged::Command(Command::Subvol(c)) => format!("Subvol {}", c.path),
+                Salvaged::Command(Command::MkFile(c)) => format!("MkFile {}", c.path),
+                Salvaged::Command(Command::End(_)) => "End".to_string(),
+                Salvaged::Command(c) => panic!("{:?}", c),
+                Salvaged::Skipped {range, error} => format!("Skipped {:?} {:?}", range, error),
+            });
+        }
+        (found, r)
+    }
+
+    #[test]
+    fn damaged_command() {
+        let (mut bytes, offsets) = stream("sv");
+        /* The name of the first MkFile */
+        bytes[offsets[1] as usize + CMD_HEADER_LEN + 4] = b'x';
+        let (found, r) = salvage(&bytes);
+        assert_eq!(found[0], "Subvol sv");
+        assert!(found[1].starts_with(&format!("Skipped {:?} ChecksumMismatch", offsets[1]..offsets[2])), "{}", found[1]);
+        assert_eq!(&found[2..], ["MkFile b", "End"]);
+        assert_eq!(r.segment().unwrap().index, 0);
+        r.finish().unwrap();
+    }
+
+    #[test]
+    fn damaged_header_between_segments() {
+        let (first, _) = stream("sv1");
+        let (second, offsets) = stream("sv2");
+        let mut bytes = [&first[..], &second[..]].concat();
+        let header = first.len() as u64;
+        bytes[header as usize] = b'x';
+        let (found, r) = salvage(&bytes);
+        assert_eq!(&found[..4], ["Subvol sv1", "MkFile a", "MkFile b", "End"]);
+        assert!(found[4].starts_with(&format!("Skipped {:?} BadMagic", header..header + offsets[0])), "{}", found[4]);
+        assert_eq!(&found[5..], ["Subvol sv2", "MkFile a", "MkFile b", "End"]);
+        let segment = r.segment().unwrap();
+        assert_eq!((segment.index, segment.version, segment.offset), (1, 1, header + offsets[0]));
+        assert_eq!(segment.path(), Some(&BtrfsString::from("sv2")));
+        r.finish().unwrap();
+    }
+
+    #[test]
+    fn garbage_at_end() {
+        let (mut bytes, _) = stream("sv");
+        let end = bytes.len() as u64;
+        bytes.extend_from_slice(b"garbage!!!");
+        let (found, r) = salvage(&bytes);
+        assert_eq!(&found[..4], ["Subvol sv", "MkFile a", "MkFile b", "End"]);
+        assert!(found[4].starts_with(&format!("Skipped {:?} Truncated", end..end + 10)), "{}", found[4]);
+        assert_eq!(found.len(), 5);
+        assert_eq!(r.bytes_consumed(), end + 10);
+    }
+}
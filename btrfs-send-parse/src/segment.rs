@@ -0,0 +1,72 @@
+use std::convert::TryFrom;
+use crate::definitions::*;
+use crate::*;
+
+/// Part of the input that describes one subvolume.
+///
+/// `btrfs send` with several subvolumes either writes a complete stream for
+/// each of them, header and END command included, or a single stream in which
+/// every subvolume starts with its own Subvol or Snapshot command. Readers
+/// start a new segment in both cases.
+#[derive(Clone, Debug, Default)]
+pub struct Segment {
+    /// Index of the segment in the input, starting at zero.
+    pub index: u32,
+    /// Offset of the stream header from the start of the input, or of the
+    /// Subvol/Snapshot command if the segment has no header of its own.
+    pub offset: u64,
+    pub version: u32,
+    /// Set once the Subvol command of the segment was read.
+    pub subvol: Option<commands::Subvol>,
+    /// Set once the Snapshot command of the segment was read.
+    pub snapshot: Option<commands::Snapshot>,
+    /// The END command of the segment was read.
+    pub ended: bool,
+}
+
+impl Segment {
+    pub(crate) fn start(index: u32, offset: u64, version: u32) -> Segment {
+        Segment {index, offset, version, ..Segment::default()}
+    }
+    /// Path of the subvolume or snapshot, once known.
+    pub fn path(&self) -> Option<&BtrfsString> {
+        match (&self.subvol, &self.snapshot) {
+            (Some(s), _) => Some(&s.path),
+            (_, Some(s)) => Some(&s.path),
+            _ => None,
+        }
+    }
+    /* A further stream header is expected after the END command */
+    pub(crate) fn expects_header(&self, opts: &ReaderOptions) -> bool {
+        opts.concatenated && self.ended
+    }
+    /* The input is complete if its last segment was */
+    pub(crate) fn check_end(&self, pos: Position) -> Result<()> {
+        if !self.ended {
+            return Err(Error::MissingEnd {pos});
+        }
+        Ok(())
+    }
+    pub(crate) fn note_header(&mut self, header: &CommandHeader) {
+        if header.cmd == Cmd::END as u16 {
+            self.ended = true;
+        }
+    }
+    /* Records the subvolume of the segment. A second Subvol or Snapshot
+     * command starts a new segment that shares the stream header. */
+    pub(crate) fn note_command(&mut self, cmd: &borrowed::Unknown, pos: Position) {
+        let c = match Cmd::try_from(cmd.header.cmd) {
+            Ok(c @ Cmd::SUBVOL) | Ok(c @ Cmd::SNAPSHOT) => c,
+            _ => return,
+        };
+        if self.subvol.is_some() || self.snapshot.is_some() {
+            *self = Segment::start(self.index + 1, pos.offset, self.version);
+        }
+        /* Malformed commands are reported when they are parsed */
+        match borrowed::parse_known_command(c, *cmd).map(borrowed::Command::into_owned) {
+            Ok(Command::Subvol(c)) => self.subvol = Some(c),
+            Ok(Command::Snapshot(c)) => self.snapshot = Some(c),
+            _ => {},
+        }
+    }
+}
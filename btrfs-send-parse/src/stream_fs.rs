@@ -0,0 +1,515 @@
+use std::collections::{BTreeMap, HashMap};
+use crate::commands::Payload;
+use crate::*;
+
+const S_IFMT: u64 = 0o170000;
+const S_IFSOCK: u64 = 0o140000;
+const S_IFREG: u64 = 0o100000;
+const S_IFBLK: u64 = 0o060000;
+const S_IFDIR: u64 = 0o040000;
+const S_IFCHR: u64 = 0o020000;
+const S_IFIFO: u64 = 0o010000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+    /// File of the parent snapshot that the stream did not say more about.
+    Unknown,
+}
+
+impl FileKind {
+    fn from_mode(mode: u64) -> FileKind {
+        match mode & S_IFMT {
+            S_IFREG => FileKind::File,
+            S_IFDIR => FileKind::Dir,
+            S_IFCHR => FileKind::CharDevice,
+            S_IFBLK => FileKind::BlockDevice,
+            S_IFIFO => FileKind::Fifo,
+            S_IFSOCK => FileKind::Socket,
+            _ => FileKind::Unknown,
+        }
+    }
+}
+
+/// File in a `StreamFs`.
+///
+/// Files created by the stream start out empty. Of files of the parent
+/// snapshot, only what the stream changed is known, the rest is None.
+#[derive(Clone, Debug)]
+pub struct Inode {
+    /// Inode number, if a command or an orphan name told it.
+    pub ino: Option<u64>,
+    pub kind: FileKind,
+    /// The file was created by the stream.
+    pub created: bool,
+    pub mode: Option<u64>,
+    pub uid: Option<u64>,
+    pub gid: Option<u64>,
+    pub rdev: u64,
+    pub size: Option<u64>,
+    pub atime: Option<Timespec>,
+    pub mtime: Option<Timespec>,
+    pub ctime: Option<Timespec>,
+    /// Extended attributes set by the stream, all of them for created files.
+    pub xattrs: BTreeMap<BtrfsString, Payload>,
+    pub fileattr: Option<u64>,
+    /// Number of names of the file in the model. Names in the parent
+    /// snapshot that the stream did not use are not counted.
+    pub nlink: u32,
+    pub symlink_target: Option<BtrfsString>,
+    /// Contents, if kept (see `StreamFs::with_data`) and known.
+    pub data: Option<Vec<u8>>,
+}
+
+impl Inode {
+    fn new(kind: FileKind, ino: Option<u64>, created: bool) -> Inode {
+        let size = if created && kind != FileKind::Dir { Some(0) } else { None };
+        Inode {
+            ino, kind, created, mode: None, uid: None, gid: None, rdev: 0, size,
+            atime: None, mtime: None, ctime: None, xattrs: BTreeMap::new(), fileattr: None,
+            nlink: 1, symlink_target: None, data: None,
+        }
+    }
+    /* Extends the size, unless it is not known */
+    fn grow(&mut self, end: u64) {
+        if let Some(ref mut size) = self.size {
+            *size = (*size).max(end);
+        }
+    }
+}
+
+/// Files and directories of a subvolume, replayed from a send stream.
+///
+/// Applies the commands to a tree of inodes in memory, so that the result
+/// can be inspected without receiving the stream. Names are tracked by a
+/// `PathTracker`, paths given to the lookup functions are those after the
+/// commands applied so far. File contents are only kept if asked for.
+///
+/// Like `PathTracker`, a Subvol or Snapshot command starts a new tree.
+pub struct StreamFs {
+    tracker: PathTracker,
+    inodes: Vec<Inode>,
+    /* Inode of each name */
+    names: HashMap<NodeId, usize>,
+    max_data_len: u64,
+}
+
+impl Default for StreamFs {
+    fn default() -> Self {
+        StreamFs::new()
+    }
+}
+
+impl StreamFs {
+    pub fn new() -> StreamFs {
+        StreamFs::with_data(0)
+    }
+    /// Also keeps the contents of created files up to `max_len` bytes long.
+    ///
+    /// Contents are lost for files written with skipped payloads, clones,
+    /// encoded writes and extent updates, and for larger files.
+    pub fn with_data(max_len: u64) -> StreamFs {
+        let tracker = PathTracker::new();
+        let root = tracker.root();
+        let mut fs = StreamFs {tracker, inodes: Vec::new(), names: HashMap::new(), max_data_len: max_len};
+        fs.add(root, Inode::new(FileKind::Dir, None, false));
+        fs
+    }
+    /// Names of the files, with their final paths once the stream was applied.
+    pub fn tracker(&self) -> &PathTracker {
+        &self.tracker
+    }
+    pub fn apply(&mut self, cmd: &Command) {
+        match cmd {
+            Command::Subvol(_) | Command::Snapshot(_) => {
+                let root = self.tracker.apply(cmd).unwrap();
+                let created = matches!(cmd, Command::Subvol(_));
+                self.add(root, Inode::new(FileKind::Dir, None, created));
+            },
+            Command::MkFile(c) => {
+                self.create(cmd, &c.path, Inode::new(FileKind::File, Some(c.ino), true));
+            },
+            Command::MkDir(c) => {
+                self.create(cmd, &c.path, Inode::new(FileKind::Dir, Some(c.ino), true));
+            },
+            Command::MkNod(c) => self.create_special(cmd, &c.path, c.ino, c.mode, c.rdev),
+            Command::MkFifo(c) => self.create_special(cmd, &c.path, c.ino, c.mode | S_IFIFO, c.rdev),
+            Command::MkSock(c) => self.create_special(cmd, &c.path, c.ino, c.mode | S_IFSOCK, c.rdev),
+            Command::SymLink(c) => {
+                let mut inode = Inode::new(FileKind::Symlink, Some(c.ino), true);
+                inode.size = Some(c.path_link.len() as u64);
+                inode.symlink_target = Some(c.path_link.clone());
+                self.create(cmd, &c.path, inode);
+            },
+            Command::Link(c) => {
+                let target = self.tracker.lookup(c.path_link.as_bytes());
+                let target = self.ensure(target);
+                self.unlink_at(&c.path);
+                let node = self.tracker.apply(cmd).unwrap();
+                self.names.insert(node, target);
+                self.inodes[target].nlink += 1;
+            },
+            Command::Rename(c) => {
+                let from = self.tracker.get(c.path.as_bytes());
+                let to = self.tracker.get(c.path_to.as_bytes());
+                if to.is_some() && to != from {
+                    self.unlink_at(&c.path_to);
+                }
+                let node = self.tracker.apply(cmd).unwrap();
+                self.ensure(node);
+            },
+            Command::UnLink(_) | Command::RmDir(_) => {
+                let node = self.tracker.apply(cmd).unwrap();
+                let i = self.ensure(node);
+                if let Command::RmDir(_) = cmd {
+                    self.inodes[i].kind = FileKind::Dir;
+                }
+                self.unlink(node);
+            },
+            Command::Write(c) => {
+                let end = c.file_offset.saturating_add(c.data.len());
+                let max = self.max_data_len;
+                let inode = self.file_mut(cmd);
+                inode.grow(end);
+                let keep = match (&mut inode.data, &c.data) {
+                    (Some(data), Payload::Data(d)) if end <= max => {
+                        let start = c.file_offset as usize;
+                        if data.len() < end as usize {
+                            data.resize(end as usize, 0);
+                        }
+                        data[start..end as usize].copy_from_slice(d);
+                        true
+                    },
+                    _ => false,
+                };
+                if !keep {
+                    inode.data = None;
+                }
+            },
+            Command::Clone(c) => {
+                let inode = self.file_mut(cmd);
+                inode.grow(c.file_offset.saturating_add(c.clone_len));
+                inode.data = None;
+            },
+            Command::EncodedWrite(c) => {
+                let inode = self.file_mut(cmd);
+                inode.grow(c.file_offset.saturating_add(c.unencoded_file_len));
+                inode.data = None;
+            },
+            Command::UpdateExtent(c) => {
+                let inode = self.file_mut(cmd);
+                inode.grow(c.file_offset.saturating_add(c.size));
+                inode.data = None;
+            },
+            Command::Truncate(c) => {
+                let max = self.max_data_len;
+                let inode = self.file_mut(cmd);
+                inode.size = Some(c.size);
+                if c.size > max {
+                    inode.data = None;
+                }
+                if let Some(ref mut data) = inode.data {
+                    data.resize(c.size as usize, 0);
+                }
+            },
+            Command::Fallocate(c) => {
+                let max = self.max_data_len;
+                let inode = self.file_mut(cmd);
+                let end = c.file_offset.saturating_add(c.size);
+                if c.mode & FALLOC_FL_KEEP_SIZE == 0 {
+                    inode.grow(end);
+                    if end > max {
+                        inode.data = None;
+                    }
+                }
+                if let Some(ref mut data) = inode.data {
+                    if c.mode & FALLOC_FL_KEEP_SIZE == 0 && data.len() < end as usize {
+                        data.resize(end as usize, 0);
+                    }
+                    if c.mode & (FALLOC_FL_PUNCH_HOLE | FALLOC_FL_ZERO_RANGE) != 0 {
+                        let start = (c.file_offset as usize).min(data.len());
+                        let end = (end as usize).min(data.len());
+                        data[start..end].iter_mut().for_each(|b| *b = 0);
+                    }
+                }
+            },
+            Command::SetXattr(c) => {
+                self.inode_mut(cmd).xattrs.insert(c.xattr_name.clone(), c.xattr_data.clone());
+            },
+            Command::RemoveXattr(c) => {
+                self.inode_mut(cmd).xattrs.remove(&c.xattr_name);
+            },
+            Command::Chmod(c) => self.inode_mut(cmd).mode = Some(c.mode),
+            Command::Chown(c) => {
+                let inode = self.inode_mut(cmd);
+                inode.uid = Some(c.uid);
+                inode.gid = Some(c.gid);
+            },
+            Command::Utimes(c) => {
+                let inode = self.inode_mut(cmd);
+                inode.atime = Some(c.atime);
+                inode.mtime = Some(c.mtime);
+                inode.ctime = Some(c.ctime);
+            },
+            Command::FileAttr(c) => self.inode_mut(cmd).fileattr = Some(c.fileattr),
+            Command::EnableVerity(_) => {
+                self.inode_mut(cmd);
+            },
+            Command::End(_) | Command::Unknown(_) => {},
+        }
+    }
+    /// Name currently at `path`. Paths not seen before are taken to exist in
+    /// the parent snapshot and are added, like with `PathTracker::lookup`.
+    pub fn lookup(&mut self, path: &[u8]) -> NodeId {
+        let node = self.tracker.lookup(path);
+        self.ensure(node);
+        node
+    }
+    /// File at `path`, if the stream used it.
+    pub fn stat(&self, path: &[u8]) -> Option<&Inode> {
+        self.inode(self.tracker.get(path)?)
+    }
+    pub fn inode(&self, node: NodeId) -> Option<&Inode> {
+        self.names.get(&node).map(|&i| &self.inodes[i])
+    }
+    /// Entries of the directory at `path` that the stream used, sorted by name.
+    pub fn read_dir(&self, path: &[u8]) -> Option<Vec<(&BtrfsString, &Inode)>> {
+        let dir = self.tracker.get(path)?;
+        if self.inode(dir)?.kind != FileKind::Dir {
+            return None;
+        }
+        let mut entries: Vec<_> = self.tracker.children(dir)
+            .filter_map(|(name, node)| Some((name, self.inode(node)?)))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        Some(entries)
+    }
+    pub fn read_link(&self, path: &[u8]) -> Option<&BtrfsString> {
+        self.stat(path)?.symlink_target.as_ref()
+    }
+    /// Contents of the file at `path`, if kept.
+    pub fn read(&self, path: &[u8]) -> Option<&[u8]> {
+        self.stat(path)?.data.as_deref()
+    }
+    fn add(&mut self, node: NodeId, inode: Inode) -> usize {
+        self.inodes.push(inode);
+        let id = self.inodes.len() - 1;
+        self.names.insert(node, id);
+        id
+    }
+    fn create(&mut self, cmd: &Command, path: &BtrfsString, mut inode: Inode) {
+        if inode.kind == FileKind::File && self.max_data_len > 0 {
+            inode.data = Some(Vec::new());
+        }
+        self.unlink_at(path);
+        let node = self.tracker.apply(cmd).unwrap();
+        self.add(node, inode);
+        self.ensure(node);
+    }
+    fn create_special(&mut self, cmd: &Command, path: &BtrfsString, ino: u64, mode: u64, rdev: u64) {
+        let mut inode = Inode::new(FileKind::from_mode(mode), Some(ino), true);
+        inode.mode = Some(mode);
+        inode.rdev = rdev;
+        self.create(cmd, path, inode);
+    }
+    /* Inode of the path of a command that changes a file */
+    fn inode_mut(&mut self, cmd: &Command) -> &mut Inode {
+        let node = self.tracker.apply(cmd).unwrap();
+        let i = self.ensure(node);
+        &mut self.inodes[i]
+    }
+    /* Same for commands that only apply to regular files */
+    fn file_mut(&mut self, cmd: &Command) -> &mut Inode {
+        let inode = self.inode_mut(cmd);
+        if inode.kind == FileKind::Unknown {
+            inode.kind = FileKind::File;
+        }
+        inode
+    }
+    /* Removes the name at `path` if there is one, before it is replaced */
+    fn unlink_at(&mut self, path: &BtrfsString) {
+        if let Some(node) = self.tracker.get(path.as_bytes()) {
+            self.unlink(node);
+        }
+    }
+    fn unlink(&mut self, node: NodeId) {
+        if let Some(i) = self.names.remove(&node) {
+            self.inodes[i].nlink = self.inodes[i].nlink.saturating_sub(1);
+        }
+    }
+    /* Inode of a name, files of the parent snapshot and the directories
+     * they are in are added when first seen */
+    fn ensure(&mut self, node: NodeId) -> usize {
+        if let Some(&i) = self.names.get(&node) {
+            return i;
+        }
+        let ino = self.tracker.ino(node);
+        let i = self.add(node, Inode::new(FileKind::Unknown, ino, false));
+        let mut child = node;
+        while let Some(dir) = self.tracker.parent(child) {
+            match self.names.get(&dir) {
+                Some(&d) => {
+                    if self.inodes[d].kind == FileKind::Unknown {
+                        self.inodes[d].kind = FileKind::Dir;
+                    }
+                    break;
+                },
+                None => {
+                    let ino = self.tracker.ino(dir);
+                    self.add(dir, Inode::new(FileKind::Dir, ino, false));
+                },
+            }
+            child = dir;
+        }
+        i
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::*;
+
+    fn s(path: &str) -> BtrfsString {
+        BtrfsString::from(path)
+    }
+
+    fn apply(fs: &mut StreamFs, cmds: &[Command]) {
+        for c in cmds {
+            fs.apply(c);
+        }
+    }
+
+    fn mkfile(path: &str, ino: u64) -> Command {
+        Command::MkFile(MkFile {path: s(path), ino})
+    }
+
+    fn write(path: &str, offset: u64, data: &[u8]) -> Command {
+        Command::Write(commands::Write {path: s(path), file_offset: offset, data: Payload::from(data.to_vec())})
+    }
+
+    fn truncate(path: &str, size: u64) -> Command {
+        Command::Truncate(Truncate {path: s(path), size})
+    }
+
+    fn fallocate(path: &str, mode: u32, offset: u64, size: u64) -> Command {
+        Command::Fallocate(Fallocate {path: s(path), mode, file_offset: offset, size})
+    }
+
+    fn size(fs: &StreamFs, path: &str) -> Option<u64> {
+        fs.stat(path.as_bytes()).unwrap().size
+    }
+
+    #[test]
+    fn nlink() {
+        let mut fs = StreamFs::new();
+        apply(&mut fs, &[mkfile("f", 257), Command::Link(Link {path: s("g"), path_link: s("f")})]);
+        assert_eq!(fs.stat(b"f").unwrap().nlink, 2);
+        assert_eq!(fs.stat(b"g").unwrap().ino, Some(257));
+        fs.apply(&Command::UnLink(UnLink {path: s("f")}));
+        assert!(fs.stat(b"f").is_none());
+        assert_eq!(fs.stat(b"g").unwrap().nlink, 1);
+        /* Renaming over the last name drops the file */
+        apply(&mut fs, &[mkfile("h", 258), Command::Rename(Rename {path: s("h"), path_to: s("g")})]);
+        assert_eq!(fs.stat(b"g").unwrap().ino, Some(258));
+        assert_eq!(fs.stat(b"g").unwrap().nlink, 1);
+    }
+
+    #[test]
+    fn file_size() {
+        let mut fs = StreamFs::new();
+        apply(&mut fs, &[mkfile("f", 257), write("f", 0, &[1; 10])]);
+        assert_eq!(size(&fs, "f"), Some(10));
+        fs.apply(&write("f", 100, &[1; 5]));
+        assert_eq!(size(&fs, "f"), Some(105));
+        fs.apply(&truncate("f", 50));
+        assert_eq!(size(&fs, "f"), Some(50));
+        fs.apply(&fallocate("f", FALLOC_FL_KEEP_SIZE, 0, 200));
+        assert_eq!(size(&fs, "f"), Some(50));
+        fs.apply(&fallocate("f", 0, 0, 200));
+        assert_eq!(size(&fs, "f"), Some(200));
+        /* Not known for files of the parent snapshot, until truncated */
+        fs.apply(&write("old", 0, &[1; 10]));
+        assert_eq!(fs.stat(b"old").unwrap().kind, FileKind::File);
+        assert_eq!(size(&fs, "old"), None);
+        fs.apply(&truncate("old", 3));
+        assert_eq!(size(&fs, "old"), Some(3));
+    }
+
+    #[test]
+    fn data() {
+        let mut fs = StreamFs::with_data(100);
+        apply(&mut fs, &[mkfile("f", 257), write("f", 0, b"hello"), write("f", 7, b"xy")]);
+        assert_eq!(fs.read(b"f"), Some(&b"hello\0\0xy"[..]));
+        fs.apply(&truncate("f", 3));
+        assert_eq!(fs.read(b"f"), Some(&b"hel"[..]));
+        fs.apply(&fallocate("f", FALLOC_FL_KEEP_SIZE | FALLOC_FL_PUNCH_HOLE, 1, 1));
+        assert_eq!(fs.read(b"f"), Some(&b"h\0l"[..]));
+        fs.apply(&fallocate("f", 0, 0, 5));
+        assert_eq!(fs.read(b"f"), Some(&b"h\0l\0\0"[..]));
+        fs.apply(&Command::Clone(commands::Clone {path: s("f"), clone_len: 2, clone_path: s("f"), ..commands::Clone::default()}));
+        assert_eq!(fs.read(b"f"), None);
+        /* Over the limit */
+        apply(&mut fs, &[mkfile("g", 258), write("g", 0, &[1; 100])]);
+        assert_eq!(fs.read(b"g").map(|d| d.len()), Some(100));
+        fs.apply(&write("g", 100, &[1]));
+        assert_eq!(fs.read(b"g"), None);
+        assert_eq!(size(&fs, "g"), Some(101));
+        /* Not kept by default */
+        let mut fs = StreamFs::new();
+        apply(&mut fs, &[mkfile("f", 257), write("f", 0, b"hello")]);
+        assert_eq!(fs.read(b"f"), None);
+    }
+
+    #[test]
+    fn read_dir() {
+        let mut fs = StreamFs::new();
+        apply(&mut fs, &[
+            Command::MkDir(MkDir {path: s("d"), ino: 257}),
+            mkfile("d/c", 258),
+            mkfile("d/a", 259),
+            Command::MkDir(MkDir {path: s("d/b"), ino: 260}),
+        ]);
+        let entries = fs.read_dir(b"d").unwrap();
+        let names: Vec<_> = entries.iter().map(|(n, i)| (n.to_string(), i.kind)).collect();
+        assert_eq!(names, [("a".to_string(), FileKind::File), ("b".to_string(), FileKind::Dir),
+                           ("c".to_string(), FileKind::File)]);
+        assert_eq!(fs.read_dir(b"").unwrap().len(), 1);
+        assert!(fs.read_dir(b"d/a").is_none());
+        assert!(fs.read_dir(b"missing").is_none());
+        /* Directories of the parent snapshot are known from their entries */
+        fs.apply(&write("old/f", 0, b"x"));
+        assert_eq!(fs.stat(b"old").unwrap().kind, FileKind::Dir);
+        assert_eq!(fs.read_dir(b"old").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn read_link() {
+        let mut fs = StreamFs::new();
+        apply(&mut fs, &[mkfile("f", 257), Command::SymLink(SymLink {path: s("l"), ino: 258, path_link: s("../target")})]);
+        assert_eq!(fs.read_link(b"l"), Some(&s("../target")));
+        let l = fs.stat(b"l").unwrap();
+        assert_eq!((l.kind, l.size), (FileKind::Symlink, Some(9)));
+        assert_eq!(fs.read_link(b"f"), None);
+    }
+
+    #[test]
+    fn xattrs() {
+        let mut fs = StreamFs::new();
+        let set = |name: &str, value: &[u8]| Command::SetXattr(SetXattr {path: s("f"), xattr_name: s(name),
+                                                                           xattr_data: Payload::from(value.to_vec())});
+        apply(&mut fs, &[mkfile("f", 257), set("user.a", b"1"), set("user.b", b"2"), set("user.a", b"3")]);
+        let xattrs = &fs.stat(b"f").unwrap().xattrs;
+        assert_eq!(xattrs.len(), 2);
+        assert_eq!(xattrs[&s("user.a")].data(), Some(&b"3"[..]));
+        fs.apply(&Command::RemoveXattr(RemoveXattr {path: s("f"), xattr_name: s("user.a")}));
+        let names: Vec<_> = fs.stat(b"f").unwrap().xattrs.keys().cloned().collect();
+        assert_eq!(names, [s("user.b")]);
+    }
+}
@@ -0,0 +1,240 @@
+use std::fmt;
+use std::borrow::{Borrow, Cow};
+#[cfg(unix)]
+use std::ffi::{OsStr, OsString};
+#[cfg(unix)]
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+#[cfg(unix)]
+use std::path::Path;
+
+/// Path or name as stored in the stream.
+///
+/// Linux file names are arbitrary bytes, so no encoding is assumed and the
+/// value is kept exactly as it was sent.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BtrfsString(Vec<u8>);
+
+impl BtrfsString {
+    pub fn new() -> BtrfsString {
+        BtrfsString(Vec::new())
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    /// Returns the string if it is valid UTF-8.
+    pub fn to_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+    #[cfg(unix)]
+    pub fn as_os_str(&self) -> &OsStr {
+        OsStr::from_bytes(&self.0)
+    }
+    #[cfg(unix)]
+    pub fn as_path(&self) -> &Path {
+        Path::new(self.as_os_str())
+    }
+    pub fn as_btrfs_str(&self) -> BtrfsStr<'_> {
+        BtrfsStr(&self.0)
+    }
+}
+
+/// Path or name borrowed from the buffer it was parsed from.
+///
+/// The borrowed counterpart of `BtrfsString`, with the same byte semantics.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BtrfsStr<'a>(&'a [u8]);
+
+impl<'a> BtrfsStr<'a> {
+    pub fn new(bytes: &'a [u8]) -> BtrfsStr<'a> {
+        BtrfsStr(bytes)
+    }
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.0
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    /// Returns the string if it is valid UTF-8.
+    pub fn to_str(&self) -> Option<&'a str> {
+        std::str::from_utf8(self.0).ok()
+    }
+    pub fn to_string_lossy(&self) -> Cow<'a, str> {
+        String::from_utf8_lossy(self.0)
+    }
+    #[cfg(unix)]
+    pub fn as_os_str(&self) -> &'a OsStr {
+        OsStr::from_bytes(self.0)
+    }
+    #[cfg(unix)]
+    pub fn as_path(&self) -> &'a Path {
+        Path::new(self.as_os_str())
+    }
+    pub fn to_btrfs_string(&self) -> BtrfsString {
+        BtrfsString(self.0.to_vec())
+    }
+}
+
+impl<'a> From<&'a [u8]> for BtrfsStr<'a> {
+    fn from(v: &'a [u8]) -> BtrfsStr<'a> {
+        BtrfsStr(v)
+    }
+}
+
+impl<'a> From<&'a str> for BtrfsStr<'a> {
+    fn from(s: &'a str) -> BtrfsStr<'a> {
+        BtrfsStr(s.as_bytes())
+    }
+}
+
+impl<'a> From<&'a BtrfsString> for BtrfsStr<'a> {
+    fn from(s: &'a BtrfsString) -> BtrfsStr<'a> {
+        s.as_btrfs_str()
+    }
+}
+
+impl<'a> From<BtrfsStr<'a>> for BtrfsString {
+    fn from(s: BtrfsStr<'a>) -> BtrfsString {
+        s.to_btrfs_string()
+    }
+}
+
+impl<'a> AsRef<[u8]> for BtrfsStr<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.0
+    }
+}
+
+impl<'a, 'b> PartialEq<&'b str> for BtrfsStr<'a> {
+    fn eq(&self, other: &&'b str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl<'a> PartialEq<BtrfsString> for BtrfsStr<'a> {
+    fn eq(&self, other: &BtrfsString) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl<'a> fmt::Debug for BtrfsStr<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_escaped(self.0, f)
+    }
+}
+
+impl<'a> fmt::Display for BtrfsStr<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_string_lossy())
+    }
+}
+
+impl From<Vec<u8>> for BtrfsString {
+    fn from(v: Vec<u8>) -> BtrfsString {
+        BtrfsString(v)
+    }
+}
+
+impl<'a> From<&'a [u8]> for BtrfsString {
+    fn from(v: &'a [u8]) -> BtrfsString {
+        BtrfsString(v.to_vec())
+    }
+}
+
+impl From<String> for BtrfsString {
+    fn from(s: String) -> BtrfsString {
+        BtrfsString(s.into_bytes())
+    }
+}
+
+impl<'a> From<&'a str> for BtrfsString {
+    fn from(s: &'a str) -> BtrfsString {
+        BtrfsString(s.as_bytes().to_vec())
+    }
+}
+
+#[cfg(unix)]
+impl From<OsString> for BtrfsString {
+    fn from(s: OsString) -> BtrfsString {
+        BtrfsString(s.into_vec())
+    }
+}
+
+#[cfg(unix)]
+impl<'a> From<&'a OsStr> for BtrfsString {
+    fn from(s: &'a OsStr) -> BtrfsString {
+        BtrfsString(s.as_bytes().to_vec())
+    }
+}
+
+/* Lets maps keyed by BtrfsString be searched with borrowed bytes */
+impl Borrow<[u8]> for BtrfsString {
+    fn borrow(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for BtrfsString {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(unix)]
+impl AsRef<OsStr> for BtrfsString {
+    fn as_ref(&self) -> &OsStr {
+        self.as_os_str()
+    }
+}
+
+#[cfg(unix)]
+impl AsRef<Path> for BtrfsString {
+    fn as_ref(&self) -> &Path {
+        self.as_path()
+    }
+}
+
+impl<'a> PartialEq<&'a str> for BtrfsString {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+/* Quoted like a str, with bytes that are not valid UTF-8 shown as \xNN */
+fn fmt_escaped(bytes: &[u8], f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "\"")?;
+    for chunk in bytes.utf8_chunks() {
+        write!(f, "{}", chunk.valid().escape_debug())?;
+        for b in chunk.invalid() {
+            write!(f, "\\x{:02x}", b)?;
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Debug for BtrfsString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_escaped(&self.0, f)
+    }
+}
+
+/* Lossy, invalid sequences are replaced by U+FFFD */
+impl fmt::Display for BtrfsString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_string_lossy())
+    }
+}
@@ -0,0 +1,507 @@
+use std::collections::{HashMap, HashSet};
+use crate::*;
+
+/// Temporary name `o<ino>-<gen>-<index>` given by the kernel to new inodes
+/// and to inodes moved out of the way, until they get their final name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OrphanName {
+    pub ino: u64,
+    pub gen: u64,
+    pub index: u64,
+}
+
+impl OrphanName {
+    pub fn parse(name: &[u8]) -> Option<OrphanName> {
+        let rest = name.strip_prefix(b"o")?;
+        let mut parts = rest.split(|&b| b == b'-');
+        let mut number = || {
+            let part = parts.next()?;
+            if part.is_empty() || !part.iter().all(u8::is_ascii_digit) {
+                return None;
+            }
+            std::str::from_utf8(part).ok()?.parse().ok()
+        };
+        let orphan = OrphanName {ino: number()?, gen: number()?, index: number()?};
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(orphan)
+    }
+}
+
+/// File or directory known to a `PathTracker`.
+///
+/// Stays the same when the file is renamed, or when a directory above it is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+#[derive(Clone, Debug)]
+struct Node {
+    name: BtrfsString,
+    /* None for roots and for removed nodes */
+    parent: Option<usize>,
+    children: HashMap<BtrfsString, usize>,
+    ino: Option<u64>,
+    root: bool,
+}
+
+/// Follows the renames in a stream, to tell what the paths in its commands
+/// are called in the received subvolume.
+///
+/// Paths in commands are only valid at the time of the command: files are
+/// created under orphan names and renamed later, and renaming a directory
+/// renames everything below it. The tracker keeps a tree of the names seen
+/// so far, so keep the `NodeId` of a path when its command is applied and
+/// ask for `path` once the stream was read. Files of the parent snapshot
+/// are added when a command uses them. Inode numbers come from the create
+/// commands and from orphan names.
+///
+/// A Subvol or Snapshot command starts a new tree, nodes of the previous
+/// subvolume keep their paths.
+#[derive(Clone, Debug)]
+pub struct PathTracker {
+    nodes: Vec<Node>,
+    root: usize,
+}
+
+/* What a command does to the tree */
+enum Op<'a> {
+    Start,
+    Create(&'a [u8], u64),
+    Rename(&'a [u8], &'a [u8]),
+    Link(&'a [u8], &'a [u8]),
+    Remove(&'a [u8]),
+    Use(&'a [u8]),
+    Nothing,
+}
+
+impl<'a> Op<'a> {
+    fn of(cmd: &'a Command) -> Op<'a> {
+        match cmd {
+            Command::Subvol(_) | Command::Snapshot(_) => Op::Start,
+            Command::MkFile(c) => Op::Create(c.path.as_bytes(), c.ino),
+            Command::MkDir(c) => Op::Create(c.path.as_bytes(), c.ino),
+            Command::MkNod(c) => Op::Create(c.path.as_bytes(), c.ino),
+            Command::MkFifo(c) => Op::Create(c.path.as_bytes(), c.ino),
+            Command::MkSock(c) => Op::Create(c.path.as_bytes(), c.ino),
+            Command::SymLink(c) => Op::Create(c.path.as_bytes(), c.ino),
+            Command::Rename(c) => Op::Rename(c.path.as_bytes(), c.path_to.as_bytes()),
+            Command::Link(c) => Op::Link(c.path.as_bytes(), c.path_link.as_bytes()),
+            Command::UnLink(c) => Op::Remove(c.path.as_bytes()),
+            Command::RmDir(c) => Op::Remove(c.path.as_bytes()),
+            Command::Write(c) => Op::Use(c.path.as_bytes()),
+            Command::Clone(c) => Op::Use(c.path.as_bytes()),
+            Command::SetXattr(c) => Op::Use(c.path.as_bytes()),
+            Command::RemoveXattr(c) => Op::Use(c.path.as_bytes()),
+            Command::Truncate(c) => Op::Use(c.path.as_bytes()),
+            Command::Chmod(c) => Op::Use(c.path.as_bytes()),
+            Command::Chown(c) => Op::Use(c.path.as_bytes()),
+            Command::Utimes(c) => Op::Use(c.path.as_bytes()),
+            Command::UpdateExtent(c) => Op::Use(c.path.as_bytes()),
+            Command::Fallocate(c) => Op::Use(c.path.as_bytes()),
+            Command::FileAttr(c) => Op::Use(c.path.as_bytes()),
+            Command::EncodedWrite(c) => Op::Use(c.path.as_bytes()),
+            Command::EnableVerity(c) => Op::Use(c.path.as_bytes()),
+            Command::End(_) | Command::Unknown(_) => Op::Nothing,
+        }
+    }
+    fn of_ref(cmd: &borrowed::Command<'a>) -> Op<'a> {
+        use crate::borrowed::Command as C;
+        match *cmd {
+            C::Subvol(_) | C::Snapshot(_) => Op::Start,
+            C::MkFile(c) => Op::Create(c.path.as_bytes(), c.ino),
+            C::MkDir(c) => Op::Create(c.path.as_bytes(), c.ino),
+            C::MkNod(c) => Op::Create(c.path.as_bytes(), c.ino),
+            C::MkFifo(c) => Op::Create(c.path.as_bytes(), c.ino),
+            C::MkSock(c) => Op::Create(c.path.as_bytes(), c.ino),
+            C::SymLink(c) => Op::Create(c.path.as_bytes(), c.ino),
+            C::Rename(c) => Op::Rename(c.path.as_bytes(), c.path_to.as_bytes()),
+            C::Link(c) => Op::Link(c.path.as_bytes(), c.path_link.as_bytes()),
+            C::UnLink(c) => Op::Remove(c.path.as_bytes()),
+            C::RmDir(c) => Op::Remove(c.path.as_bytes()),
+            C::Write(c) => Op::Use(c.path.as_bytes()),
+            C::Clone(c) => Op::Use(c.path.as_bytes()),
+            C::SetXattr(c) => Op::Use(c.path.as_bytes()),
+            C::RemoveXattr(c) => Op::Use(c.path.as_bytes()),
+            C::Truncate(c) => Op::Use(c.path.as_bytes()),
+            C::Chmod(c) => Op::Use(c.path.as_bytes()),
+            C::Chown(c) => Op::Use(c.path.as_bytes()),
+            C::Utimes(c) => Op::Use(c.path.as_bytes()),
+            C::UpdateExtent(c) => Op::Use(c.path.as_bytes()),
+            C::Fallocate(c) => Op::Use(c.path.as_bytes()),
+            C::FileAttr(c) => Op::Use(c.path.as_bytes()),
+            C::EncodedWrite(c) => Op::Use(c.path.as_bytes()),
+            C::EnableVerity(c) => Op::Use(c.path.as_bytes()),
+            C::End | C::Unknown(_) => Op::Nothing,
+        }
+    }
+}
+
+/* Splits a path into the parent directory and the last component */
+fn split_last(path: &[u8]) -> (&[u8], &[u8]) {
+    let path = trim_slashes(path);
+    match path.iter().rposition(|&b| b == b'/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => (&[], path),
+    }
+}
+
+fn trim_slashes(mut path: &[u8]) -> &[u8] {
+    while let Some(rest) = path.strip_suffix(b"/") {
+        path = rest;
+    }
+    path
+}
+
+impl Default for PathTracker {
+    fn default() -> Self {
+        PathTracker::new()
+    }
+}
+
+impl PathTracker {
+    pub fn new() -> PathTracker {
+        let mut tracker = PathTracker {nodes: Vec::new(), root: 0};
+        tracker.root = tracker.add_root();
+        tracker
+    }
+    /// Root of the current subvolume, the empty path.
+    pub fn root(&self) -> NodeId {
+        NodeId(self.root)
+    }
+    /// Applies a command, returns the node of its PATH attribute afterwards.
+    ///
+    /// That is the new node for create and link commands, the moved node for
+    /// Rename and the removed one for UnLink and RmDir. For Subvol and
+    /// Snapshot, it is the root of the new tree.
+    pub fn apply(&mut self, cmd: &Command) -> Option<NodeId> {
+        self.apply_op(Op::of(cmd))
+    }
+    /// Like `apply`, for commands read with `BtrfsReader::read_command_ref`.
+    pub fn apply_ref(&mut self, cmd: &borrowed::Command) -> Option<NodeId> {
+        self.apply_op(Op::of_ref(cmd))
+    }
+    fn apply_op(&mut self, op: Op) -> Option<NodeId> {
+        let id = match op {
+            Op::Start => {
+                self.root = self.add_root();
+                self.root
+            },
+            Op::Create(path, ino) => self.create(path, Some(ino)),
+            Op::Rename(from, to) => {
+                let id = self.lookup_node(from);
+                let (dir, name) = split_last(to);
+                let dir = self.lookup_node(dir);
+                /* A directory can not be moved into itself, nor replace the root */
+                if !self.nodes[id].root && !name.is_empty() && !self.is_below(dir, id) {
+                    self.detach(id);
+                    self.attach(id, dir, name);
+                }
+                id
+            },
+            Op::Link(path, target) => {
+                let target = self.lookup_node(target);
+                let ino = self.nodes[target].ino;
+                self.create(path, ino)
+            },
+            Op::Remove(path) => {
+                let id = self.lookup_node(path);
+                self.detach(id);
+                id
+            },
+            Op::Use(path) => self.lookup_node(path),
+            Op::Nothing => return None,
+        };
+        Some(NodeId(id))
+    }
+    /// Node currently at `path`. Paths not seen before are taken to exist
+    /// in the parent snapshot and are added.
+    pub fn lookup(&mut self, path: &[u8]) -> NodeId {
+        NodeId(self.lookup_node(path))
+    }
+    /// Node currently at `path`, if it was seen before.
+    pub fn get(&self, path: &[u8]) -> Option<NodeId> {
+        let mut id = self.root;
+        for name in path.split(|&b| b == b'/').filter(|n| !n.is_empty()) {
+            id = *self.nodes[id].children.get(name)?;
+        }
+        Some(NodeId(id))
+    }
+    /// Current path of a node relative to its subvolume, None if it, or a
+    /// directory above it, was removed.
+    pub fn path(&self, id: NodeId) -> Option<BtrfsString> {
+        let mut names = Vec::new();
+        let mut id = id.0;
+        while !self.nodes[id].root {
+            names.push(self.nodes[id].name.as_bytes());
+            id = self.nodes[id].parent?;
+        }
+        names.reverse();
+        Some(BtrfsString::from(names.join(&b'/')))
+    }
+    /// Inode number of a node, if a command or an orphan name told it.
+    pub fn ino(&self, id: NodeId) -> Option<u64> {
+        self.nodes[id.0].ino
+    }
+    /// Directory the node is in, None for roots and removed nodes.
+    pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+        self.nodes[id.0].parent.map(NodeId)
+    }
+    /// Name of the node in its directory, the last one if it was removed.
+    pub fn name(&self, id: NodeId) -> &BtrfsString {
+        &self.nodes[id.0].name
+    }
+    /// Nodes in a directory, in no particular order.
+    pub fn children(&self, id: NodeId) -> impl Iterator<Item = (&BtrfsString, NodeId)> + '_ {
+        self.nodes[id.0].children.iter().map(|(name, &id)| (name, NodeId(id)))
+    }
+    fn add_root(&mut self) -> usize {
+        self.nodes.push(Node {name: BtrfsString::new(), parent: None, children: HashMap::new(),
+                              ino: None, root: true});
+        self.nodes.len() - 1
+    }
+    fn lookup_node(&mut self, path: &[u8]) -> usize {
+        let mut id = self.root;
+        for name in path.split(|&b| b == b'/').filter(|n| !n.is_empty()) {
+            id = match self.nodes[id].children.get(name) {
+                Some(&child) => child,
+                None => self.add_child(id, name, None),
+            };
+        }
+        id
+    }
+    /* An empty path names the root, which is never replaced */
+    fn create(&mut self, path: &[u8], ino: Option<u64>) -> usize {
+        let (dir, name) = split_last(path);
+        let dir = self.lookup_node(dir);
+        if name.is_empty() {
+            return dir;
+        }
+        if let Some(&old) = self.nodes[dir].children.get(name) {
+            self.detach(old);
+        }
+        self.add_child(dir, name, ino)
+    }
+    fn add_child(&mut self, dir: usize, name: &[u8], ino: Option<u64>) -> usize {
+        let ino = ino.or_else(|| OrphanName::parse(name).map(|o| o.ino));
+        let id = self.nodes.len();
+        self.nodes.push(Node {name: BtrfsString::from(name), parent: Some(dir), children: HashMap::new(),
+                              ino, root: false});
+        self.nodes[dir].children.insert(BtrfsString::from(name), id);
+        id
+    }
+    fn is_below(&self, mut id: usize, dir: usize) -> bool {
+        loop {
+            if id == dir {
+                return true;
+            }
+            match self.nodes[id].parent {
+                Some(parent) => id = parent,
+                None => return false,
+            }
+        }
+    }
+    /* Removes the node from its directory, the nodes below it stay with it */
+    fn detach(&mut self, id: usize) {
+        if let Some(dir) = self.nodes[id].parent.take() {
+            let name = std::mem::take(&mut self.nodes[id].name);
+            self.nodes[dir].children.remove(&name);
+            self.nodes[id].name = name;
+        }
+    }
+    /* Moves a detached node to `dir`, replacing what was there */
+    fn attach(&mut self, id: usize, dir: usize, name: &[u8]) {
+        if let Some(&old) = self.nodes[dir].children.get(name) {
+            self.detach(old);
+        }
+        let node = &mut self.nodes[id];
+        node.name = BtrfsString::from(name);
+        node.parent = Some(dir);
+        if node.ino.is_none() {
+            node.ino = OrphanName::parse(name).map(|o| o.ino);
+        }
+        self.nodes[dir].children.insert(BtrfsString::from(name), id);
+    }
+}
+
+/* Files of the parent snapshot by their directory and name there. They are
+ * recorded, with the directories above them, before the stream moves or
+ * removes them, so that paths in the parent snapshot can still be followed. */
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Origins {
+    nodes: HashMap<(NodeId, BtrfsString), NodeId>,
+    seen: HashSet<NodeId>,
+}
+
+impl Origins {
+    /* Call while `node` is still where it was in the parent snapshot */
+    pub(crate) fn see(&mut self, tracker: &PathTracker, node: NodeId) {
+        let mut id = Some(node);
+        while let Some(node) = id {
+            if !self.seen.insert(node) {
+                break;
+            }
+            id = tracker.parent(node);
+            if let Some(dir) = id {
+                self.nodes.insert((dir, tracker.name(node).clone()), node);
+            }
+        }
+    }
+    /* Current path of the file at `origin` in the parent snapshot */
+    pub(crate) fn current_path(&self, tracker: &PathTracker, origin: &[u8]) -> Option<BtrfsString> {
+        let mut id = tracker.root();
+        let mut names = origin.split(|&b| b == b'/').filter(|n| !n.is_empty());
+        while let Some(name) = names.next() {
+            match self.nodes.get(&(id, BtrfsString::from(name))) {
+                Some(&node) => id = node,
+                None => {
+                    /* Not seen yet, so still in the same place */
+                    let mut path = tracker.path(id)?.into_bytes();
+                    for name in Some(name).into_iter().chain(names) {
+                        if !path.is_empty() {
+                            path.push(b'/');
+                        }
+                        path.extend_from_slice(name);
+                    }
+                    return Some(BtrfsString::from(path));
+                },
+            }
+        }
+        tracker.path(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::*;
+
+    fn s(path: &str) -> BtrfsString {
+        BtrfsString::from(path)
+    }
+
+    fn mkfile(path: &str, ino: u64) -> Command {
+        Command::MkFile(MkFile {path: s(path), ino})
+    }
+
+    fn mkdir(path: &str, ino: u64) -> Command {
+        Command::MkDir(MkDir {path: s(path), ino})
+    }
+
+    fn rename(from: &str, to: &str) -> Command {
+        Command::Rename(Rename {path: s(from), path_to: s(to)})
+    }
+
+    fn path(t: &PathTracker, id: NodeId) -> Option<String> {
+        t.path(id).map(|p| p.to_string())
+    }
+
+    #[test]
+    fn orphan_name() {
+        assert_eq!(OrphanName::parse(b"o257-12-0"), Some(OrphanName {ino: 257, gen: 12, index: 0}));
+        assert_eq!(OrphanName::parse(b"o257-12"), None);
+        assert_eq!(OrphanName::parse(b"o257-12-0-1"), None);
+        assert_eq!(OrphanName::parse(b"o257--0"), None);
+        assert_eq!(OrphanName::parse(b"a257-12-0"), None);
+    }
+
+    #[test]
+    fn orphan_renamed_into_place() {
+        let mut t = PathTracker::new();
+        let f = t.apply(&mkfile("o257-12-0", 257)).unwrap();
+        assert_eq!(path(&t, f), Some("o257-12-0".to_string()));
+        assert_eq!(t.apply(&rename("o257-12-0", "f")), Some(f));
+        assert_eq!(path(&t, f), Some("f".to_string()));
+        assert_eq!(t.ino(f), Some(257));
+        assert_eq!(t.get(b"f"), Some(f));
+        assert_eq!(t.get(b"o257-12-0"), None);
+        /* Files of the parent snapshot get their inode from an orphan name */
+        let old = t.lookup(b"o300-5-1");
+        assert_eq!(t.ino(old), Some(300));
+    }
+
+    #[test]
+    fn directory_rename() {
+        let mut t = PathTracker::new();
+        let d = t.apply(&mkdir("d", 257)).unwrap();
+        let sub = t.apply(&mkdir("d/sub", 258)).unwrap();
+        let f = t.apply(&mkfile("d/sub/f", 259)).unwrap();
+        t.apply(&rename("d", "e"));
+        assert_eq!(path(&t, f), Some("e/sub/f".to_string()));
+        assert_eq!(path(&t, sub), Some("e/sub".to_string()));
+        assert_eq!(t.parent(sub), Some(d));
+        assert_eq!(t.get(b"e/sub/f"), Some(f));
+        /* Paths of the parent snapshot are added when used */
+        let g = t.apply(&Command::Write(commands::Write {path: s("old/g"), ..commands::Write::default()})).unwrap();
+        t.apply(&rename("old", "e/old"));
+        assert_eq!(path(&t, g), Some("e/old/g".to_string()));
+    }
+
+    #[test]
+    fn rename_over_existing() {
+        let mut t = PathTracker::new();
+        let a = t.apply(&mkfile("a", 257)).unwrap();
+        let b = t.apply(&mkfile("b", 258)).unwrap();
+        t.apply(&rename("a", "b"));
+        assert_eq!(path(&t, a), Some("b".to_string()));
+        assert_eq!(path(&t, b), None);
+        assert_eq!(t.parent(b), None);
+        assert_eq!(t.name(b).as_bytes(), b"b");
+        assert_eq!(t.children(t.root()).count(), 1);
+    }
+
+    #[test]
+    fn unlink_and_create_again() {
+        let mut t = PathTracker::new();
+        let old = t.apply(&mkfile("f", 257)).unwrap();
+        assert_eq!(t.apply(&Command::UnLink(UnLink {path: s("f")})), Some(old));
+        assert_eq!(path(&t, old), None);
+        assert_eq!(t.get(b"f"), None);
+        let new = t.apply(&mkfile("f", 258)).unwrap();
+        assert_ne!(old, new);
+        assert_eq!(path(&t, new), Some("f".to_string()));
+        assert_eq!(t.ino(new), Some(258));
+        assert_eq!(path(&t, old), None);
+    }
+
+    #[test]
+    fn directory_into_itself() {
+        let mut t = PathTracker::new();
+        let d = t.apply(&mkdir("d", 257)).unwrap();
+        let sub = t.apply(&mkdir("d/sub", 258)).unwrap();
+        t.apply(&rename("d", "d/sub/d"));
+        t.apply(&rename("d", "d/x"));
+        assert_eq!(path(&t, d), Some("d".to_string()));
+        assert_eq!(path(&t, sub), Some("d/sub".to_string()));
+        assert_eq!(t.get(b"d/sub/d"), None);
+    }
+
+    #[test]
+    fn empty_path() {
+        let mut t = PathTracker::new();
+        let root = t.root();
+        assert_eq!(t.apply(&mkfile("", 257)), Some(root));
+        assert_eq!(t.apply(&mkdir("/", 258)), Some(root));
+        assert_eq!(t.children(root).count(), 0);
+        let f = t.apply(&mkfile("f", 259)).unwrap();
+        t.apply(&rename("f", ""));
+        assert_eq!(path(&t, f), Some("f".to_string()));
+        assert_eq!(t.get(b""), Some(root));
+    }
+
+    #[test]
+    fn new_subvolume() {
+        let mut t = PathTracker::new();
+        let f = t.apply(&mkfile("f", 257)).unwrap();
+        let root = t.apply(&Command::Subvol(Subvol {path: s("sv2"), ..Subvol::default()})).unwrap();
+        assert_eq!(root, t.root());
+        assert_eq!(t.get(b"f"), None);
+        assert_eq!(path(&t, f), Some("f".to_string()));
+        let g = t.apply(&mkfile("g", 257)).unwrap();
+        let snap = t.apply(&Command::Snapshot(Snapshot {path: s("sv3"), ..Snapshot::default()})).unwrap();
+        assert_ne!(snap, root);
+        assert_eq!(snap, t.root());
+        assert_eq!(t.children(snap).count(), 0);
+        assert_eq!(path(&t, g), Some("g".to_string()));
+    }
+}
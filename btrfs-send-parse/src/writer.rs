@@ -0,0 +1,458 @@
+use std::io;
+use std::io::Write;
+use std::convert::TryFrom;
+use byteorder::{LittleEndian, WriteBytesExt};
+use crate::definitions::*;
+use crate::*;
+
+/// Encodes commands into a send stream that `btrfs receive` accepts.
+///
+/// Typed commands are written with their attributes in the order the kernel
+/// uses. `Command::Unknown` is written as it was read, so a stream copied from
+/// `BtrfsReader::read_generic_command` is reproduced byte for byte.
+///
+/// Commands longer than the stream version allows are rejected before
+/// anything is written: btrfs receive reads v1 commands into a 64 KiB buffer.
+pub struct BtrfsWriter<W: Write> {
+    w: W,
+    version: u32,
+    /* Payload of the command being encoded, reused between commands */
+    buf: Vec<u8>,
+    offset: u64,
+    cmd_count: u64,
+}
+
+/* Builds the TLV payload of a single command */
+struct Tlvs<'a> {
+    buf: &'a mut Vec<u8>,
+    version: u32,
+}
+
+impl<'a> Tlvs<'a> {
+    fn put(&mut self, attr: Attr, value: &[u8]) -> Result<()> {
+        self.put_raw(attr as u16, value)
+    }
+    fn put_raw(&mut self, key: u16, value: &[u8]) -> Result<()> {
+        if value.len() > u16::MAX as usize {
+            return Err(Error::attribute_too_long(Attr::try_from(key).unwrap_or(Attr::UNSPEC)));
+        }
+        self.buf.write_u16::<LittleEndian>(key).unwrap();
+        self.buf.write_u16::<LittleEndian>(value.len() as u16).unwrap();
+        self.buf.extend_from_slice(value);
+        Ok(())
+    }
+    fn put_u8(&mut self, attr: Attr, v: u8) -> Result<()> {
+        self.put(attr, &[v])
+    }
+    fn put_u32(&mut self, attr: Attr, v: u32) -> Result<()> {
+        let mut b = [0u8; 4];
+        (&mut b[..]).write_u32::<LittleEndian>(v).unwrap();
+        self.put(attr, &b)
+    }
+    fn put_u64(&mut self, attr: Attr, v: u64) -> Result<()> {
+        let mut b = [0u8; 8];
+        (&mut b[..]).write_u64::<LittleEndian>(v).unwrap();
+        self.put(attr, &b)
+    }
+    fn put_string(&mut self, attr: Attr, v: &BtrfsString) -> Result<()> {
+        self.put(attr, v.as_bytes())
+    }
+    fn put_uuid(&mut self, attr: Attr, v: &Uuid) -> Result<()> {
+        self.put(attr, &v.data)
+    }
+    fn put_timespec(&mut self, attr: Attr, v: &Timespec) -> Result<()> {
+        let mut b = [0u8; 12];
+        (&mut b[0..8]).write_u64::<LittleEndian>(v.sec).unwrap();
+        (&mut b[8..12]).write_u32::<LittleEndian>(v.nsec).unwrap();
+        self.put(attr, &b)
+    }
+    fn put_payload(&mut self, attr: Attr, v: &commands::Payload) -> Result<()> {
+        let data = v.data().ok_or_else(|| Error::skipped_payload(attr))?;
+        if attr == Attr::DATA {
+            self.put_data(data)
+        } else {
+            self.put(attr, data)
+        }
+    }
+    /* Since v2, DATA has no length and must be the last attribute */
+    fn put_data(&mut self, v: &[u8]) -> Result<()> {
+        if self.version < 2 {
+            return self.put(Attr::DATA, v);
+        }
+        self.buf.write_u16::<LittleEndian>(Attr::DATA as u16).unwrap();
+        self.buf.extend_from_slice(v);
+        Ok(())
+    }
+}
+
+impl<W: Write> BtrfsWriter<W> {
+    /// Writes the stream header and returns a writer for the commands.
+    pub fn new(mut w: W, version: u32) -> Result<BtrfsWriter<W>> {
+        let pos = Position::default();
+        if version == 0 || version > MAX_VERSION {
+            return Err(Error::UnsupportedVersion {version, pos});
+        }
+        w.write_all(MAGIC.as_bytes()).map_err(|e| Error::from_io(e, pos))?;
+        w.write_u32::<LittleEndian>(version).map_err(|e| Error::from_io(e, pos))?;
+        Ok(BtrfsWriter {w, version, buf: Vec::new(), offset: STREAM_HEADER_LEN as u64, cmd_count: 0})
+    }
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+    pub fn get_ref(&self) -> &W {
+        &self.w
+    }
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+    pub fn flush(&mut self) -> Result<()> {
+        let pos = self.position();
+        self.w.flush().map_err(|e| Error::from_io(e, pos))
+    }
+    fn position(&self) -> Position {
+        Position {offset: self.offset, command: self.cmd_count}
+    }
+    pub fn write_command(&mut self, cmd: &Command) -> Result<()> {
+        let c = match cmd {
+            Command::Unknown(u) => return self.write_generic_command(u),
+            Command::Subvol(_) => Cmd::SUBVOL,
+            Command::Snapshot(_) => Cmd::SNAPSHOT,
+            Command::MkFile(_) => Cmd::MKFILE,
+            Command::MkDir(_) => Cmd::MKDIR,
+            Command::MkNod(_) => Cmd::MKNOD,
+            Command::MkFifo(_) => Cmd::MKFIFO,
+            Command::MkSock(_) => Cmd::MKSOCK,
+            Command::SymLink(_) => Cmd::SYMLINK,
+            Command::Rename(_) => Cmd::RENAME,
+            Command::Link(_) => Cmd::LINK,
+            Command::UnLink(_) => Cmd::UNLINK,
+            Command::RmDir(_) => Cmd::RMDIR,
+            Command::Write(_) => Cmd::WRITE,
+            Command::Clone(_) => Cmd::CLONE,
+            Command::SetXattr(_) => Cmd::SET_XATTR,
+            Command::RemoveXattr(_) => Cmd::REMOVE_XATTR,
+            Command::Truncate(_) => Cmd::TRUNCATE,
+            Command::Chmod(_) => Cmd::CHMOD,
+            Command::Chown(_) => Cmd::CHOWN,
+            Command::Utimes(_) => Cmd::UTIMES,
+            Command::UpdateExtent(_) => Cmd::UPDATE_EXTENT,
+            Command::End(_) => Cmd::END,
+            Command::Fallocate(_) => Cmd::FALLOCATE,
+            Command::FileAttr(_) => Cmd::FILEATTR,
+            Command::EncodedWrite(_) => Cmd::ENCODED_WRITE,
+            Command::EnableVerity(_) => Cmd::ENABLE_VERITY,
+        };
+        let pos = self.position();
+        if c.min_version() > self.version {
+            return Err(Error::UnsupportedCommand {cmd: c, version: self.version, pos});
+        }
+        self.encode_and_write(c as u16, |t| encode_command(t, cmd))
+            .map_err(|e| e.in_command(c, pos))
+    }
+    /// Writes the command with its attributes exactly in the order they were read.
+    pub fn write_generic_command(&mut self, cmd: &commands::Unknown) -> Result<()> {
+        let pos = self.position();
+        let c = Cmd::try_from(cmd.header.cmd).unwrap_or(Cmd::UNSPEC);
+        self.encode_and_write(cmd.header.cmd, |t| put_tlv_data(t, &cmd.data))
+            .map_err(|e| e.in_command(c, pos))
+    }
+    fn encode_and_write<F>(&mut self, cmd: u16, encode: F) -> Result<()>
+        where F: FnOnce(&mut Tlvs) -> Result<()>
+    {
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        let r = encode(&mut Tlvs {buf: &mut buf, version: self.version})
+            .and_then(|_| self.write_raw(cmd, &buf));
+        self.buf = buf;
+        r
+    }
+    /* Adds the header with length and checksum to an encoded payload */
+    fn write_raw(&mut self, cmd: u16, payload: &[u8]) -> Result<()> {
+        let pos = self.position();
+        let max = if self.version < 2 {
+            (SEND_BUF_SIZE_V1 - CMD_HEADER_LEN - 1) as u64
+        } else {
+            u32::MAX as u64
+        };
+        if payload.len() as u64 > max {
+            return Err(Error::LimitExceeded {limit: Limit::CommandLen, value: payload.len() as u64, max, pos});
+        }
+        let mut header = CommandHeader {len: payload.len() as u32, cmd, crc32: 0};
+        header.crc32 = command_crc32(&header, payload);
+        let mut hdr = [0u8; CMD_HEADER_LEN];
+        {
+            let mut h = &mut hdr[..];
+            h.write_u32::<LittleEndian>(header.len).unwrap();
+            h.write_u16::<LittleEndian>(header.cmd).unwrap();
+            h.write_u32::<LittleEndian>(header.crc32).unwrap();
+        }
+        let r: io::Result<()> = self.w.write_all(&hdr).and_then(|_| self.w.write_all(payload));
+        r.map_err(|e| Error::from_io(e, pos))?;
+        self.offset += (CMD_HEADER_LEN + payload.len()) as u64;
+        self.cmd_count += 1;
+        Ok(())
+    }
+}
+
+fn encode_command(t: &mut Tlvs, cmd: &Command) -> Result<()> {
+    match cmd {
+        Command::Unknown(_) => unreachable!(),
+        Command::Subvol(c) => {
+            t.put_string(Attr::PATH, &c.path)?;
+            t.put_uuid(Attr::UUID, &c.uuid)?;
+            t.put_u64(Attr::CTRANSID, c.ctransid)?;
+        },
+        Command::Snapshot(c) => {
+            t.put_string(Attr::PATH, &c.path)?;
+            t.put_uuid(Attr::UUID, &c.uuid)?;
+            t.put_u64(Attr::CTRANSID, c.ctransid)?;
+            t.put_uuid(Attr::CLONE_UUID, &c.clone_uuid)?;
+            t.put_u64(Attr::CLONE_CTRANSID, c.clone_ctransid)?;
+        },
+        Command::MkFile(c) => put_path_ino(t, &c.path, c.ino)?,
+        Command::MkDir(c) => put_path_ino(t, &c.path, c.ino)?,
+        Command::MkNod(c) => {
+            put_path_ino(t, &c.path, c.ino)?;
+            t.put_u64(Attr::RDEV, c.rdev)?;
+            t.put_u64(Attr::MODE, c.mode)?;
+        },
+        Command::MkFifo(c) => {
+            put_path_ino(t, &c.path, c.ino)?;
+            t.put_u64(Attr::RDEV, c.rdev)?;
+            t.put_u64(Attr::MODE, c.mode)?;
+        },
+        Command::MkSock(c) => {
+            put_path_ino(t, &c.path, c.ino)?;
+            t.put_u64(Attr::RDEV, c.rdev)?;
+            t.put_u64(Attr::MODE, c.mode)?;
+        },
+        Command::SymLink(c) => {
+            put_path_ino(t, &c.path, c.ino)?;
+            t.put_string(Attr::PATH_LINK, &c.path_link)?;
+        },
+        Command::Rename(c) => {
+            t.put_string(Attr::PATH, &c.path)?;
+            t.put_string(Attr::PATH_TO, &c.path_to)?;
+        },
+        Command::Link(c) => {
+            t.put_string(Attr::PATH, &c.path)?;
+            t.put_string(Attr::PATH_LINK, &c.path_link)?;
+        },
+        Command::UnLink(c) => t.put_string(Attr::PATH, &c.path)?,
+        Command::RmDir(c) => t.put_string(Attr::PATH, &c.path)?,
+        Command::Write(c) => {
+            t.put_string(Attr::PATH, &c.path)?;
+            t.put_u64(Attr::FILE_OFFSET, c.file_offset)?;
+            t.put_payload(Attr::DATA, &c.data)?;
+        },
+        Command::Clone(c) => {
+            t.put_string(Attr::PATH, &c.path)?;
+            t.put_u64(Attr::FILE_OFFSET, c.file_offset)?;
+            t.put_u64(Attr::CLONE_LEN, c.clone_len)?;
+            t.put_uuid(Attr::CLONE_UUID, &c.clone_uuid)?;
+            t.put_u64(Attr::CLONE_CTRANSID, c.clone_ctransid)?;
+            t.put_string(Attr::CLONE_PATH, &c.clone_path)?;
+            t.put_u64(Attr::CLONE_OFFSET, c.clone_offset)?;
+        },
+        Command::SetXattr(c) => {
+            t.put_string(Attr::PATH, &c.path)?;
+            t.put_string(Attr::XATTR_NAME, &c.xattr_name)?;
+            t.put_payload(Attr::XATTR_DATA, &c.xattr_data)?;
+        },
+        Command::RemoveXattr(c) => {
+            t.put_string(Attr::PATH, &c.path)?;
+            t.put_string(Attr::XATTR_NAME, &c.xattr_name)?;
+        },
+        Command::Truncate(c) => {
+            t.put_string(Attr::PATH, &c.path)?;
+            t.put_u64(Attr::SIZE, c.size)?;
+        },
+        Command::Chmod(c) => {
+            t.put_string(Attr::PATH, &c.path)?;
+            t.put_u64(Attr::MODE, c.mode)?;
+        },
+        Command::Chown(c) => {
+            t.put_string(Attr::PATH, &c.path)?;
+            t.put_u64(Attr::UID, c.uid)?;
+            t.put_u64(Attr::GID, c.gid)?;
+        },
+        Command::Utimes(c) => {
+            t.put_string(Attr::PATH, &c.path)?;
+            t.put_timespec(Attr::ATIME, &c.atime)?;
+            t.put_timespec(Attr::MTIME, &c.mtime)?;
+            t.put_timespec(Attr::CTIME, &c.ctime)?;
+        },
+        Command::UpdateExtent(c) => {
+            t.put_string(Attr::PATH, &c.path)?;
+            t.put_u64(Attr::FILE_OFFSET, c.file_offset)?;
+            t.put_u64(Attr::SIZE, c.size)?;
+        },
+        Command::End(_) => {},
+        Command::Fallocate(c) => {
+            t.put_string(Attr::PATH, &c.path)?;
+            t.put_u32(Attr::FALLOCATE_MODE, c.mode)?;
+            t.put_u64(Attr::FILE_OFFSET, c.file_offset)?;
+            t.put_u64(Attr::SIZE, c.size)?;
+        },
+        Command::FileAttr(c) => {
+            t.put_string(Attr::PATH, &c.path)?;
+            t.put_u64(Attr::FILEATTR, c.fileattr)?;
+        },
+        Command::EncodedWrite(c) => {
+            t.put_string(Attr::PATH, &c.path)?;
+            t.put_u64(Attr::FILE_OFFSET, c.file_offset)?;
+            t.put_u64(Attr::UNENCODED_FILE_LEN, c.unencoded_file_len)?;
+            t.put_u64(Attr::UNENCODED_LEN, c.unencoded_len)?;
+            t.put_u64(Attr::UNENCODED_OFFSET, c.unencoded_offset)?;
+            /* The kernel always sends both, also when they are none */
+            t.put_u32(Attr::COMPRESSION, c.compression)?;
+            t.put_u32(Attr::ENCRYPTION, c.encryption)?;
+            t.put_payload(Attr::DATA, &c.data)?;
+        },
+        Command::EnableVerity(c) => {
+            t.put_string(Attr::PATH, &c.path)?;
+            t.put_u8(Attr::VERITY_ALGORITHM, c.algorithm)?;
+            t.put_u32(Attr::VERITY_BLOCK_SIZE, c.block_size)?;
+            t.put(Attr::VERITY_SALT_DATA, &c.salt_data)?;
+            t.put(Attr::VERITY_SIG_DATA, &c.sig_data)?;
+        },
+    }
+    Ok(())
+}
+
+fn put_tlv_data(t: &mut Tlvs, data: &TLVData) -> Result<()> {
+    for e in data.entries.iter() {
+        if e.key == Attr::DATA as u16 {
+            t.put_data(&e.value)?;
+        } else {
+            t.put_raw(e.key, &e.value)?;
+        }
+    }
+    Ok(())
+}
+
+/* Payload of attributes that were read from a stream of `version` */
+pub(crate) fn encode_tlv_data(data: &TLVData, version: u32) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    put_tlv_data(&mut Tlvs {buf: &mut buf, version}, data)?;
+    Ok(buf)
+}
+
+/* Inode number 0 means it was not present in the original command */
+fn put_path_ino(t: &mut Tlvs, path: &BtrfsString, ino: u64) -> Result<()> {
+    t.put_string(Attr::PATH, path)?;
+    if ino != 0 {
+        t.put_u64(Attr::INO, ino)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::*;
+
+    fn s(path: &str) -> BtrfsString {
+        BtrfsString::from(path)
+    }
+
+    fn commands(version: u32) -> Vec<Command> {
+        let mut cmds = vec![
+            Command::Subvol(Subvol {path: s("sv"), uuid: Uuid {data: [7; 16]}, ctransid: 12}),
+            Command::MkFile(MkFile {path: s("o257-12-0"), ino: 257}),
+            Command::Rename(Rename {path: s("o257-12-0"), path_to: s("f")}),
+            Command::Write(commands::Write {path: s("f"), file_offset: 4096, data: Payload::from(vec![1; 3000])}),
+            Command::SetXattr(SetXattr {path: s("f"), xattr_name: s("user.a"), xattr_data: Payload::from(b"v".to_vec())}),
+            Command::Utimes(Utimes {path: s("f"), atime: Timespec {sec: 1, nsec: 2}, ..Utimes::default()}),
+        ];
+        if version >= 2 {
+            cmds.push(Command::Fallocate(Fallocate {path: s("f"), mode: FALLOC_FL_KEEP_SIZE, file_offset: 0, size: 8192}));
+            cmds.push(Command::EncodedWrite(EncodedWrite {
+                path: s("f"), file_offset: 8192, unencoded_file_len: 4096, unencoded_len: 4096,
+                unencoded_offset: 0, compression: 1, encryption: 0, data: Payload::from(vec![2; 100]),
+            }));
+        }
+        if version >= 3 {
+            cmds.push(Command::EnableVerity(EnableVerity {
+                path: s("f"), algorithm: 1, block_size: 4096, salt_data: vec![3; 8], sig_data: Vec::new(),
+            }));
+        }
+        cmds.push(Command::End(End {}));
+        cmds
+    }
+
+    fn write(version: u32, cmds: &[Command]) -> Result<Vec<u8>> {
+        let mut w = BtrfsWriter::new(Vec::new(), version)?;
+        for c in cmds {
+            w.write_command(c)?;
+        }
+        Ok(w.into_inner())
+    }
+
+    fn round_trip(version: u32) {
+        let cmds = commands(version);
+        let stream = write(version, &cmds).unwrap();
+        let mut r = BtrfsReader::new(&stream[..]).unwrap();
+        let mut read = Vec::new();
+        while let Some(c) = r.read_command().unwrap() {
+            read.push(c);
+        }
+        r.finish().unwrap();
+        assert_eq!(format!("{:?}", read), format!("{:?}", cmds));
+        assert_eq!(write(version, &read).unwrap(), stream);
+    }
+
+    #[test]
+    fn round_trip_v1() {
+        round_trip(1);
+    }
+
+    #[test]
+    fn round_trip_v2() {
+        round_trip(2);
+    }
+
+    #[test]
+    fn round_trip_v3() {
+        round_trip(3);
+    }
+
+    #[test]
+    fn newer_commands_rejected_in_v1() {
+        let cmds = commands(2);
+        match write(1, &cmds) {
+            Err(Error::UnsupportedCommand {cmd: Cmd::FALLOCATE, version: 1, ..}) => {},
+            r => panic!("{:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn v1_command_too_long() {
+        /* Header 10, PATH 5, FILE_OFFSET 12 and DATA 4 bytes, plus the data */
+        let write = |len| Command::Write(commands::Write {path: s("f"), file_offset: 0, data: Payload::from(vec![0; len])});
+        let mut w = BtrfsWriter::new(Vec::new(), 1).unwrap();
+        w.write_command(&write(65535 - 31)).unwrap();
+        let written = w.get_ref().len();
+        match w.write_command(&write(65536 - 31)) {
+            Err(Error::LimitExceeded {limit: Limit::CommandLen, value: 65526, max: 65525, pos}) => {
+                assert_eq!(pos, Position {offset: STREAM_HEADER_LEN as u64 + 65535, command: 1});
+            },
+            r => panic!("{:?}", r),
+        }
+        /* Nothing of the rejected command was written */
+        assert_eq!(w.get_ref().len(), written);
+        /* v2 has no such limit */
+        let mut w = BtrfsWriter::new(Vec::new(), 2).unwrap();
+        w.write_command(&write(65536 - 31)).unwrap();
+    }
+
+    #[test]
+    fn encoded_write_attributes() {
+        let cmd = Command::EncodedWrite(EncodedWrite {path: s("f"), data: Payload::from(vec![1; 10]), ..EncodedWrite::default()});
+        let stream = write(2, &[cmd]).unwrap();
+        let mut r = BtrfsReader::new(&stream[..]).unwrap();
+        let keys: Vec<u16> = r.read_generic_command().unwrap().unwrap().data.entries.iter().map(|e| e.key).collect();
+        assert_eq!(keys, [Attr::PATH, Attr::FILE_OFFSET, Attr::UNENCODED_FILE_LEN, Attr::UNENCODED_LEN,
+                          Attr::UNENCODED_OFFSET, Attr::COMPRESSION, Attr::ENCRYPTION, Attr::DATA].map(|a| a as u16));
+    }
+}
@@ -8,74 +8,88 @@ use clap::{App, Arg};
 use std::collections::HashMap;
 use std::iter::FromIterator;
 use std::io;
+use std::io::Write;
 use std::fs;
-use serde::ser::{Serializer, SerializeSeq, Serialize};
 use std::process::{exit, Child, Stdio, Command};
 
 struct FileInfo {
     changes_size: u64,
 }
 
+/* Sizes are kept per file and not per path, the tracker tells the final
+ * path of each file once the stream was read */
 struct FileMap {
-    map: HashMap<String, FileInfo>,
+    tracker: bf::PathTracker,
+    map: HashMap<bf::NodeId, FileInfo>,
+    /* Last path of files removed after data was sent for them */
+    removed: HashMap<bf::NodeId, bf::BtrfsString>,
 }
 
 impl FileMap {
     fn new() -> Self {
-        FileMap {map: HashMap::new()}
+        FileMap {tracker: bf::PathTracker::new(), map: HashMap::new(), removed: HashMap::new()}
     }
-    fn get(&mut self, key: &str) -> &mut FileInfo{
-        self.map.entry(key.to_string()).or_insert(FileInfo {changes_size: 0})
+    fn acc(&mut self, file: bf::NodeId, amount: u64) {
+        self.map.entry(file).or_insert(FileInfo {changes_size: 0}).changes_size += amount
     }
-    fn acc(&mut self, key: &str, amount: u64) {
-        self.get(key).changes_size += amount
-    }
-    fn rename(&mut self, old: &str, new: &str) {
-        if let Some(v) = self.map.remove(old) {
-            *self.get(new) = v;
+    fn remove(&mut self, file: bf::NodeId, path: bf::BtrfsStr) {
+        if self.map.contains_key(&file) {
+            self.removed.insert(file, path.into());
         }
     }
+    /* The data of removed files was still sent, so it is shown under their last name */
+    fn path(&self, file: bf::NodeId) -> bf::BtrfsString {
+        self.tracker.path(file)
+            .or_else(|| self.removed.get(&file).cloned())
+            .unwrap_or_else(|| self.tracker.name(file).clone())
+    }
 }
 
+/* File names are raw bytes, so the tree is written out by hand instead of through serde. */
 struct FileTreeNode {
-    name: String,
-    children: HashMap<String, Box<FileTreeNode>>,
+    name: Vec<u8>,
+    children: HashMap<Vec<u8>, Box<FileTreeNode>>,
     changes_size: u64,
 }
 
-#[derive(Serialize)]
-struct InfoBlockJSON {
-    name: String,
-    asize: u64,
-    dsize: u64,
-}
-
-impl Serialize for FileTreeNode {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> 
-        where S: Serializer
-    {
-        let mut root = serializer.serialize_seq(Some(1 + self.children.len()))?;
-        root.serialize_element(&InfoBlockJSON{name: self.name.clone(), asize: self.changes_size, dsize: self.changes_size})?;
-        for c in self.children.values() {
-            root.serialize_element(c)?;
-        }
-        root.end()
-    }
-}
-
 impl FileTreeNode {
-    fn new(name: &str) -> FileTreeNode {
-        FileTreeNode {children: HashMap::new(), changes_size: 0, name: name.to_string()}
+    fn new(name: &[u8]) -> FileTreeNode {
+        FileTreeNode {children: HashMap::new(), changes_size: 0, name: name.to_vec()}
     }
-    fn add(&mut self, path: &[&str], info: &FileInfo) {
-        if path.len() == 0 {
+    fn add(&mut self, path: &[&[u8]], info: &FileInfo) {
+        if path.is_empty() {
             self.changes_size += info.changes_size;
         } else {
-            let f = self.children.entry(path[0].to_string())
+            let f = self.children.entry(path[0].to_vec())
                 .or_insert_with(|| Box::new(FileTreeNode::new(path[0])));
             f.add(&path[1..], info);
         }
     }
+    fn write_json(&self, w: &mut dyn io::Write) -> io::Result<()> {
+        write!(w, "[{{\"name\":")?;
+        write_json_name(w, &self.name)?;
+        write!(w, ",\"asize\":{},\"dsize\":{}}}", self.changes_size, self.changes_size)?;
+        for c in self.children.values() {
+            write!(w, ",")?;
+            c.write_json(w)?;
+        }
+        write!(w, "]")
+    }
+}
+
+/* Escapes what JSON requires, but passes other bytes through unchanged. NCDu
+ * expects file names as they are on disk, even if they are not valid UTF-8. */
+fn write_json_name(w: &mut dyn io::Write, name: &[u8]) -> io::Result<()> {
+    w.write_all(b"\"")?;
+    for &b in name {
+        match b {
+            b'"' => w.write_all(b"\\\"")?,
+            b'\\' => w.write_all(b"\\\\")?,
+            0x00..=0x1f => write!(w, "\\u{:04x}", b)?,
+            _ => w.write_all(&[b])?,
+        }
+    }
+    w.write_all(b"\"")
 }
 
 #[derive(Serialize)]
@@ -83,20 +97,30 @@ struct MetadataJSON {
     producer: String,
 }
 
-#[derive(Serialize)]
-struct RootJSON (u32, u32, MetadataJSON, FileTreeNode);
-
 enum InputStream {
-    File(Box<dyn io::Read>),
+    File(fs::File),
+    Stdin(io::Stdin),
     BtrfsSend(Child)
 }
-impl InputStream {
-    fn get_stream(&mut self) -> &mut io::Read {
+impl io::Read for InputStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match self {
-            InputStream::File(ref mut file) => file,
-            InputStream::BtrfsSend(ref mut child) => child.stdout.as_mut().unwrap(),
+            InputStream::File(ref mut file) => file.read(buf),
+            InputStream::Stdin(ref mut stdin) => stdin.read(buf),
+            InputStream::BtrfsSend(ref mut child) => child.stdout.as_mut().unwrap().read(buf),
         }
     }
+}
+/* Lets the reader seek over file data instead of reading it */
+impl io::Seek for InputStream {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        match self {
+            InputStream::File(ref mut file) => file.seek(pos),
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "input is not seekable")),
+        }
+    }
+}
+impl InputStream {
     fn close(&mut self) {
         if let InputStream::BtrfsSend(child) = self {
             let ret = child.wait().unwrap();
@@ -107,8 +131,19 @@ impl InputStream {
     }
 }
 
-fn write_tree(w: &mut io::Write, tree: FileTreeNode) {
-    serde_json::ser::to_writer(w, &RootJSON(1, 0, MetadataJSON{producer: "btsdu".to_string()}, tree)).unwrap();
+fn write_tree(w: &mut dyn io::Write, tree: FileTreeNode) -> io::Result<()> {
+    let mut w = io::BufWriter::new(w);
+    write!(w, "[1,0,")?;
+    serde_json::ser::to_writer(&mut w, &MetadataJSON{producer: "btsdu".to_string()})?;
+    write!(w, ",")?;
+    tree.write_json(&mut w)?;
+    write!(w, "]")?;
+    w.flush()
+}
+
+fn stream_error(e: bf::Error) -> ! {
+    eprintln!("Can not parse the btrfs stream: {}", e);
+    exit(1);
 }
 
 fn main() {
@@ -118,7 +153,9 @@ fn main() {
         .arg(Arg::with_name("input")
             .value_name("INPUT")
             .required(true)
-            .help("Subvolume location to analyze. If -s is given, this btrfs-send output msut be specified instead."))
+            .multiple(true)
+            .help("Subvolume location to analyze. If -s is given, this btrfs-send output msut be specified instead. \
+                  Several subvolumes can be given, they are shown under their names."))
         .arg(Arg::with_name("parent")
             .short("-p")
             .value_name("PARENT")
@@ -133,21 +170,24 @@ fn main() {
             .help("Do not run ncdu, but output usage data in JSON format for later usage."))
         .get_matches();
 
-    if matches.is_present("send-stream") {
-        if matches.is_present("parent") {
-            eprintln!("The -p option can not be used together with -s.");
-            exit(1);
-        }
+    if matches.is_present("send-stream") && matches.is_present("parent") {
+        eprintln!("The -p option can not be used together with -s.");
+        exit(1);
     }
-    let mut map = FileMap::new();
+    if matches.is_present("send-stream") && matches.occurrences_of("input") > 1 {
+        eprintln!("Only one stream can be given together with -s.");
+        exit(1);
+    }
+    /* One map per subvolume (segment of the stream) */
+    let mut maps: Vec<(bf::BtrfsString, FileMap)> = Vec::new();
     {
         /* Open the correct stream for input */
-        let mut stream_source = if matches.is_present("send-stream") {
+        let stream_source = if matches.is_present("send-stream") {
             let file_path = matches.value_of("input").unwrap();
             if file_path == "-" {
-                InputStream::File(Box::new(io::stdin()))
+                InputStream::Stdin(io::stdin())
             } else {
-                InputStream::File(Box::new(fs::File::open(file_path).unwrap()))
+                InputStream::File(fs::File::open(file_path).unwrap())
             }
         } else {
             let mut cmd = Command::new("btrfs");
@@ -155,42 +195,73 @@ fn main() {
             if let Some(parent) = matches.value_of("parent") {
                 cmd.arg("-p").arg(parent);
             }
-            cmd.arg(matches.value_of("input").unwrap());
+            cmd.args(matches.values_of("input").unwrap());
             cmd.stdout(Stdio::piped());
             InputStream::BtrfsSend(cmd.spawn().unwrap())
         };
-        {
-              
-            let mut reader = bf::BtrfsReader::new(stream_source.get_stream()).unwrap();
-            let mut cmd_count = 0;
-            loop {
-                match reader.read_command().unwrap() {
-                    None => break,
-                    Some(bf::Command::Rename(c)) => map.rename(&c.path, &c.path_to),
-                    Some(bf::Command::Write(c)) => map.acc(&c.path, c.data.len() as u64),
-                    Some(bf::Command::SetXattr(c)) => map.acc(&c.path, c.xattr_data.len() as u64),
-                    _ => {},
-                }
-                cmd_count += 1;
+        /* Only the sizes of file data are needed */
+        let opts = bf::ReaderOptions {skip_payload: true, ..bf::ReaderOptions::default()};
+        let mut reader = bf::BtrfsReader::with_seek(stream_source, opts)
+            .unwrap_or_else(|e| stream_error(e));
+        let mut cmd_count = 0;
+        /* Borrowed commands avoid copying every path and data block */
+        while let Some(cmd) = reader.read_command_ref().unwrap_or_else(|e| stream_error(e)) {
+            /* Each subvolume starts with its Subvol or Snapshot command */
+            match cmd {
+                bf::borrowed::Command::Subvol(c) => maps.push((c.path.into(), FileMap::new())),
+                bf::borrowed::Command::Snapshot(c) => maps.push((c.path.into(), FileMap::new())),
+                _ => if maps.is_empty() {
+                    maps.push((bf::BtrfsString::new(), FileMap::new()));
+                },
+            }
+            let map = &mut maps.last_mut().unwrap().1;
+            let file = map.tracker.apply_ref(&cmd);
+            let size = match cmd {
+                bf::borrowed::Command::Write(c) => Some(c.data.len()),
+                bf::borrowed::Command::EncodedWrite(c) => Some(c.unencoded_len),
+                bf::borrowed::Command::SetXattr(c) => Some(c.xattr_data.len()),
+                _ => None,
+            };
+            if let (Some(file), Some(size)) = (file, size) {
+                map.acc(file, size);
             }
-            eprintln!("Processed {} commands", cmd_count);
+            match (file, cmd) {
+                (Some(file), bf::borrowed::Command::UnLink(c)) => map.remove(file, c.path),
+                (Some(file), bf::borrowed::Command::RmDir(c)) => map.remove(file, c.path),
+                _ => {},
+            }
+            cmd_count += 1;
+        }
+        eprintln!("Processed {} commands ({} bytes)", cmd_count, reader.bytes_consumed());
+        if let Err(e) = reader.finish() {
+            eprintln!("********************************************************************");
+            eprintln!("WARNING: {}.", e);
+            eprintln!("The stream is incomplete, the usage shown covers only part of it.");
+            eprintln!("********************************************************************");
         }
-        stream_source.close();
+        reader.into_inner().close();
     }
-    let mut tree = FileTreeNode::new("/");
-    for (p, f) in map.map {
-        let parts = Vec::from_iter(p.split('/'));
-        tree.add(&parts, &f);
+    let mut tree = FileTreeNode::new(b"/");
+    let multiple = maps.len() > 1;
+    for (name, map) in maps {
+        for (file, f) in map.map.iter() {
+            let p = map.path(*file);
+            let mut parts = Vec::from_iter(p.as_bytes().split(|&c| c == b'/'));
+            if multiple {
+                parts.insert(0, name.as_bytes());
+            }
+            tree.add(&parts, f);
+        }
     }
 
     if matches.is_present("raw") {
-        write_tree(&mut io::stdout(), tree)
+        write_tree(&mut io::stdout(), tree).unwrap();
     } else {
         let mut cmd = Command::new("ncdu");
         cmd.arg("-f").arg("-");
         cmd.stdin(Stdio::piped());
         let mut child = cmd.spawn().expect("Can not execute ncdu -- is it installed and in path?");
-        write_tree(child.stdin.as_mut().unwrap(), tree);
+        write_tree(child.stdin.as_mut().unwrap(), tree).unwrap();
         child.wait().unwrap();
     }
 }
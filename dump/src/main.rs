@@ -1,16 +1,62 @@
 extern crate btrfs_send_parse as bf;
+use std::env;
 use std::io;
+use std::process::exit;
 
-fn main() {
-    let mut input = io::stdin();
-    let mut parser = bf::BtrfsReader::new(&mut input).unwrap();
+fn fail(e: bf::Error) -> ! {
+    eprintln!("Error: {}", e);
+    exit(1);
+}
+
+fn dump() {
+    let mut parser = bf::BtrfsReader::new(io::stdin()).unwrap_or_else(|e| fail(e));
     let opts = bf::CommandPrintOptions::default();
     println!("Stream version: {}", parser.version());
-    loop {
-        let cmd = match parser.read_command().unwrap() {
-            None => break,
-            Some(x) => x
-        };
+    let mut segment = 0;
+    while let Some(cmd) = parser.next() {
+        let cmd = cmd.unwrap_or_else(|e| fail(e));
+        /* Further streams concatenated in the input */
+        let s = parser.segment();
+        if s.index != segment {
+            segment = s.index;
+            println!("Segment {} at offset {}, stream version: {}", s.index, s.offset, s.version);
+        }
         cmd.print(&mut io::stdout(), &opts).unwrap();
     }
+    parser.finish().unwrap_or_else(|e| fail(e));
+}
+
+/* Prints what can be recovered from a damaged stream, and what was skipped */
+fn salvage() {
+    let mut parser = bf::SalvageReader::new(io::stdin());
+    let opts = bf::CommandPrintOptions::default();
+    let mut segment = None;
+    while let Some(r) = parser.next() {
+        match r.unwrap_or_else(|e| fail(e)) {
+            bf::Salvaged::Command(cmd) => {
+                let s = parser.segment().unwrap();
+                if segment != Some(s.index) {
+                    segment = Some(s.index);
+                    println!("Segment {} at offset {}, stream version: {}", s.index, s.offset, s.version);
+                }
+                cmd.print(&mut io::stdout(), &opts).unwrap();
+            },
+            bf::Salvaged::Skipped {range, error} =>
+                println!("Skipped {} bytes at offset {}: {}", range.end - range.start, range.start, error),
+        }
+    }
+    if let Err(e) = parser.finish() {
+        eprintln!("Warning: {}", e);
+    }
+}
+
+fn main() {
+    match env::args().nth(1).as_deref() {
+        None => dump(),
+        Some("--salvage") => salvage(),
+        Some(_) => {
+            eprintln!("Usage: dump [--salvage] < STREAM");
+            exit(2);
+        },
+    }
 }